@@ -0,0 +1,6 @@
+-- @endpoint all_user_count
+-- @import all_users from endpoint:all_users
+WITH all_u as (
+	@all_users()
+)
+select count(*) from all_u
@@ -0,0 +1,3 @@
+-- @auth verify
+-- @endpoint currentUser
+select @auth.id as id
@@ -1,13 +1,16 @@
 use serde::Deserialize;
 use serde_json::Value;
+use std::collections::BTreeMap;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Binding {
     Int(i64),
     Float(f64),
     Bool(bool),
     String(String),
     Json(Value),
+    /// an array of scalars that all share the same type, nulls aside
+    Array(Vec<Binding>),
     Null,
 }
 
@@ -16,15 +19,31 @@ impl Binding {
         use std::io::Write;
         let mut buf = Vec::new();
 
+        // numbers are parenthesized so a negative one can not turn `10-@x` into a `--` comment
         match self {
-            Binding::Int(i) => write!(&mut buf, "{}", i)?,
-            Binding::Float(float) => write!(&mut buf, "{}", float)?,
+            Binding::Int(i) => write!(&mut buf, "({})", i)?,
+            Binding::Float(float) if float.is_nan() => write!(&mut buf, "'NaN'")?,
+            Binding::Float(float) if float.is_infinite() && *float > 0.0 => {
+                write!(&mut buf, "'Infinity'")?
+            }
+            Binding::Float(float) if float.is_infinite() => write!(&mut buf, "'-Infinity'")?,
+            Binding::Float(float) => write!(&mut buf, "({})", float)?,
             Binding::Bool(b) => write!(&mut buf, "{}", b)?,
-            Binding::String(string) => write!(&mut buf, "'{}'", string)?,
-            Binding::Json(json) => {
-                write!(&mut buf, "'")?;
-                serde_json::to_writer(&mut buf, &json)?;
-                write!(&mut buf, "'")?;
+            Binding::String(string) => write!(&mut buf, "{}", quote_literal(string))?,
+            Binding::Json(json) => write!(
+                &mut buf,
+                "{}",
+                quote_literal(&serde_json::to_string(&json)?)
+            )?,
+            Binding::Array(values) => {
+                let values = values
+                    .iter()
+                    .map(Binding::to_sql_string)
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                write!(&mut buf, "ARRAY[{}]", values.join(", "))?;
+                if self.array_type().is_none() {
+                    write!(&mut buf, "::text[]")?;
+                }
             }
             Binding::Null => write!(&mut buf, "NULL")?,
         };
@@ -32,7 +51,7 @@ impl Binding {
         Ok(String::from_utf8(buf)?)
     }
 
-    fn from_json(value: Value) -> anyhow::Result<Self> {
+    pub fn from_json(value: Value) -> anyhow::Result<Self> {
         let val = match value {
             Value::Null => Binding::Null,
             Value::Bool(val) => Binding::Bool(val),
@@ -51,11 +70,98 @@ impl Binding {
                     Err(anyhow!("unexpected number type",))?
                 }
             }
+            Value::Array(values) if values.iter().all(is_scalar) => {
+                let values = values
+                    .into_iter()
+                    .map(Binding::from_json)
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let mut types = values
+                    .iter()
+                    .filter(|value| **value != Binding::Null)
+                    .map(Binding::type_name);
+                if let Some(first) = types.next() {
+                    if let Some(other) = types.find(|name| *name != first) {
+                        Err(anyhow!(
+                            "array elements must all have the same type but found {} and {}",
+                            first,
+                            other
+                        ))?
+                    }
+                }
+                Binding::Array(values)
+            }
             _ => Binding::Json(value),
         };
 
         Ok(val)
     }
+
+    /// converts the binding into a single json value, leaving nulls as sql nulls
+    pub fn to_json(&self) -> anyhow::Result<Self> {
+        match self {
+            Binding::Null | Binding::Json(_) => Ok(self.clone()),
+            _ => Ok(Binding::Json(self.to_json_value()?)),
+        }
+    }
+
+    pub fn to_json_value(&self) -> anyhow::Result<Value> {
+        let value = match self {
+            Binding::Int(i) => Value::from(*i),
+            Binding::Float(float) => serde_json::Number::from_f64(*float)
+                .map(Value::Number)
+                .ok_or_else(|| anyhow!("{} can not be represented as json", float))?,
+            Binding::Bool(b) => Value::Bool(*b),
+            Binding::String(string) => Value::String(string.clone()),
+            Binding::Json(json) => json.clone(),
+            Binding::Array(values) => Value::Array(
+                values
+                    .iter()
+                    .map(Binding::to_json_value)
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            Binding::Null => Value::Null,
+        };
+        Ok(value)
+    }
+
+    /// the type of the first non null element of an array
+    pub fn array_type(&self) -> Option<&'static str> {
+        match self {
+            Binding::Array(values) => values
+                .iter()
+                .find(|value| **value != Binding::Null)
+                .map(Binding::type_name),
+            _ => None,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Binding::Int(_) => "int",
+            Binding::Float(_) => "float",
+            Binding::Bool(_) => "bool",
+            Binding::String(_) => "string",
+            Binding::Json(_) => "json",
+            Binding::Array(_) => "array",
+            Binding::Null => "null",
+        }
+    }
+}
+
+fn is_scalar(value: &Value) -> bool {
+    !matches!(value, Value::Array(_) | Value::Object(_))
+}
+
+/// quotes a string the same way as postgres' `quote_literal`: single quotes are
+/// doubled and strings with backslashes are written as escape strings so they
+/// are read back the same regardless of `standard_conforming_strings`
+fn quote_literal(string: &str) -> String {
+    let escaped = string.replace('\'', "''");
+    if string.contains('\\') {
+        format!("E'{}'", escaped.replace('\\', "\\\\"))
+    } else {
+        format!("'{}'", escaped)
+    }
 }
 
 impl<'de> Deserialize<'de> for Binding {
@@ -67,3 +173,125 @@ impl<'de> Deserialize<'de> for Binding {
         Binding::from_json(value).map_err(|err| serde::de::Error::custom(err))
     }
 }
+
+/// the bindings sent to an endpoint. an object binds named parameters while an array
+/// binds positional parameters, which are keyed by their position starting at "1"
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Payload(pub BTreeMap<String, Binding>);
+
+impl<'de> Deserialize<'de> for Payload {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bindings = match Value::deserialize(deserializer)? {
+            Value::Object(values) => values
+                .into_iter()
+                .map(|(key, value)| Ok((key, Binding::from_json(value)?)))
+                .collect::<anyhow::Result<_>>(),
+            Value::Array(values) => values
+                .into_iter()
+                .enumerate()
+                .map(|(idx, value)| Ok(((idx + 1).to_string(), Binding::from_json(value)?)))
+                .collect::<anyhow::Result<_>>(),
+            _ => Err(anyhow!("payload must be a json object or array")),
+        }
+        .map_err(serde::de::Error::custom)?;
+        Ok(Payload(bindings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn to_sql_string_test() {
+        let sql = |binding: Binding| binding.to_sql_string().unwrap();
+        assert_eq!(sql(Binding::String("O'Brien".into())), "'O''Brien'");
+        assert_eq!(
+            sql(Binding::String("'; drop table users; --".into())),
+            "'''; drop table users; --'"
+        );
+        assert_eq!(
+            sql(Binding::String(r"C:\temp\'x".into())),
+            r"E'C:\\temp\\''x'"
+        );
+        assert_eq!(
+            sql(Binding::String("héllo 世界 🎉".into())),
+            "'héllo 世界 🎉'"
+        );
+        assert_eq!(sql(Binding::Float(f64::NAN)), "'NaN'");
+        assert_eq!(sql(Binding::Float(f64::NEG_INFINITY)), "'-Infinity'");
+        assert_eq!(sql(Binding::Float(1.5)), "(1.5)");
+        assert_eq!(sql(Binding::Int(-5)), "(-5)");
+        assert_eq!(sql(Binding::Float(-2.5)), "(-2.5)");
+        assert_eq!(
+            format!("10-{} and tenant = 1", sql(Binding::Int(-5))),
+            "10-(-5) and tenant = 1"
+        );
+        assert_eq!(sql(Binding::Null), "NULL");
+        assert_eq!(
+            sql(Binding::Array(vec![Binding::Int(1), Binding::Null])),
+            "ARRAY[(1), NULL]"
+        );
+        assert_eq!(sql(Binding::Array(vec![])), "ARRAY[]::text[]");
+        assert_eq!(
+            sql(Binding::Json(json!({"name": "O'Brien", "path": r"a\b"}))),
+            r#"E'{"name":"O''Brien","path":"a\\\\b"}'"#
+        );
+    }
+
+    #[test]
+    fn integer_from_json_test() {
+        let binding: Binding = serde_json::from_str("42").unwrap();
+        assert_eq!(binding, Binding::Int(42));
+
+        let binding: Binding = serde_json::from_str("9223372036854775807").unwrap();
+        assert_eq!(binding, Binding::Int(i64::MAX));
+
+        let binding: Binding = serde_json::from_str("-9223372036854775808").unwrap();
+        assert_eq!(binding, Binding::Int(i64::MIN));
+
+        let err = serde_json::from_str::<Binding>("9223372036854775808").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("number 9223372036854775808 is out of bounds for postgres"));
+    }
+
+    #[test]
+    fn array_from_json_test() {
+        let binding: Binding = serde_json::from_value(json!([1, null, 3])).unwrap();
+        assert_eq!(
+            binding,
+            Binding::Array(vec![Binding::Int(1), Binding::Null, Binding::Int(3)])
+        );
+        assert_eq!(binding.array_type(), Some("int"));
+
+        let binding: Binding = serde_json::from_value(json!([])).unwrap();
+        assert_eq!(binding, Binding::Array(vec![]));
+
+        // arrays of objects are still bound as json
+        let binding: Binding = serde_json::from_value(json!([{"id": 1}])).unwrap();
+        assert!(matches!(binding, Binding::Json(_)));
+
+        let err = serde_json::from_value::<Binding>(json!([1, "two"])).unwrap_err();
+        assert!(err.to_string().contains("same type"));
+    }
+
+    #[test]
+    fn payload_from_json_test() {
+        let Payload(bindings) = serde_json::from_value(json!({"id": 1})).unwrap();
+        assert_eq!(bindings.get("id"), Some(&Binding::Int(1)));
+
+        let Payload(bindings) = serde_json::from_value(json!(["a", [1, 2]])).unwrap();
+        assert_eq!(bindings.get("1"), Some(&Binding::String("a".into())));
+        assert_eq!(
+            bindings.get("2"),
+            Some(&Binding::Array(vec![Binding::Int(1), Binding::Int(2)]))
+        );
+
+        assert!(serde_json::from_value::<Payload>(json!(1)).is_err());
+    }
+}
@@ -92,7 +92,10 @@ AND @email = 'testing 123 @haha'
 OR 0 = @id"#;
         assert_valid_ast(
             test_str,
-            vec![&Decorator::Param("email"), &Decorator::Param("id")],
+            vec![
+                &Decorator::Param("email", None),
+                &Decorator::Param("id", None),
+            ],
             vec![
                 &InterpSpan::Param("id"),
                 &InterpSpan::Param("email"),
@@ -107,7 +110,10 @@ OR 0 = @id"#;
 select * from users"#;
         assert_valid_ast(
             test_str,
-            vec![&Decorator::Param("email"), &Decorator::Param("id")],
+            vec![
+                &Decorator::Param("email", None),
+                &Decorator::Param("id", None),
+            ],
             vec![],
             1,
         );
@@ -141,7 +147,7 @@ select * from test"#;
 ; ; ;"#;
         assert_eq!(
             Ast::parse(path.clone(), test_str).unwrap_err().to_string(),
-            "Parsing Error: ErrorKind(\"; ; ;\", ConstError(\"must have at least one sql statement\"))"
+            "Parsing Failure: ErrorKind(\"; ; ;\", ConstError(\"this module has no statements\"))"
         );
     }
 }
@@ -1,33 +1,55 @@
 use either::Either;
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while},
+    bytes::complete::{tag, take_while, take_while1},
     character::complete::one_of,
-    combinator::{cut, opt},
-    multi::fold_many0,
+    character::complete::satisfy,
+    combinator::{cut, not, opt},
+    multi::{fold_many0, separated_list1},
     number::complete::float,
-    sequence::{delimited, preceded},
+    sequence::{delimited, preceded, terminated},
     Parser,
 };
 use std::path::{Path, PathBuf};
 
-use crate::codegen::module::AuthSettings;
+use crate::codegen::module::{AuthSettings, ParamKind, Returns};
 
 use super::{
     super::result::{PResult, ParseError},
     super::span_ref::SpanRef,
     parser::{
-        is_alpha_or_underscore, line_space0, line_space1, space, string_literal,
+        endpoint_name, is_alpha_or_underscore, line_space0, line_space1, space, string_literal,
         with_multi_line_comment, with_single_line_comment,
     },
+    sql::statement_annotation,
 };
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Decorator<'a> {
     Auth(AuthSettings),
-    Import(SpanRef<'a, &'a str>, SpanRef<'a, &'a Path>),
+    Import(SpanRef<'a, &'a str>, SpanRef<'a, ImportSource<'a>>),
+    Endpoint(&'a str),
+    Param(&'a str, Option<ParamKind>),
+    Stream,
+    /// seconds a response is cached for
+    Cache(u64),
+    /// the postgres channel whose notifications are forwarded over a websocket
+    Subscribe(&'a str),
+    /// groups endpoints in listings, does not affect routing
+    Tag(&'a str),
+    /// the message sent instead of the error of a unique or foreign key violation
+    OnConflict(&'a str),
+    /// how the rows of the endpoint are unwrapped
+    Returns(Returns),
+}
+
+/// where the module of an import is found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportSource<'a> {
+    /// a path relative to the importing module like './friends.sql'
+    Path(&'a Path),
+    /// the endpoint the module declares like `endpoint:getActiveUsers`
     Endpoint(&'a str),
-    Param(&'a str),
 }
 
 fn get_multiplier(chr: char) -> Result<f32, &'static str> {
@@ -43,6 +65,28 @@ fn get_multiplier(chr: char) -> Result<f32, &'static str> {
     Ok(res)
 }
 
+fn parse_param_kind<'a>(input: &'a str) -> PResult<'a, ParamKind> {
+    let (output, kind) = take_while1(is_alpha_or_underscore)(input)?;
+    match kind {
+        "json" | "jsonb" => Ok((output, ParamKind::Json)),
+        _ => Err(nom::Err::Failure(ParseError::const_error(
+            input,
+            "unknown parameter type, expected json",
+        ))),
+    }
+}
+
+fn parse_returns_mode<'a>(input: &'a str) -> PResult<'a, Returns> {
+    let (output, mode) = take_while1(is_alpha_or_underscore)(input)?;
+    match mode {
+        "scalar" => Ok((output, Returns::Scalar)),
+        _ => Err(nom::Err::Failure(ParseError::const_error(
+            input,
+            "unknown return mode, expected scalar",
+        ))),
+    }
+}
+
 fn parse_interval(input: &str) -> PResult<f32> {
     let (output, (seconds, chr_opt)) = float.and(opt(one_of("smhdMy"))).parse(input)?;
     let seconds = match chr_opt {
@@ -56,17 +100,55 @@ fn parse_interval(input: &str) -> PResult<f32> {
     Ok((output, seconds))
 }
 
+fn is_role_char(chr: char) -> bool {
+    is_alpha_or_underscore(chr) || chr == '-' || chr == ':' || chr == '.'
+}
+
+/// a `--` note after a decorator's value that runs until the end of the line,
+/// or the end of the comment inside of multi-line comments
+///     @param id -- the user's id
+fn inline_note<'a>(input: &'a str) -> PResult<'a, &'a str> {
+    let (input, _) = tag("--")(input)?;
+    let end = input.find('\n').unwrap_or(input.len());
+    let end = input[..end].find("*/").unwrap_or(end);
+    Ok((&input[end..], &input[..end]))
+}
+
+/// the spaces and optional note that end a decorator
+fn decorator_end<'a>(input: &'a str) -> PResult<'a, ()> {
+    line_space0
+        .and(opt(inline_note))
+        .and(line_space0)
+        .map(|_| ())
+        .parse(input)
+}
+
 impl<'a> Decorator<'a> {
-    fn parse_param(input: &'a str) -> PResult<&'a str> {
-        decorator("param", take_while(is_alpha_or_underscore))(input)
+    /// parses one or more comma separated parameter names with optional types
+    /// examples:
+    ///     @param id
+    ///     @param id, email, name
+    ///     @param filter: json
+    fn parse_params(input: &'a str) -> PResult<'a, Vec<(SpanRef<'a, &'a str>, Option<ParamKind>)>> {
+        decorator(
+            "param",
+            separated_list1(
+                line_space0.and(tag(",")).and(line_space0),
+                SpanRef::parse(take_while1(is_alpha_or_underscore)).and(opt(preceded(
+                    line_space0.and(tag(":")).and(line_space0),
+                    cut(parse_param_kind),
+                ))),
+            ),
+        )(input)
     }
 
-    fn parse_import(input: &'a str) -> PResult<(SpanRef<'a, &'a str>, SpanRef<'a, &'a Path>)> {
-        let import = |input: &'a str| {
-            let (input, import_name) = SpanRef::parse(take_while(is_alpha_or_underscore))(input)?;
-            let (input, _) = line_space1(input)?;
-            let (input, _) = tag("from")(input)?;
-            let (input, _) = line_space1(input)?;
+    /// examples:
+    ///     @import friends from './friends.sql'
+    ///     @import friends from endpoint:getFriends
+    fn parse_import(
+        input: &'a str,
+    ) -> PResult<(SpanRef<'a, &'a str>, SpanRef<'a, ImportSource<'a>>)> {
+        let path = |input: &'a str| {
             let (input, literal) = SpanRef::parse(string_literal)(input)?;
 
             if literal.len() < 3 {
@@ -85,19 +167,55 @@ impl<'a> Decorator<'a> {
                 )))?
             }
 
-            Ok((input, (import_name, path)))
+            Ok((input, path.map(ImportSource::Path)))
+        };
+        let endpoint = SpanRef::parse(preceded(tag("endpoint:"), cut(endpoint_name)))
+            .map(|endpoint| endpoint.map(ImportSource::Endpoint));
+        let mut source = alt((endpoint, path));
+        let import = move |input: &'a str| {
+            let (input, import_name) = SpanRef::parse(take_while(is_alpha_or_underscore))(input)?;
+            let (input, _) = line_space1(input)?;
+            let (input, _) = tag("from")(input)?;
+            let (input, _) = line_space1(input)?;
+            let (input, source) = source.parse(input)?;
+            Ok((input, (import_name, source)))
         };
         decorator("import", import)(input)
     }
 
-    fn parse_endpoint(input: &'a str) -> PResult<&'a str> {
-        decorator("endpoint", take_while(is_alpha_or_underscore))(input)
+    /// parses one or more comma separated endpoint names, later names are aliases
+    /// examples:
+    ///     @endpoint getUsers
+    ///     @endpoint users.list, getUsers
+    fn parse_endpoints(input: &'a str) -> PResult<'a, Vec<SpanRef<'a, &'a str>>> {
+        decorator(
+            "endpoint",
+            separated_list1(
+                line_space0.and(tag(",")).and(line_space0),
+                SpanRef::parse(endpoint_name),
+            ),
+        )(input)
     }
 
+    /// examples:
+    ///     @auth verify
+    ///     @auth verify 2d role=admin
+    ///     @auth verify role=admin,editor
     fn parse_auth(input: &'a str) -> PResult<AuthSettings> {
-        let verify_token = preceded(tag("verify"), opt(preceded(line_space0, parse_interval)))
-            .map(|opt| opt.map(|val| val as u64))
-            .map(AuthSettings::VerifyToken);
+        let roles = preceded(
+            line_space1.and(tag("role=")),
+            cut(separated_list1(
+                tag(","),
+                take_while1(is_role_char).map(str::to_string),
+            )),
+        );
+        let verify_token = preceded(
+            tag("verify"),
+            opt(preceded(line_space0, parse_interval)).and(opt(roles)),
+        )
+        .map(|(exp, roles)| {
+            AuthSettings::VerifyToken(exp.map(|val| val as u64), roles.unwrap_or_default())
+        });
 
         let set_token = preceded(tag("authorize").and(line_space1), parse_interval)
             .map(|val| val as u64)
@@ -108,14 +226,82 @@ impl<'a> Decorator<'a> {
         decorator("auth", alt((verify_token, set_token, remove_token)))(input)
     }
 
+    /// caches the rows of a read only endpoint for the given interval
+    ///     @cache 30s
+    fn parse_cache(input: &'a str) -> PResult<'a, u64> {
+        decorator("cache", parse_interval.map(|val| val as u64))(input)
+    }
+
+    /// forwards the notifications of a postgres channel to websocket clients
+    ///     @subscribe user_events
+    fn parse_subscribe(input: &'a str) -> PResult<'a, &'a str> {
+        decorator("subscribe", take_while1(is_alpha_or_underscore))(input)
+    }
+
+    /// a tag that groups the endpoint with others, can be repeated
+    ///     @tag billing
+    fn parse_tag(input: &'a str) -> PResult<'a, &'a str> {
+        decorator("tag", take_while1(is_role_char))(input)
+    }
+
+    /// replaces the error of a unique or foreign key violation. `{constraint}` is
+    /// replaced with the name of the violated constraint
+    ///     @on_conflict "this email is already registered"
+    fn parse_on_conflict(input: &'a str) -> PResult<'a, &'a str> {
+        decorator(
+            "on_conflict",
+            string_literal.map(|literal: &'a str| &literal[1..literal.len() - 1]),
+        )(input)
+    }
+
+    /// unwraps the only column of the only row into a bare json value
+    ///     @returns scalar
+    fn parse_returns(input: &'a str) -> PResult<'a, Returns> {
+        decorator("returns", parse_returns_mode)(input)
+    }
+
+    /// marks an endpoint as streaming its rows instead of buffering them
+    ///     @stream
+    fn parse_stream(input: &'a str) -> PResult<'a, &'a str> {
+        delimited(
+            line_space0.and(tag("@")),
+            terminated(tag("stream"), not(satisfy(is_alpha_or_underscore))),
+            decorator_end,
+        )(input)
+    }
+
     pub fn parse(input: &'a str) -> PResult<Self> {
         alt((
-            Self::parse_param.map(Decorator::Param),
-            Self::parse_endpoint.map(Decorator::Endpoint),
+            Self::parse_stream.map(|_| Decorator::Stream),
             Self::parse_auth.map(Decorator::Auth),
+            Self::parse_cache.map(Decorator::Cache),
+            Self::parse_subscribe.map(Decorator::Subscribe),
+            Self::parse_tag.map(Decorator::Tag),
+            Self::parse_on_conflict.map(Decorator::OnConflict),
+            Self::parse_returns.map(Decorator::Returns),
             Self::parse_import.map(|(v1, v2)| Decorator::Import(v1, v2)),
         ))(input)
     }
+
+    /// parses a decorator into all the decorators it declares. each parameter or
+    /// endpoint in a list keeps its own span so that errors point to the right name.
+    pub fn parse_spans(input: &'a str) -> PResult<'a, Vec<SpanRef<'a, Self>>> {
+        alt((
+            Self::parse_params.map(|params| {
+                params
+                    .into_iter()
+                    .map(|(param, kind)| param.map(|param| Decorator::Param(param, kind)))
+                    .collect()
+            }),
+            Self::parse_endpoints.map(|endpoints| {
+                endpoints
+                    .into_iter()
+                    .map(|endpoint| endpoint.map(Decorator::Endpoint))
+                    .collect()
+            }),
+            SpanRef::parse(Self::parse).map(|decorator| vec![decorator]),
+        ))(input)
+    }
 }
 
 fn decorator<'a, A, P>(decorator: &'static str, parser: P) -> impl FnMut(&'a str) -> PResult<A>
@@ -128,7 +314,7 @@ where
             .and(tag(decorator))
             .and(line_space1),
         cut(parser),
-        line_space0,
+        decorator_end,
     )
 }
 
@@ -163,39 +349,58 @@ impl<'a> Decorators<'a> {
         self.0
             .iter()
             .filter_map(move |decorator| match &decorator.value {
-                Decorator::Import(_, path) => path
-                    .map(|path| {
-                        let mut cur_loc = file_loc.to_path_buf();
-                        cur_loc.pop();
-                        cur_loc.push(path);
-                        Some(cur_loc)
+                Decorator::Import(_, source) => source
+                    .map(|source| match source {
+                        ImportSource::Path(path) => {
+                            let mut cur_loc = file_loc.to_path_buf();
+                            cur_loc.pop();
+                            cur_loc.push(path);
+                            Some(cur_loc)
+                        }
+                        ImportSource::Endpoint(_) => None,
                     })
                     .transpose(),
                 _ => None,
             })
     }
 
+    /// the endpoints imported with `endpoint:`, which are resolved once every module's
+    /// endpoint is known
+    pub fn endpoint_dependencies(&self) -> impl Iterator<Item = SpanRef<'a, &'a str>> + '_ {
+        self.0
+            .iter()
+            .filter_map(|decorator| match &decorator.value {
+                Decorator::Import(_, source) => match source.value {
+                    ImportSource::Endpoint(endpoint) => Some(source.with(endpoint)),
+                    ImportSource::Path(_) => None,
+                },
+                _ => None,
+            })
+    }
+
     // TODO do not permit decorators with stuff after that isn't a space
     pub fn parse(input: &'a str) -> PResult<Self> {
         let (input, decorators) = fold_many0(
             delimited(
                 space,
-                alt((
-                    with_multi_line_comment(SpanRef::<Decorator>::parse(Decorator::parse))
-                        .map(Either::Left),
-                    with_single_line_comment(SpanRef::<Decorator>::parse(Decorator::parse))
-                        .map(Either::Right),
-                )),
+                // the annotation of the first statement ends the front matter
+                preceded(
+                    not(statement_annotation),
+                    alt((
+                        with_multi_line_comment(Decorator::parse_spans).map(Either::Left),
+                        with_single_line_comment(Decorator::parse_spans).map(Either::Right),
+                    )),
+                ),
                 space,
             ),
             vec![],
             |mut acc, item| match item {
-                Either::Left(item) => {
-                    acc.extend(item.into_iter());
+                Either::Left(items) => {
+                    acc.extend(items.into_iter().flatten());
                     acc
                 }
-                Either::Right(Some(item)) => {
-                    acc.push(item);
+                Either::Right(Some(items)) => {
+                    acc.extend(items);
                     acc
                 }
                 Either::Right(None) => acc,
@@ -212,38 +417,134 @@ mod tests {
 
     #[test]
     fn decorator_parse_test() {
+        fn unwrap_params<'a>(
+            params: Vec<(SpanRef<'a, &'a str>, Option<ParamKind>)>,
+        ) -> Vec<&'a str> {
+            params.into_iter().map(|(span, _)| span.value).collect()
+        }
+
         let test_str = r#"@param shalom_yiblet"#;
-        assert_eq!(Decorator::parse_param(test_str).unwrap().1, "shalom_yiblet");
+        assert_eq!(
+            unwrap_params(Decorator::parse_params(test_str).unwrap().1),
+            vec!["shalom_yiblet"]
+        );
 
         let test_str = r#"@param shalom"#;
-        assert_eq!(Decorator::parse_param(test_str).unwrap().1, "shalom");
+        assert_eq!(
+            unwrap_params(Decorator::parse_params(test_str).unwrap().1),
+            vec!["shalom"]
+        );
 
-        let test_str = "@endpoint getUsers \n\n";
-        assert_eq!(Decorator::parse_endpoint(test_str).unwrap().1, "getUsers");
+        let test_str = r#"@param id, email ,name"#;
+        assert_eq!(
+            unwrap_params(Decorator::parse_params(test_str).unwrap().1),
+            vec!["id", "email", "name"]
+        );
+
+        let test_str = r#"@param filter: json, id"#;
+        assert_eq!(
+            Decorator::parse_params(test_str)
+                .unwrap()
+                .1
+                .into_iter()
+                .map(|(span, kind)| (span.value, kind))
+                .collect::<Vec<_>>(),
+            vec![("filter", Some(ParamKind::Json)), ("id", None)]
+        );
+        assert!(matches!(
+            Decorator::parse_params("@param filter: blob"),
+            Err(nom::Err::Failure(_))
+        ));
+
+        let endpoints = |input| {
+            Decorator::parse_endpoints(input).map(|(_, endpoints)| {
+                endpoints
+                    .into_iter()
+                    .map(|endpoint| endpoint.value)
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(
+            endpoints("@endpoint getUsers \n\n").unwrap(),
+            vec!["getUsers"]
+        );
+        assert_eq!(
+            endpoints("@endpoint users.list \n\n").unwrap(),
+            vec!["users.list"]
+        );
+        assert_eq!(
+            endpoints("@endpoint get-user \n\n").unwrap(),
+            vec!["get-user"]
+        );
+        assert!(endpoints("@endpoint get-user. \n\n").is_err());
+        assert_eq!(
+            endpoints("@endpoint users.list, getUsers \n\n").unwrap(),
+            vec!["users.list", "getUsers"]
+        );
 
         let test_str = "@auth verify \n\n";
         assert_eq!(
             Decorator::parse_auth(test_str).unwrap().1,
-            AuthSettings::VerifyToken(None)
+            AuthSettings::VerifyToken(None, vec![])
         );
 
         let test_str = "@auth verify";
         assert_eq!(
             Decorator::parse_auth(test_str).unwrap().1,
-            AuthSettings::VerifyToken(None)
+            AuthSettings::VerifyToken(None, vec![])
         );
 
         let test_str = "@auth verify 2d \n\n";
         assert_eq!(
             Decorator::parse_auth(test_str).unwrap().1,
-            AuthSettings::VerifyToken(Some(60 * 60 * 24 * 2))
+            AuthSettings::VerifyToken(Some(60 * 60 * 24 * 2), vec![])
+        );
+
+        let test_str = "@auth verify role=admin \n\n";
+        assert_eq!(
+            Decorator::parse_auth(test_str).unwrap().1,
+            AuthSettings::VerifyToken(None, vec!["admin".to_string()])
         );
 
+        let test_str = "@auth verify 2d role=admin,billing-editor -- staff only\n";
+        assert_eq!(
+            Decorator::parse_auth(test_str).unwrap().1,
+            AuthSettings::VerifyToken(
+                Some(60 * 60 * 24 * 2),
+                vec!["admin".to_string(), "billing-editor".to_string()]
+            )
+        );
+
+        assert!(matches!(
+            Decorator::parse_auth("@auth verify role=\n"),
+            Err(nom::Err::Failure(_))
+        ));
+
         let test_str = "@auth authorize 32d \n\n";
         assert_eq!(
             Decorator::parse_auth(test_str).unwrap().1,
             AuthSettings::SetToken(60 * 60 * 24 * 32)
         );
+
+        assert_eq!(Decorator::parse_cache("@cache 30s \n").unwrap().1, 30);
+        assert_eq!(Decorator::parse_cache("@cache 5m").unwrap().1, 300);
+        assert!(matches!(
+            Decorator::parse_cache("@cache soon"),
+            Err(nom::Err::Failure(_))
+        ));
+
+        assert_eq!(
+            Decorator::parse_tag("@tag billing \n").unwrap().1,
+            "billing"
+        );
+        assert_eq!(
+            Decorator::parse_tag("@tag admin-tools").unwrap().1,
+            "admin-tools"
+        );
+        assert!(matches!(
+            Decorator::parse_tag("@tag \n"),
+            Err(nom::Err::Failure(_))
+        ));
     }
 
     #[test]
@@ -251,18 +552,30 @@ mod tests {
         fn unwrap_spans<A, B>((v1, v2): (SpanRef<A>, SpanRef<B>)) -> (A, B) {
             (v1.value, v2.value)
         }
+        assert!(Decorator::parse_stream("@stream \n").is_ok());
+        assert!(Decorator::parse_stream("@streaming \n").is_err());
+
         let test_str = "@import friends_of from './../friends' \n\n";
         assert_eq!(
             unwrap_spans(Decorator::parse_import(test_str).unwrap().1),
-            ("friends_of", Path::new("./../friends"))
+            ("friends_of", ImportSource::Path(Path::new("./../friends")))
         );
 
         let test_str = "@import friends_of from 'friends' \n\n";
         assert_eq!(
             unwrap_spans(Decorator::parse_import(test_str).unwrap().1),
-            ("friends_of", Path::new("friends"))
+            ("friends_of", ImportSource::Path(Path::new("friends")))
         );
 
+        let test_str = "@import friends_of from endpoint:friends.list \n\n";
+        assert_eq!(
+            unwrap_spans(Decorator::parse_import(test_str).unwrap().1),
+            ("friends_of", ImportSource::Endpoint("friends.list"))
+        );
+
+        let test_str = "@import friends_of from endpoint:friends. \n\n";
+        assert!(Decorator::parse_import(test_str).is_err());
+
         let test_str = "@import friends_of from '/friends' \n\n";
         assert!(Decorator::parse_import(test_str).is_err());
 
@@ -290,7 +603,39 @@ select * from users;
             parse_decorators.map(unwrap).parse(test_str).unwrap(),
             (
                 "select * from users;\n",
-                vec![Decorator::Endpoint("getUser"), Decorator::Param("users")]
+                vec![
+                    Decorator::Endpoint("getUser"),
+                    Decorator::Param("users", None)
+                ]
+            )
+        );
+
+        let test_str = r#"
+-- @endpoint getUser -- by id
+-- @param id  -- the user id
+-- @param name, email--and how to reach them
+/* @stream -- rows can be many
+ * @auth verify -- logged in users only */
+-- @subscribe user_events
+-- @tag users
+-- @on_conflict "the user already exists ({constraint})" -- shown to clients
+select * from users;
+"#;
+        assert_eq!(
+            parse_decorators.map(unwrap).parse(test_str).unwrap(),
+            (
+                "select * from users;\n",
+                vec![
+                    Decorator::Endpoint("getUser"),
+                    Decorator::Param("id", None),
+                    Decorator::Param("name", None),
+                    Decorator::Param("email", None),
+                    Decorator::Stream,
+                    Decorator::Auth(AuthSettings::VerifyToken(None, vec![])),
+                    Decorator::Subscribe("user_events"),
+                    Decorator::Tag("users"),
+                    Decorator::OnConflict("the user already exists ({constraint})"),
+                ]
             )
         );
 
@@ -313,7 +658,10 @@ select * from users;
             parse_decorators.map(unwrap).parse(test_str).unwrap(),
             (
                 "select * from users;\n",
-                vec![Decorator::Endpoint("getUser"), Decorator::Param("users")]
+                vec![
+                    Decorator::Endpoint("getUser"),
+                    Decorator::Param("users", None)
+                ]
             )
         );
 
@@ -342,6 +690,31 @@ select * from users;
 -- @auth vxerify
 -- @param users
 select * from users;
+"#;
+        assert!(parse_decorators(test_str).is_err());
+
+        let test_str = r#"
+/* @endpoint getUser
+ * @param id, email */
+-- @param name
+select * from users;
+"#;
+        assert_eq!(
+            parse_decorators.map(unwrap).parse(test_str).unwrap(),
+            (
+                "select * from users;\n",
+                vec![
+                    Decorator::Endpoint("getUser"),
+                    Decorator::Param("id", None),
+                    Decorator::Param("email", None),
+                    Decorator::Param("name", None)
+                ]
+            )
+        );
+
+        let test_str = r#"
+-- @param id, email,
+select * from users;
 "#;
         assert!(parse_decorators(test_str).is_err());
     }
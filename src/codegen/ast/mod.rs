@@ -4,6 +4,7 @@ mod parser;
 mod sql;
 
 pub use ast::Ast;
-pub use decorator::{Decorator, Decorators};
-pub use sql::InterpSpan;
-pub use sql::StatementSpan;
+pub use decorator::{Decorator, Decorators, ImportSource};
+pub use parser::is_endpoint_separator;
+pub use sql::{ArgSpan, InterpSpan};
+pub use sql::{StatementAnnotation, StatementSpan};
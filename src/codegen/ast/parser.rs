@@ -1,9 +1,9 @@
 use nom::{
     branch::alt,
-    bytes::complete::{is_not, tag, take, take_till, take_while},
+    bytes::complete::{is_not, tag, take, take_till, take_while, take_while1},
     character::complete::satisfy,
     combinator::{cut, eof, opt, peek},
-    multi::{fold_many0, separated_list0},
+    multi::{fold_many0, many0, separated_list0},
     sequence::delimited,
     Err, Parser,
 };
@@ -154,6 +154,7 @@ where
         .and(tag("*/"));
 
     move |input: &'a str| {
+        let opening = input;
         let (input, _) = start.parse(input)?;
         let (input, res): (&'a str, Vec<Option<O>>) = separated_list0(
             |c| delimiter.parse(c),
@@ -161,7 +162,14 @@ where
                 .map(Some)
                 .or(inactive_comment.map(|_| None)),
         )(input)?;
-        let (input, _) = cut(|c| end.parse(c)).parse(input)?;
+        let (input, _) = cut(|c| end.parse(c)).parse(input).map_err(|err| {
+            // point at the opening of the comment when it is never closed
+            if input.contains("*/") {
+                err
+            } else {
+                Err::Failure(ParseError::const_error(opening, "comment is unterminated"))
+            }
+        })?;
         Ok((input, res.into_iter().filter_map(|c| c).collect()))
     }
 }
@@ -180,6 +188,32 @@ pub fn is_alpha_or_underscore(chr: char) -> bool {
     chr.is_alphanumeric() || chr == '_'
 }
 
+pub fn is_endpoint_separator(chr: char) -> bool {
+    chr == '-' || chr == '.'
+}
+
+/// parses an endpoint name made up of words joined by '-' or '.'
+/// examples:
+///     getUsers
+///     users.list
+///     get-user
+pub fn endpoint_name(input: &str) -> PResult<'_, &str> {
+    let (output, _) = take_while1(is_alpha_or_underscore)
+        .and(many0(
+            satisfy(is_endpoint_separator).and(take_while1(is_alpha_or_underscore)),
+        ))
+        .parse(input)?;
+
+    if output.starts_with(is_endpoint_separator) {
+        return Err(Err::Failure(ParseError::const_error(
+            output,
+            "'-' and '.' must be placed between the words of an endpoint name",
+        )));
+    }
+
+    Ok((output, &input[..input.len() - output.len()]))
+}
+
 #[cfg(test)]
 mod tests {
     use nom::sequence::delimited;
@@ -233,6 +267,19 @@ mod tests {
         assert_eq!(parser.parse(test_str).unwrap().1.len(), 4);
     }
 
+    #[test]
+    fn endpoint_name_test() {
+        assert_eq!(endpoint_name("getUsers").unwrap(), ("", "getUsers"));
+        assert_eq!(
+            endpoint_name("users.list \n").unwrap(),
+            (" \n", "users.list")
+        );
+        assert_eq!(endpoint_name("get-user.v2").unwrap(), ("", "get-user.v2"));
+        assert!(endpoint_name("-users").is_err());
+        assert!(endpoint_name("users.").is_err());
+        assert!(endpoint_name("users--list").is_err());
+    }
+
     #[test]
     fn separated_list_test() {
         let mut parser = separated_list0(tag(",").and(space), tag("t"));
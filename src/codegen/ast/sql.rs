@@ -1,8 +1,10 @@
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_while1},
-    combinator::opt,
+    character::complete::{digit1, satisfy},
+    combinator::{eof, not, opt, peek, recognize},
     multi::{fold_many1, separated_list0, separated_list1},
+    number::complete::recognize_float,
     sequence::{delimited, preceded, terminated},
     Parser,
 };
@@ -10,41 +12,145 @@ use nom::{
 use super::{
     super::result::{ErrorKind, PResult, ParseError},
     super::span_ref::SpanRef,
-    parser::{is_alpha_or_underscore, space, string_literal},
+    parser::{is_alpha_or_underscore, line_space0, space, string_literal},
 };
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct StatementSpan<'a>(pub Vec<SpanRef<'a, InterpSpan<'a>>>);
 
 impl<'a> StatementSpan<'a> {
     pub fn is_empty(&self) -> bool {
         self.0.iter().all(|interp| match &interp.value {
-            // all literals are pure whitespace
-            InterpSpan::Literal(lit) => lit.find(|chr: char| !chr.is_whitespace()).is_none(),
+            // all literals are pure whitespace or comments
+            InterpSpan::Literal(lit) => is_blank(lit),
 
             // if using a call site then the statement is nonempty
             InterpSpan::CallSite(_, _) => true,
+            InterpSpan::Conditional(_, _) | InterpSpan::Foreach(_, _) => true,
 
             // other types of interps do not exist
             _ => false,
         })
     }
+
+    /// the annotations in the `--` comments before the statement's sql
+    pub fn annotations(&self) -> Vec<StatementAnnotation> {
+        let mut annotations = vec![];
+        let mut text = match self.0.first().map(|interp| &interp.value) {
+            Some(InterpSpan::Literal(lit)) => lit.as_str(),
+            _ => return annotations,
+        };
+        loop {
+            text = text.trim_start();
+            if !text.starts_with("--") {
+                return annotations;
+            }
+            if let Ok((_, annotation)) = statement_annotation(text) {
+                annotations.push(annotation);
+            }
+            text = text.find('\n').map_or("", |idx| &text[idx..]);
+        }
+    }
+}
+
+/// a line comment that annotates the statement after it instead of the module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatementAnnotation {
+    /// `-- @optional` runs the statement inside of a savepoint
+    Optional,
+    /// `-- @returns_rows` returns the rows of the statement instead of the last one's
+    ReturnsRows,
+}
+
+/// examples:
+///     -- @optional
+///     -- @returns_rows
+pub fn statement_annotation<'a>(input: &'a str) -> PResult<'a, StatementAnnotation> {
+    delimited(
+        tag("--").and(line_space0).and(tag("@")),
+        terminated(
+            alt((
+                tag("optional").map(|_| StatementAnnotation::Optional),
+                tag("returns_rows").map(|_| StatementAnnotation::ReturnsRows),
+            )),
+            not(satisfy(is_alpha_or_underscore)),
+        ),
+        line_space0.and(alt((tag("\n"), eof))),
+    )
+    .parse(input)
+}
+
+/// whether the text only has whitespace and `--` or `/* */` comments
+fn is_blank(mut text: &str) -> bool {
+    loop {
+        text = text.trim_start();
+        if text.starts_with("--") {
+            text = text.find('\n').map_or("", |idx| &text[idx..]);
+        } else if text.starts_with("/*") {
+            text = text.find("*/").map_or("", |idx| &text[idx + 2..]);
+        } else {
+            return text.is_empty();
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum InterpSpan<'a> {
     Literal(String), // literals are parsed combined together
     Param(&'a str),
-    AuthParam(&'a str),
-    CallSite(&'a str, Vec<SpanRef<'a, &'a str>>),
+    /// a parameter bound by its position in an array payload like '@1'
+    PositionalParam(usize),
+    AuthParam(&'a str), // dotted path into the auth claims like 'org.id'
+    CallSite(&'a str, Vec<SpanRef<'a, ArgSpan<'a>>>),
+    /// a fragment that is only kept when the parameter is in the payload
+    ///     @if(status) AND status = @status @endif
+    Conditional(&'a str, Vec<SpanRef<'a, InterpSpan<'a>>>),
+    /// a fragment that is repeated for every row of an array parameter, separated by commas
+    ///     @foreach(rows) (@rows.email, @rows.name) @endforeach
+    Foreach(&'a str, Vec<SpanRef<'a, InterpSpan<'a>>>),
+    /// a field of the current row inside of a `@foreach` fragment like '@rows.email'
+    Field(&'a str, &'a str),
+}
+
+impl<'a> InterpSpan<'a> {
+    /// the interps along with the interps inside of their conditional and foreach fragments
+    pub fn flatten<'b>(
+        interps: &'b [SpanRef<'a, InterpSpan<'a>>],
+    ) -> Vec<&'b SpanRef<'a, InterpSpan<'a>>> {
+        let mut flattened = vec![];
+        for interp in interps {
+            flattened.push(interp);
+            if let InterpSpan::Conditional(_, fragment) | InterpSpan::Foreach(_, fragment) =
+                &interp.value
+            {
+                flattened.extend(Self::flatten(fragment));
+            }
+        }
+        flattened
+    }
+}
+
+/// an argument passed into a call site
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgSpan<'a> {
+    Param(&'a str),   // 'id' or '@id'
+    Literal(&'a str), // ''active'' or '42'
 }
 
 #[derive(Debug, PartialEq, Eq)]
 enum Token<'a> {
-    Param(&'a str),                               // 'hello'
-    AuthParam(&'a str),                           // 'hello'
-    CallSite(&'a str, Vec<SpanRef<'a, &'a str>>), // 'hello'
-    StringLiteral(&'a str),                       // '" thing "'
+    Param(&'a str),                                   // 'hello'
+    PositionalParam(usize),                           // '@1'
+    AuthParam(&'a str),                               // 'hello'
+    CallSite(&'a str, Vec<SpanRef<'a, ArgSpan<'a>>>), // 'hello'
+    If(&'a str),                                      // '@if(hello)'
+    EndIf,                                            // '@endif'
+    Foreach(&'a str),                                 // '@foreach(rows)'
+    EndForeach,                                       // '@endforeach'
+    Field(&'a str, &'a str),                          // '@rows.email'
+    EscapedAt,                                        // '@@' before a word
+    Annotation(&'a str),                              // '-- @optional'
+    StringLiteral(&'a str),                           // '" thing "'
     Word(&'a str),
     Space(&'a str),
     Other(char),
@@ -62,10 +168,71 @@ fn lex_at_word<'a>(input: &'a str) -> PResult<'a, &'a str> {
     .parse(input)
 }
 
+/// `@1` but not `@1st`, which is a named parameter
+fn lex_positional<'a>(input: &'a str) -> PResult<'a, usize> {
+    let (rest, digits) = preceded(
+        nom::character::complete::char('@'),
+        terminated(digit1, not(satisfy(is_alpha_or_underscore))),
+    )
+    .parse(input)?;
+    let position = digits.parse().map_err(|_| {
+        nom::Err::Failure(ParseError::const_error(
+            input,
+            "positional parameter is too large",
+        ))
+    })?;
+    Ok((rest, position))
+}
+
+/// a dotted path of words such as 'org.id'
+fn lex_path<'a>(input: &'a str) -> PResult<'a, &'a str> {
+    recognize(separated_list1(
+        nom::character::complete::char('.'),
+        lex_word,
+    ))
+    .parse(input)
+}
+
 fn lex_string_literal<'a>(input: &'a str) -> PResult<'a, &'a str> {
     string_literal(input)
 }
 
+/// `@@word` is written as `@word` instead of starting a parameter. a `@@` that is not
+/// followed by a word, like postgres' text search operator, is left alone
+fn lex_escaped_at<'a>(input: &'a str) -> PResult<'a, &'a str> {
+    terminated(tag("@@"), peek(satisfy(is_alpha_or_underscore))).parse(input)
+}
+
+/// replaces every escaped `@@word` in the text with `@word`
+fn unescape_at(text: &str) -> String {
+    let mut buf = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(idx) = rest.find("@@") {
+        let (before, after) = rest.split_at(idx);
+        buf.push_str(before);
+        buf.push_str(if lex_escaped_at(after).is_ok() {
+            "@"
+        } else {
+            "@@"
+        });
+        rest = &after[2..];
+    }
+    buf.push_str(rest);
+    buf
+}
+
+fn lex_number<'a>(input: &'a str) -> PResult<'a, &'a str> {
+    terminated(recognize_float, not(satisfy(is_alpha_or_underscore))).parse(input)
+}
+
+fn lex_arg<'a>(input: &'a str) -> PResult<'a, ArgSpan<'a>> {
+    alt((
+        lex_string_literal.map(ArgSpan::Literal),
+        lex_number.map(ArgSpan::Literal),
+        preceded(opt(tag("@")), lex_word).map(ArgSpan::Param),
+    ))(input)
+}
+
 fn lex_end_statement<'a>(input: &'a str) -> PResult<'a, ()> {
     nom::character::complete::char(';').map(|_| ()).parse(input)
 }
@@ -91,28 +258,57 @@ fn lex_other_char<'a>(input: &'a str) -> PResult<'a, char> {
 fn parse_token<'a>(input: &'a str) -> PResult<'a, Token<'a>> {
     {
         use Token::*;
-        let auth_param = preceded(tag("@auth."), lex_word).map(AuthParam);
+        let auth_param = preceded(tag("@auth."), lex_path).map(AuthParam);
+        let positional_param = lex_positional.map(PositionalParam);
         let param = lex_at_word.map(Param);
         let call_site = lex_at_word
             .and(delimited(
                 tag("(").and(space),
                 terminated(
                     separated_list0(space.and(tag(",")).and(space), |input: &'a str| {
-                        let (input, res) = SpanRef::parse(lex_word)(input)?;
+                        let (input, res) = SpanRef::parse(lex_arg)(input)?;
                         Ok((input, res))
                     }),
                     opt(space.and(tag(",")).and(space)),
                 ),
                 space.and(tag(")")),
             ))
-            .map(|(func, params): (&'a str, Vec<SpanRef<'a, &'a str>>)| CallSite(func, params));
+            .map(|(func, params): (&'a str, Vec<SpanRef<'a, ArgSpan<'a>>>)| CallSite(func, params));
+        let if_start = delimited(
+            tag("@if").and(space).and(tag("(")).and(space),
+            lex_word,
+            space.and(tag(")")),
+        )
+        .map(If);
+        let end_if = terminated(tag("@endif"), not(satisfy(is_alpha_or_underscore))).map(|_| EndIf);
+        let foreach_start = delimited(
+            tag("@foreach").and(space).and(tag("(")).and(space),
+            lex_word,
+            space.and(tag(")")),
+        )
+        .map(Foreach);
+        let end_foreach = terminated(tag("@endforeach"), not(satisfy(is_alpha_or_underscore)))
+            .map(|_| EndForeach);
+        let field = lex_at_word
+            .and(preceded(tag("."), lex_word))
+            .map(|(param, field)| Field(param, field));
+        let escaped_at = lex_escaped_at.map(|_| EscapedAt);
+        let annotation = recognize(statement_annotation).map(Annotation);
         let string_literal = lex_string_literal.map(StringLiteral);
         let word = lex_word.map(Word);
         let space = lex_space.map(Space);
         let other = lex_other_char.map(Other);
         let (input, output) = alt((
+            escaped_at,
+            annotation,
+            if_start,
+            end_if,
+            foreach_start,
+            end_foreach,
             call_site,
             auth_param,
+            positional_param,
+            field,
             param,
             string_literal,
             space,
@@ -123,6 +319,106 @@ fn parse_token<'a>(input: &'a str) -> PResult<'a, Token<'a>> {
     }
 }
 
+/// a part of a statement before the conditional and foreach fragments are nested
+#[derive(Clone)]
+enum Piece<'a> {
+    Interp(InterpSpan<'a>),
+    If(&'a str),
+    EndIf,
+    Foreach(&'a str),
+    EndForeach,
+}
+
+/// the kind of fragment that is still waiting for its end
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Fragment {
+    If,
+    Foreach,
+}
+
+/// a fragment that is still open along with the parameter it was opened with and the
+/// interps inside of it
+type OpenFragment<'a> = (
+    Fragment,
+    SpanRef<'a, &'a str>,
+    Vec<SpanRef<'a, InterpSpan<'a>>>,
+);
+
+/// nests the interps between each `@if` and its `@endif` into a conditional fragment
+/// and the ones between each `@foreach` and its `@endforeach` into a foreach fragment
+fn nest_fragments<'a>(
+    pieces: Vec<SpanRef<'a, Piece<'a>>>,
+) -> Result<Vec<SpanRef<'a, InterpSpan<'a>>>, ParseError<'a>> {
+    let mut open: Vec<OpenFragment<'a>> = vec![];
+    let mut statement = vec![];
+    for piece in pieces {
+        let (kind, end_error) = match piece.value {
+            Piece::Interp(interp) => {
+                if let InterpSpan::Field(param, _) = interp {
+                    let in_foreach = open
+                        .iter()
+                        .any(|(kind, open, _)| *kind == Fragment::Foreach && open.value == param);
+                    if !in_foreach {
+                        Err(ParseError::const_error(
+                            piece.start,
+                            "row fields can only be used inside of a @foreach over their parameter",
+                        )
+                        .spanning(piece.end))?
+                    }
+                }
+                let interp = SpanRef {
+                    start: piece.start,
+                    end: piece.end,
+                    value: interp,
+                };
+                match open.last_mut() {
+                    Some((_, _, fragment)) => fragment.push(interp),
+                    None => statement.push(interp),
+                }
+                continue;
+            }
+            Piece::If(param) => {
+                open.push((Fragment::If, piece.with(param), vec![]));
+                continue;
+            }
+            Piece::Foreach(param) => {
+                open.push((Fragment::Foreach, piece.with(param), vec![]));
+                continue;
+            }
+            Piece::EndIf => (Fragment::If, "@endif without a matching @if"),
+            Piece::EndForeach => (Fragment::Foreach, "@endforeach without a matching @foreach"),
+        };
+
+        let (param, fragment) = match open.pop() {
+            Some((open, param, fragment)) if open == kind => (param, fragment),
+            _ => Err(ParseError::const_error(piece.start, end_error))?,
+        };
+        let nested = SpanRef {
+            start: param.start,
+            end: piece.end,
+            value: match kind {
+                Fragment::If => InterpSpan::Conditional(param.value, fragment),
+                Fragment::Foreach => InterpSpan::Foreach(param.value, fragment),
+            },
+        };
+        match open.last_mut() {
+            Some((_, _, fragment)) => fragment.push(nested),
+            None => statement.push(nested),
+        }
+    }
+    match open.pop() {
+        Some((Fragment::If, param, _)) => Err(ParseError::const_error(
+            param.start,
+            "@if is missing its @endif",
+        )),
+        Some((Fragment::Foreach, param, _)) => Err(ParseError::const_error(
+            param.start,
+            "@foreach is missing its @endforeach",
+        )),
+        None => Ok(statement),
+    }
+}
+
 fn parse_sql_statement<'a>(input: &'a str) -> PResult<'a, StatementSpan<'a>> {
     use Token::*;
 
@@ -144,9 +440,17 @@ fn parse_sql_statement<'a>(input: &'a str) -> PResult<'a, StatementSpan<'a>> {
         |(mut builder, mut statement), token: SpanRef<'a, Token>| {
             // first set builder
             match &token.value {
-                Param(_) | AuthParam(_) | CallSite(_, _) => {
+                Param(_)
+                | PositionalParam(_)
+                | AuthParam(_)
+                | CallSite(_, _)
+                | If(_)
+                | EndIf
+                | Foreach(_)
+                | EndForeach
+                | Field(_, _) => {
                     if builder.len() != 0 {
-                        statement.push(builder.map(InterpSpan::Literal));
+                        statement.push(builder.map(|lit| Piece::Interp(InterpSpan::Literal(lit))));
                         builder = SpanRef {
                             start: token.end,
                             end: token.end,
@@ -154,65 +458,76 @@ fn parse_sql_statement<'a>(input: &'a str) -> PResult<'a, StatementSpan<'a>> {
                         };
                     }
                 }
-                StringLiteral(lit) | Word(lit) | Space(lit) => {
+                StringLiteral(lit) => {
+                    builder.push_str(unescape_at(lit).as_str());
+                }
+                Word(lit) | Space(lit) | Annotation(lit) => {
                     builder.push_str(lit);
                 }
+                EscapedAt => {
+                    builder.push('@');
+                }
                 Other(chr) => {
                     builder.push(*chr);
                 }
             };
 
             // second add the current parameter
-            match &token.value {
-                Param(param) => {
-                    statement.push(token.as_ref().map(|_| InterpSpan::Param(param)));
-                }
-                AuthParam(param) => {
-                    statement.push(token.as_ref().map(|_| InterpSpan::AuthParam(param)));
+            let piece = match &token.value {
+                Param(param) => Some(Piece::Interp(InterpSpan::Param(param))),
+                PositionalParam(position) => {
+                    Some(Piece::Interp(InterpSpan::PositionalParam(*position)))
                 }
+                AuthParam(param) => Some(Piece::Interp(InterpSpan::AuthParam(param))),
                 CallSite(func, args) => {
-                    statement.push(
-                        token
-                            .as_ref()
-                            .map(|_| InterpSpan::CallSite(func, args.clone())),
-                    );
+                    Some(Piece::Interp(InterpSpan::CallSite(func, args.clone())))
                 }
-                _ => {}
+                If(param) => Some(Piece::If(param)),
+                EndIf => Some(Piece::EndIf),
+                Foreach(param) => Some(Piece::Foreach(param)),
+                EndForeach => Some(Piece::EndForeach),
+                Field(param, field) => Some(Piece::Interp(InterpSpan::Field(param, field))),
+                _ => None,
             };
+            if let Some(piece) = piece {
+                statement.push(token.with(piece));
+            }
 
             (builder, statement)
         },
     )
     .map(|(final_literal, mut statement)| {
-        let statement_span = if final_literal.len() == 0 {
-            statement
-        } else {
-            statement.push(final_literal.map(InterpSpan::Literal));
-            statement
-        };
-        StatementSpan(statement_span)
+        if final_literal.len() != 0 {
+            statement.push(final_literal.map(|lit| Piece::Interp(InterpSpan::Literal(lit))));
+        }
+        statement
     });
 
-    let (input, statement) =
-        parse_statement
-            .parse(input)
-            .map_err(|err: nom::Err<ParseError>| {
-                err.map(|err| match err {
-                    ParseError::NomError(input, nom::error::ErrorKind::Many1) => {
-                        ParseError::const_error(input, "must have at least one sql statement")
-                    }
-                    _ => err,
-                })
-            })?;
-    Ok((input, statement))
+    let (input, pieces) = parse_statement
+        .parse(input)
+        .map_err(|err: nom::Err<ParseError>| {
+            err.map(|err| match err {
+                ParseError::NomError(input, nom::error::ErrorKind::Many1) => {
+                    ParseError::const_error(input, "must have at least one sql statement")
+                }
+                _ => err,
+            })
+        })?;
+    let statement = nest_fragments(pieces).map_err(nom::Err::Failure)?;
+    Ok((input, StatementSpan(statement)))
 }
 
 pub fn parse_statements<'a>(og_input: &'a str) -> PResult<'a, Vec<SpanRef<'a, StatementSpan<'a>>>> {
+    // statements between semicolons can be empty so leading, repeated and trailing
+    // semicolons are all skipped the same way
     let (input, res): (&str, Vec<SpanRef<StatementSpan>>) = separated_list1(
         fold_many1(lex_end_statement, (), |_, _| ()),
-        SpanRef::<StatementSpan>::parse(parse_sql_statement),
+        SpanRef::<StatementSpan>::parse(|input| {
+            opt(parse_sql_statement)
+                .map(Option::unwrap_or_default)
+                .parse(input)
+        }),
     )(og_input)?;
-    let (input, _) = opt(lex_end_statement)(input)?;
 
     let res: Vec<SpanRef<StatementSpan>> = res
         .into_iter()
@@ -256,18 +571,59 @@ mod tests {
         let call_site = crate::matches_map!(token,
             Token::CallSite("func", vals) => vals.iter().map(|span| span.value).collect::<Vec<_>>()
         );
-        assert_eq!(call_site, Some(vec!["id", "b"]));
+        assert_eq!(
+            call_site,
+            Some(vec![ArgSpan::Param("id"), ArgSpan::Param("b")])
+        );
 
         let test_str = r#"@func(id, b, c)"#;
         let (_, token) = parse_token(test_str).unwrap();
         let call_site = crate::matches_map!(token,
             Token::CallSite("func", vals) => vals.iter().map(|span| span.value).collect::<Vec<_>>()
         );
-        assert_eq!(call_site, Some(vec!["id", "b", "c"]));
+        assert_eq!(
+            call_site,
+            Some(vec![
+                ArgSpan::Param("id"),
+                ArgSpan::Param("b"),
+                ArgSpan::Param("c")
+            ])
+        );
+
+        let test_str = r#"@func('active', @tenant_id, 4.5)"#;
+        let (_, token) = parse_token(test_str).unwrap();
+        let call_site = crate::matches_map!(token,
+            Token::CallSite("func", vals) => vals.iter().map(|span| span.value).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            call_site,
+            Some(vec![
+                ArgSpan::Literal("'active'"),
+                ArgSpan::Param("tenant_id"),
+                ArgSpan::Literal("4.5")
+            ])
+        );
+
+        let test_str = r#"@2"#;
+        let (_, token) = parse_token(test_str).unwrap();
+        assert_eq!(token, Token::PositionalParam(2));
+
+        let test_str = r#"@1st"#;
+        let (_, token) = parse_token(test_str).unwrap();
+        assert_eq!(token, Token::Param("1st"));
 
         let test_str = r#"'testing'"#;
         let (_, token) = parse_token(test_str).unwrap();
         assert_eq!(token, Token::StringLiteral("'testing'"));
+
+        let test_str = r#"@auth.user_id"#;
+        let (_, token) = parse_token(test_str).unwrap();
+        assert_eq!(token, Token::AuthParam("user_id"));
+
+        let test_str = r#"@auth.org.id."#;
+        let (rest, token) = parse_token(test_str).unwrap();
+        assert_eq!(token, Token::AuthParam("org.id"));
+        assert_eq!(rest, ".");
     }
 
     #[test]
@@ -323,4 +679,162 @@ mod tests {
         "#;
         let _err = parse_statements(test_str).unwrap_err();
     }
+
+    #[test]
+    fn trailing_statements_test() {
+        for sql in &[
+            "select 1",
+            "select 1;",
+            "select 1;;;",
+            ";select 1; ;\n",
+            "select 1;\n-- trailing comment",
+            "select 1;\n/* trailing\n comment */\n",
+            "select 1; -- first\n; /* second */",
+        ] {
+            let (rest, statements) = parse_statements(sql).unwrap();
+            assert_eq!((rest, statements.len()), ("", 1), "{:?}", sql);
+        }
+
+        // a comment after the last statement without a semicolon stays part of it
+        let (_, statements) = parse_statements("select 1\n-- trailing comment").unwrap();
+        assert_eq!(statements.len(), 1);
+
+        for sql in &["", "  \n", ";", "; ; ;", "-- only a comment", "/* unterminated"] {
+            let err = parse_statements(sql).unwrap_err();
+            assert!(
+                matches!(
+                    err,
+                    nom::Err::Failure(ParseError::ErrorKind(
+                        _,
+                        ErrorKind::ConstError("this module has no statements")
+                    ))
+                ),
+                "{:?}: {:?}",
+                sql,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn conditional_test() {
+        let interps = |statement: &StatementSpan<'static>| {
+            statement
+                .0
+                .iter()
+                .map(|span| span.value.clone())
+                .collect::<Vec<_>>()
+        };
+        let (_, statement) =
+            parse_sql_statement("where true @if(status) and status = @status @endif").unwrap();
+        assert_eq!(statement.0.len(), 2);
+        assert_eq!(
+            statement.0[0].value,
+            InterpSpan::Literal("where true ".into())
+        );
+        let fragment = crate::matches_map!(&statement.0[1].value,
+            InterpSpan::Conditional("status", fragment) => fragment.iter().map(|span| span.value.clone()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            fragment,
+            Some(vec![
+                InterpSpan::Literal(" and status = ".into()),
+                InterpSpan::Param("status"),
+                InterpSpan::Literal(" ".into()),
+            ])
+        );
+
+        // fragments nest
+        let (_, statement) = parse_sql_statement("@if(a) @a @if( b ) @b @endif @endif").unwrap();
+        assert_eq!(statement.0.len(), 1);
+        assert_eq!(InterpSpan::flatten(&statement.0).len(), 9);
+
+        for sql in &[
+            "select @if(a) @a",
+            "select @a @endif",
+            "select @if(a) @a; @endif",
+        ] {
+            assert!(
+                matches!(parse_sql_statement(sql), Err(nom::Err::Failure(_))),
+                "{}",
+                sql
+            );
+        }
+
+        // words that only start like the markers are parameters
+        let (_, statement) = parse_sql_statement("@endiff").unwrap();
+        assert_eq!(interps(&statement), vec![InterpSpan::Param("endiff")]);
+    }
+
+    #[test]
+    fn foreach_test() {
+        let (_, statement) =
+            parse_sql_statement("values @foreach(rows) (@rows.email, @rows.name) @endforeach")
+                .unwrap();
+        assert_eq!(statement.0.len(), 2);
+        let fragment = crate::matches_map!(&statement.0[1].value,
+            InterpSpan::Foreach("rows", fragment) => fragment.iter().map(|span| span.value.clone()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            fragment,
+            Some(vec![
+                InterpSpan::Literal(" (".into()),
+                InterpSpan::Field("rows", "email"),
+                InterpSpan::Literal(", ".into()),
+                InterpSpan::Field("rows", "name"),
+                InterpSpan::Literal(") ".into()),
+            ])
+        );
+
+        for sql in &[
+            "select @foreach(rows) @rows.id",
+            "select @foreach(rows) @rows.id @endif",
+            "select @if(rows) @foreach(rows) @rows.id @endif @endforeach",
+            "select @rows.id",
+            "select @foreach(other) @rows.id @endforeach",
+        ] {
+            assert!(
+                matches!(parse_sql_statement(sql), Err(nom::Err::Failure(_))),
+                "{}",
+                sql
+            );
+        }
+    }
+
+    #[test]
+    fn escaped_at_test() {
+        let (rest, token) = parse_token("@@example").unwrap();
+        assert_eq!((rest, token), ("example", Token::EscapedAt));
+        let (_, token) = parse_token("@@ to_tsquery").unwrap();
+        assert_eq!(token, Token::Other('@'));
+
+        let literals = |sql: &'static str| {
+            let (_, statement) = parse_sql_statement(sql).unwrap();
+            statement
+                .0
+                .into_iter()
+                .map(|span| span.value)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            literals("WHERE email LIKE '%@@example.com'"),
+            vec![InterpSpan::Literal(
+                "WHERE email LIKE '%@example.com'".into()
+            )]
+        );
+        assert_eq!(
+            literals("select @@name, @id"),
+            vec![
+                InterpSpan::Literal("select @name, ".into()),
+                InterpSpan::Param("id")
+            ]
+        );
+        // the text search operator keeps both signs
+        assert_eq!(
+            literals("where body @@ to_tsquery('a @@ b')"),
+            vec![InterpSpan::Literal(
+                "where body @@ to_tsquery('a @@ b')".into()
+            )]
+        );
+    }
 }
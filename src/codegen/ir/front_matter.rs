@@ -1,13 +1,14 @@
 use super::reserved_words::check_reserved_words;
 use crate::codegen::{
-    ast::Decorator,
+    ast::{is_endpoint_separator, Decorator, ImportSource},
     result::{CResult, IrErrorKind, ParseError},
     span_ref::SpanRef,
-    AuthSettings, Module,
+    AuthSettings, Module, ParamKind, Returns,
 };
 use std::{
     borrow::Borrow,
-    collections::{BTreeMap, BTreeSet},
+    collections::BTreeMap,
+    iter,
     ops::Deref,
     path::{Path, PathBuf},
 };
@@ -17,28 +18,63 @@ use std::{
 pub struct FrontMatter {
     /// canonicalized location
     pub location: PathBuf,
-    pub endpoint: Option<String>,
+    /// the name the module is served at followed by its aliases
+    pub endpoints: Vec<String>,
     pub params: Vec<String>,
+    /// the declared types of the parameters that have one
+    pub param_kinds: BTreeMap<String, ParamKind>,
     /// maps import name to canonicalized location and names of that module's parameters
     /// FIXME remove module parameter names this part would break if the importing module's
     /// changes it's parameter names during watch mode.
     pub imports: BTreeMap<String, (PathBuf, Vec<String>)>,
     pub auth_settings: Option<AuthSettings>,
+    /// whether rows are streamed to the client as they arrive
+    pub stream: bool,
+    /// seconds the rows of the endpoint are cached for
+    pub cache_ttl: Option<u64>,
+    /// the postgres channel that websocket clients of the endpoint listen to
+    pub subscribe: Option<String>,
+    /// the tags that group the endpoint in listings
+    pub tags: Vec<String>,
+    /// the message sent instead of the error of a unique or foreign key violation
+    pub on_conflict: Option<String>,
+    /// how the rows of the endpoint are unwrapped before they are sent
+    pub returns: Option<Returns>,
+    /// whether every statement only reads data so the endpoint can run on a replica.
+    /// set once the statements are parsed
+    pub read_only: bool,
 }
 
 impl FrontMatter {
     pub fn check_reserved_words<'a, 'b>(
         decorators: &'b Vec<SpanRef<'a, Decorator<'a>>>,
     ) -> impl Iterator<Item = ParseError<'a>> + 'b {
-        let iter = decorators
-            .iter()
-            .filter_map(|decorator| match decorator.deref() {
-                Decorator::Import(input, _path) => Some(*input),
-                Decorator::Endpoint(keyword) | Decorator::Param(keyword) => {
-                    Some(decorator.with(keyword))
+        let iter = decorators.iter().flat_map(|decorator| {
+            // need to use dynamic dispatch to allow for multiple return types
+            let iter: Box<dyn Iterator<Item = SpanRef<'a, &'b str>> + 'b> = match decorator.deref()
+            {
+                Decorator::Import(input, _path) => Box::new(iter::once(*input)),
+                // each word of a dotted or dashed endpoint name is checked on its own
+                Decorator::Endpoint(keyword) => {
+                    let keyword: &'a str = keyword;
+                    Box::new(
+                        keyword
+                            .split(is_endpoint_separator)
+                            .map(move |word| decorator.with(word)),
+                    )
                 }
-                Decorator::Auth(_) => None,
-            });
+                Decorator::Param(keyword, _) => Box::new(iter::once(decorator.with(*keyword))),
+                Decorator::Auth(_)
+                | Decorator::Stream
+                | Decorator::Cache(_)
+                | Decorator::Subscribe(_)
+                | Decorator::Tag(_)
+                | Decorator::OnConflict(_)
+                | Decorator::Returns(_) => Box::new(iter::empty()),
+            };
+
+            iter
+        });
 
         check_reserved_words(iter)
     }
@@ -55,6 +91,7 @@ impl FrontMatter {
         //     4. all parameters cannot conflict with imports
         //     5. all imports are from modules with one statement
         //     6. all imports have modules
+        //     7. no module imports itself
         //
         // for future consideration:
         //  if these rules get too complicated to maintain simply
@@ -65,18 +102,43 @@ impl FrontMatter {
             Decorator::Import(_, _) => 0,
             Decorator::Auth(_) => 1,
             Decorator::Endpoint(_) => 2,
-            Decorator::Param(_) => 3,
+            Decorator::Stream => 3,
+            Decorator::Cache(_) => 4,
+            Decorator::Subscribe(_) => 5,
+            Decorator::Tag(_) => 6,
+            Decorator::OnConflict(_) => 7,
+            Decorator::Returns(_) => 8,
+            Decorator::Param(..) => 9,
         });
 
-        let mut endpoint = None;
+        let mut endpoints: Vec<&str> = vec![];
         let mut params: Vec<String> = vec![];
-        let mut params_set = BTreeSet::new();
+        let mut param_kinds = BTreeMap::new();
+        // maps each parameter to where it was first declared
+        let mut params_set = BTreeMap::new();
         let mut import_map = BTreeMap::new();
         let mut auth_settings = None;
+        let mut stream = false;
+        let mut cache_ttl = None;
+        let mut subscribe = None;
+        let mut tags: Vec<String> = vec![];
+        let mut on_conflict = None;
+        let mut returns = None;
 
         let mut deps = vec![];
         let mut errors = vec![];
 
+        // a module is never among the modules it can import from so self imports are
+        // found by its own location and endpoint instead
+        let own_location = location.canonicalize().unwrap_or_else(|_| location.clone());
+        let own_endpoints: Vec<&str> = decorators
+            .iter()
+            .filter_map(|decorator| match decorator.value {
+                Decorator::Endpoint(endpoint) => Some(endpoint),
+                _ => None,
+            })
+            .collect();
+
         errors.extend(Self::check_reserved_words(&decorators));
         for decorator in decorators {
             match decorator.value {
@@ -89,19 +151,56 @@ impl FrontMatter {
                         ))
                     };
 
-                    let mut location = location.clone();
-                    location.pop();
-                    location.push(file.value);
-
-                    let location = match location.canonicalize() {
-                        Ok(location) => location,
-                        Err(_) => {
-                            errors.push(ParseError::IrErrorKind(
-                                file.start,
-                                IrErrorKind::ConstError("could not import module"),
+                    let location = match file.value {
+                        ImportSource::Path(path) => {
+                            let mut location = location.clone();
+                            location.pop();
+                            location.push(path);
+                            location.canonicalize().map_err(|_| {
+                                ParseError::IrErrorKind(
+                                    file.start,
+                                    IrErrorKind::ConstError("could not import module"),
+                                )
+                            })
+                        }
+                        ImportSource::Endpoint(endpoint) if own_endpoints.contains(&endpoint) => {
+                            Ok(own_location.clone())
+                        }
+                        // endpoints can only be found among the modules that were already built
+                        ImportSource::Endpoint(endpoint) => modules
+                            .iter()
+                            .find(|(_, module)| {
+                                let module: &Module = (*module).borrow();
+                                module
+                                    .front_matter
+                                    .endpoints
+                                    .iter()
+                                    .any(|name| name == endpoint)
+                            })
+                            .map(|(location, _)| {
+                                let location: &Path = location.borrow();
+                                location.to_path_buf()
+                            })
+                            .ok_or_else(|| {
+                                ParseError::IrErrorKind(
+                                    file.start,
+                                    IrErrorKind::UndefinedEndpointError(endpoint.to_string()),
+                                )
+                            }),
+                    };
+                    let location = match location {
+                        Ok(location) if location == own_location => {
+                            errors.push(ParseError::const_error(
+                                decorator.start,
+                                "module cannot import itself",
                             ));
                             continue;
                         }
+                        Ok(location) => location,
+                        Err(err) => {
+                            errors.push(err);
+                            continue;
+                        }
                     };
 
                     let module = match modules.get(location.as_path()) {
@@ -137,26 +236,127 @@ impl FrontMatter {
                 Decorator::Auth(val) => auth_settings = Some(val),
 
                 // endpoints
-                Decorator::Endpoint(dec) => match endpoint {
-                    Some(_) => Result::Err(ParseError::const_error(
+                Decorator::Endpoint(dec) if endpoints.contains(&dec) => Result::Err(
+                    ParseError::const_error(decorator.start, "endpoint already declared"),
+                )?,
+                Decorator::Endpoint(dec) => endpoints.push(dec),
+
+                // streaming
+                Decorator::Stream if stream => Result::Err(ParseError::const_error(
+                    decorator.start,
+                    "multiple stream declarations detected",
+                ))?,
+                Decorator::Stream => match auth_settings {
+                    Some(AuthSettings::SetToken(_)) | Some(AuthSettings::RemoveToken) => {
+                        Result::Err(ParseError::const_error(
+                            decorator.start,
+                            "streamed endpoints can not set or clear auth tokens",
+                        ))?
+                    }
+                    _ => stream = true,
+                },
+
+                // caching
+                Decorator::Cache(_) if cache_ttl.is_some() => {
+                    Result::Err(ParseError::const_error(
                         decorator.start,
-                        "multiple endpoint declarations detected",
-                    ))?,
-                    None => {
-                        endpoint = Some(dec.to_owned());
+                        "multiple cache declarations detected",
+                    ))?
+                }
+                Decorator::Cache(_) if stream => Result::Err(ParseError::const_error(
+                    decorator.start,
+                    "streamed endpoints can not be cached",
+                ))?,
+                Decorator::Cache(ttl) => match auth_settings {
+                    Some(AuthSettings::SetToken(_)) | Some(AuthSettings::RemoveToken) => {
+                        Result::Err(ParseError::const_error(
+                            decorator.start,
+                            "endpoints that set or clear auth tokens can not be cached",
+                        ))?
                     }
+                    _ => cache_ttl = Some(ttl),
                 },
 
-                // parameters
-                Decorator::Param(param) if params_set.contains(param) => Result::Err(
-                    ParseError::const_error(decorator.start, "parameter already declared"),
+                // subscriptions
+                Decorator::Subscribe(_) if subscribe.is_some() => {
+                    Result::Err(ParseError::const_error(
+                        decorator.start,
+                        "multiple subscribe declarations detected",
+                    ))?
+                }
+                Decorator::Subscribe(_) if stream || cache_ttl.is_some() => {
+                    Result::Err(ParseError::const_error(
+                        decorator.start,
+                        "subscribed endpoints can not be streamed or cached",
+                    ))?
+                }
+                Decorator::Subscribe(channel) => match auth_settings {
+                    Some(AuthSettings::SetToken(_)) | Some(AuthSettings::RemoveToken) => {
+                        Result::Err(ParseError::const_error(
+                            decorator.start,
+                            "subscribed endpoints can not set or clear auth tokens",
+                        ))?
+                    }
+                    _ => subscribe = Some(channel.to_string()),
+                },
+
+                // tags
+                Decorator::Tag(tag) if tags.iter().any(|declared| declared == tag) => Result::Err(
+                    ParseError::const_error(decorator.start, "tag already declared"),
                 )?,
-                Decorator::Param(param) if import_map.get(param).is_some() => Result::Err(
+                Decorator::Tag(tag) => tags.push(tag.to_string()),
+
+                // conflict messages
+                Decorator::OnConflict(_) if on_conflict.is_some() => {
+                    Result::Err(ParseError::const_error(
+                        decorator.start,
+                        "multiple on_conflict declarations detected",
+                    ))?
+                }
+                Decorator::OnConflict(message) => on_conflict = Some(message.to_string()),
+
+                // unwrapping rows
+                Decorator::Returns(_) if returns.is_some() => {
+                    Result::Err(ParseError::const_error(
+                        decorator.start,
+                        "multiple returns declarations detected",
+                    ))?
+                }
+                Decorator::Returns(_) if stream || subscribe.is_some() => {
+                    Result::Err(ParseError::const_error(
+                        decorator.start,
+                        "streamed and subscribed endpoints can not unwrap their rows",
+                    ))?
+                }
+                Decorator::Returns(mode) => match auth_settings {
+                    Some(AuthSettings::SetToken(_)) | Some(AuthSettings::RemoveToken) => {
+                        Result::Err(ParseError::const_error(
+                            decorator.start,
+                            "endpoints that set or clear auth tokens can not unwrap their rows",
+                        ))?
+                    }
+                    _ => returns = Some(mode),
+                },
+
+                // parameters
+                Decorator::Param(param, _) if params_set.contains_key(param) => {
+                    Result::Err(ParseError::Multiple(vec![
+                        ParseError::const_error(decorator.start, "parameter already declared"),
+                        ParseError::const_error(
+                            params_set[param],
+                            "parameter was first declared here",
+                        ),
+                    ]))?
+                }
+                Decorator::Param(param, _) if import_map.get(param).is_some() => Result::Err(
                     ParseError::const_error(decorator.start, "parameter is used for an import"),
                 )?,
-                Decorator::Param(param) => {
+                Decorator::Param(param, kind) => {
+                    if let Some(kind) = kind {
+                        param_kinds.insert(param.to_string(), kind);
+                    }
                     params.push(param.to_string());
-                    params_set.insert(param);
+                    params_set.insert(param, decorator.start);
                 }
             };
         }
@@ -177,10 +377,18 @@ impl FrontMatter {
         if errors.len() == 0 {
             Ok(Self {
                 location,
-                endpoint: endpoint.map(|v| v.to_string()),
+                endpoints: endpoints.into_iter().map(str::to_string).collect(),
                 params,
+                param_kinds,
                 imports: import_map,
                 auth_settings,
+                stream,
+                cache_ttl,
+                subscribe,
+                tags,
+                on_conflict,
+                returns,
+                read_only: false,
             })
         } else if errors.len() == 1 {
             Err(errors.pop().unwrap())
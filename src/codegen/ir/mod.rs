@@ -3,4 +3,4 @@ mod reserved_words;
 mod statement;
 
 pub use front_matter::FrontMatter;
-pub use statement::{Interp, Statements};
+pub use statement::{Arg, Interp, Statements};
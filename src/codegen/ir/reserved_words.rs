@@ -3,7 +3,22 @@ use crate::codegen::{
     span_ref::SpanRef,
 };
 
-const RESERVED_WORDS: [&'static str; 5] = ["auth", "import", "param", "throw", "endpoint"];
+/// names that can not be given to parameters, imports or endpoints. sql keywords are
+/// allowed since parameters are sent as `$n` placeholders and imports are inlined as
+/// parenthesized subqueries, so their names never reach postgres. `if`, `endif`,
+/// `foreach` and `endforeach` are reserved because they would be lexed as the start
+/// or end of a fragment instead of a parameter or import
+const RESERVED_WORDS: [&'static str; 9] = [
+    "auth",
+    "import",
+    "param",
+    "throw",
+    "endpoint",
+    "if",
+    "endif",
+    "foreach",
+    "endforeach",
+];
 
 pub fn check_reserved_words<'b, 'a: 'b, I: Iterator<Item = SpanRef<'a, &'b str>> + 'b>(
     iter: I,
@@ -1,6 +1,6 @@
 use super::{
     super::{
-        ast::{InterpSpan, StatementSpan},
+        ast::{ArgSpan, InterpSpan, StatementSpan},
         result::IrErrorKind,
         result::{CResult, ErrorKind, ParseError},
         span_ref::SpanRef,
@@ -17,9 +17,33 @@ pub struct Statements(pub Vec<Vec<Interp>>);
 pub enum Interp {
     Literal(String),
     Param(String),
+    /// a parameter bound from an array payload where `@1` is the first element
+    PositionalParam(usize),
     AuthParam(String),
     // TODO allow for expressions inside call sites
-    CallSite(String, Vec<String>),
+    CallSite(String, Vec<Arg>),
+    /// a fragment that is left out when the parameter is not in the payload
+    Conditional(String, Vec<Interp>),
+    /// a fragment that is repeated for every row of an array parameter
+    Foreach(String, Vec<Interp>),
+    /// a field of the current row of the `@foreach` over the parameter
+    Field(String, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Arg {
+    Param(String),
+    /// a string or number literal that is inlined into the imported module
+    Literal(String),
+}
+
+impl Arg {
+    pub fn from(span: &ArgSpan) -> Self {
+        match span {
+            ArgSpan::Param(param) => Self::Param(param.to_string()),
+            ArgSpan::Literal(lit) => Self::Literal(lit.to_string()),
+        }
+    }
 }
 
 impl Interp {
@@ -27,12 +51,40 @@ impl Interp {
         match span {
             InterpSpan::Literal(lit) => Self::Literal(lit.to_string()),
             InterpSpan::Param(param) => Self::Param(param.to_string()),
+            InterpSpan::PositionalParam(position) => Self::PositionalParam(*position),
             InterpSpan::AuthParam(param) => Self::AuthParam(param.to_string()),
             InterpSpan::CallSite(func, arg) => Self::CallSite(
                 func.to_string(),
-                arg.iter().map(|val| val.to_string()).collect(),
+                arg.iter().map(|val| Arg::from(&val.value)).collect(),
+            ),
+            InterpSpan::Conditional(param, fragment) => Self::Conditional(
+                param.to_string(),
+                fragment
+                    .iter()
+                    .map(|interp| Self::from(&interp.value))
+                    .collect(),
             ),
+            InterpSpan::Foreach(param, fragment) => Self::Foreach(
+                param.to_string(),
+                fragment
+                    .iter()
+                    .map(|interp| Self::from(&interp.value))
+                    .collect(),
+            ),
+            InterpSpan::Field(param, field) => Self::Field(param.to_string(), field.to_string()),
+        }
+    }
+
+    /// the interps along with the interps inside of their conditional and foreach fragments
+    pub fn flatten(interps: &[Interp]) -> Vec<&Interp> {
+        let mut flattened = vec![];
+        for interp in interps {
+            flattened.push(interp);
+            if let Interp::Conditional(_, fragment) | Interp::Foreach(_, fragment) = interp {
+                flattened.extend(Self::flatten(fragment));
+            }
         }
+        flattened
     }
 }
 
@@ -41,22 +93,34 @@ impl Statements {
         sql: &'b Vec<SpanRef<'a, StatementSpan<'a>>>,
     ) -> impl Iterator<Item = ParseError<'a>> + 'b {
         let iter = sql.iter().flat_map(|statement| {
-            statement.0.iter().flat_map(|interp| {
-                // need to use dynamic dispatch to allow for multiple return types
-                let iter: Box<dyn Iterator<Item = SpanRef<'a, &str>>> = match &interp.value {
-                    InterpSpan::Literal(lit) => {
-                        Box::new(iter::once(interp.as_ref().map(|_| lit.as_str())))
-                    }
-                    InterpSpan::Param(param) | InterpSpan::AuthParam(param) => {
-                        Box::new(iter::once(interp.as_ref().map(|_| *param)))
-                    }
-                    InterpSpan::CallSite(func, args) => Box::new(
-                        iter::once(interp.as_ref().map(|_| *func)).chain(args.iter().cloned()),
-                    ),
-                };
+            InterpSpan::flatten(&statement.0)
+                .into_iter()
+                .flat_map(|interp| {
+                    // need to use dynamic dispatch to allow for multiple return types
+                    let iter: Box<dyn Iterator<Item = SpanRef<'a, &str>>> = match &interp.value {
+                        InterpSpan::Literal(lit) => {
+                            Box::new(iter::once(interp.as_ref().map(|_| lit.as_str())))
+                        }
+                        InterpSpan::Param(param)
+                        | InterpSpan::AuthParam(param)
+                        | InterpSpan::Conditional(param, _)
+                        | InterpSpan::Foreach(param, _)
+                        | InterpSpan::Field(param, _) => {
+                            Box::new(iter::once(interp.as_ref().map(|_| *param)))
+                        }
+                        InterpSpan::PositionalParam(_) => Box::new(iter::empty()),
+                        InterpSpan::CallSite(func, args) => {
+                            Box::new(iter::once(interp.as_ref().map(|_| *func)).chain(
+                                args.iter().filter_map(|arg| match arg.value {
+                                    ArgSpan::Param(param) => Some(arg.with(param)),
+                                    ArgSpan::Literal(_) => None,
+                                }),
+                            ))
+                        }
+                    };
 
-                iter
-            })
+                    iter
+                })
         });
 
         check_reserved_words(iter)
@@ -69,50 +133,80 @@ impl Statements {
         let params_set: BTreeSet<_> = front_matter.params.iter().map(String::as_str).collect();
         let mut errors = vec![];
 
-        for interp_ref in sql.iter().flat_map(|stmt| stmt.value.0.iter()) {
+        for interp_ref in sql
+            .iter()
+            .flat_map(|stmt| InterpSpan::flatten(&stmt.value.0))
+        {
             match &interp_ref.value {
                 InterpSpan::CallSite(func, args) => {
                     // if function does not exist
                     match front_matter.imports.get(*func) {
-                        None => errors.push(ParseError::IrErrorKind(
-                            interp_ref.start,
-                            IrErrorKind::UndefinedFunctionError(func.to_string()),
-                        )),
+                        None => errors.push(
+                            ParseError::IrErrorKind(
+                                interp_ref.start,
+                                IrErrorKind::UndefinedFunctionError(func.to_string()),
+                            )
+                            .spanning(interp_ref.end),
+                        ),
                         Some((_, func_args)) if func_args.len() != args.len() => {
                             errors.push(ParseError::IrErrorKind(
                                 interp_ref.start,
-                                IrErrorKind::WrongNumberArgumentsError(func_args.len(), args.len()),
+                                IrErrorKind::WrongNumberArgumentsError(
+                                    func.to_string(),
+                                    func_args.clone(),
+                                    args.len(),
+                                ),
                             ))
                         }
                         Some(_) => {}
                     }
 
                     for arg in args.iter() {
-                        if !params_set.contains(arg.value) {
-                            errors.push(ParseError::error_kind(
-                                interp_ref.start,
-                                ErrorKind::UndefinedArgumentError(
-                                    arg.to_string(),
-                                    func.to_string(),
-                                ),
-                            ))
+                        match arg.value {
+                            ArgSpan::Param(param) if !params_set.contains(param) => errors.push(
+                                ParseError::error_kind(
+                                    arg.start,
+                                    ErrorKind::UndefinedArgumentError(
+                                        param.to_string(),
+                                        func.to_string(),
+                                    ),
+                                )
+                                .spanning(arg.end),
+                            ),
+                            _ => {}
                         }
                     }
                 }
 
-                InterpSpan::Param(param) if !params_set.contains(param) => {
+                InterpSpan::Param(param) if !params_set.contains(param) => errors.push(
+                    ParseError::error_kind(
+                        interp_ref.start,
+                        ErrorKind::UndefinedParameterError(param.to_string()),
+                    )
+                    .spanning(interp_ref.end),
+                ),
+                // the span of a fragment is the whole fragment so only its start is marked
+                InterpSpan::Conditional(param, _) | InterpSpan::Foreach(param, _)
+                    if !params_set.contains(param) =>
+                {
                     errors.push(ParseError::error_kind(
                         interp_ref.start,
                         ErrorKind::UndefinedParameterError(param.to_string()),
                     ))
                 }
+                InterpSpan::PositionalParam(0) => errors.push(ParseError::const_error(
+                    interp_ref.start,
+                    "positional parameters start at @1",
+                )),
                 _ => {}
             }
         }
 
+        errors.extend(Self::check_mixed_params(sql));
+
         let has_auth = sql
             .iter()
-            .flat_map(|stmt| stmt.0.iter())
+            .flat_map(|stmt| InterpSpan::flatten(&stmt.0))
             .find(|interp| matches!(interp.value, InterpSpan::AuthParam(_)));
 
         if let Some(auth) = has_auth {
@@ -131,6 +225,75 @@ impl Statements {
         errors
     }
 
+    /// positional parameters are bound from an array payload and named parameters from
+    /// an object payload so a module can only use one of the two
+    fn check_mixed_params<'a>(sql: &Vec<SpanRef<'a, StatementSpan<'a>>>) -> Vec<ParseError<'a>> {
+        let interps: Vec<_> = sql
+            .iter()
+            .flat_map(|stmt| InterpSpan::flatten(&stmt.value.0))
+            .collect();
+        let uses_named = interps.iter().any(|interp| match &interp.value {
+            InterpSpan::Param(_)
+            | InterpSpan::Conditional(_, _)
+            | InterpSpan::Foreach(_, _)
+            | InterpSpan::Field(_, _) => true,
+            InterpSpan::CallSite(_, args) => args
+                .iter()
+                .any(|arg| matches!(arg.value, ArgSpan::Param(_))),
+            _ => false,
+        });
+        if !uses_named {
+            return vec![];
+        }
+
+        interps
+            .into_iter()
+            .filter(|interp| matches!(interp.value, InterpSpan::PositionalParam(_)))
+            .map(|interp| {
+                ParseError::const_error(
+                    interp.start,
+                    "positional parameters can not be mixed with named parameters",
+                )
+            })
+            .collect()
+    }
+
+    /// warnings for the declared parameters that no statement or call site uses
+    pub fn unused_params<'a>(&self, declared: &[SpanRef<'a, &'a str>]) -> Vec<ParseError<'a>> {
+        let used: BTreeSet<&str> = self
+            .0
+            .iter()
+            .flat_map(|statement| Interp::flatten(statement))
+            .flat_map(|interp| {
+                // need to use dynamic dispatch to allow for multiple return types
+                let iter: Box<dyn Iterator<Item = &str>> = match interp {
+                    Interp::Param(param)
+                    | Interp::Conditional(param, _)
+                    | Interp::Foreach(param, _)
+                    | Interp::Field(param, _) => Box::new(iter::once(param.as_str())),
+                    Interp::CallSite(_, args) => {
+                        Box::new(args.iter().filter_map(|arg| match arg {
+                            Arg::Param(param) => Some(param.as_str()),
+                            Arg::Literal(_) => None,
+                        }))
+                    }
+                    Interp::Literal(_) | Interp::PositionalParam(_) | Interp::AuthParam(_) => {
+                        Box::new(iter::empty())
+                    }
+                };
+                iter
+            })
+            .collect();
+
+        declared
+            .iter()
+            .filter(|param| !used.contains(param.value))
+            .map(|param| {
+                ParseError::const_error(param.start, "parameter is declared but never used")
+            })
+            .collect()
+    }
+
     pub fn new<'a>(
         front_matter: &FrontMatter,
         sql: Vec<SpanRef<'a, StatementSpan<'a>>>,
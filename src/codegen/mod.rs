@@ -5,5 +5,5 @@ mod result;
 mod span_ref;
 mod toposort;
 
-pub use ir::Interp;
-pub use module::{AuthSettings, Module, ModuleError, ParamType};
+pub use ir::{Arg, Interp};
+pub use module::{AuthSettings, MissingRole, Module, ModuleError, ParamKind, ParamType, Returns};
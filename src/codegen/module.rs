@@ -1,14 +1,15 @@
 use super::{
-    ast::{Ast, Decorators},
+    ast::{Ast, Decorator, Decorators, StatementAnnotation},
     ir::{FrontMatter, Interp, Statements},
     result::{CResult, ParseError},
+    span_ref::SpanRef,
 };
 use crate::{
     binding::Binding,
     codegen::toposort::topological_sort,
     config::Secret,
     util::{
-        error_printing::{print_error, print_unpositioned_error, PrintableError},
+        error_printing::{print_error_span, print_unpositioned_error, PrintableError},
         mixed_ref::MixedRef,
         path::path_relative_to_current_dir,
     },
@@ -23,21 +24,70 @@ use thiserror::Error;
 // TODO set up "pre-interpolated" sql type
 #[derive(Debug, Clone, PartialEq)]
 pub enum AuthSettings {
-    VerifyToken(Option<u64>),
+    /// the refresh interval and the roles, any of which the token must hold
+    VerifyToken(Option<u64>, Vec<String>),
     SetToken(u64), // number of seconds till expiration
     RemoveToken,
 }
 
+/// the token was verified but it does not hold any of the roles the endpoint requires
+#[derive(Error, Debug)]
+#[error("token does not have a role that is allowed to use this endpoint")]
+pub struct MissingRole;
+
+/// whether the `role` claim or any element of the `roles` claim is one of the roles
+fn has_role(claims: &BTreeMap<String, Binding>, roles: &[String]) -> bool {
+    let listed = match claims.get("roles") {
+        Some(Binding::Array(values)) => values.iter().collect(),
+        _ => vec![],
+    };
+    claims
+        .get("role")
+        .into_iter()
+        .chain(listed)
+        .any(|claim| matches!(claim, Binding::String(role) if roles.contains(role)))
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ParamType {
     Auth(String),
     Param(String),
+    /// a parameter bound by its position in an array payload, starting at 1
+    Positional(usize),
+    /// a parameter declared with `: json` that is always bound as a single jsonb value
+    Json(String),
+    /// a field of one row of an array parameter that a `@foreach` fragment loops over
+    Field(String, usize, String),
+}
+
+/// the type a parameter is declared with, as in `@param filter: json`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamKind {
+    Json,
+}
+
+/// how the rows of an endpoint are unwrapped, as in `@returns scalar`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Returns {
+    /// the only column of the only row as a bare json value
+    Scalar,
 }
 
+/// first words of statements that can only read data
+const READ_STATEMENTS: [&str; 4] = ["select", "with", "values", "table"];
+
+/// words that make an otherwise reading statement write data or lock rows. `into` catches
+/// `select into` creating a table and `share` catches `for share` and `for key share`
+const WRITE_KEYWORDS: [&str; 17] = [
+    "insert", "update", "delete", "merge", "truncate", "copy", "create", "alter", "drop", "grant",
+    "revoke", "lock", "call", "nextval", "setval", "into", "share",
+];
+
 #[derive(Error, Debug)]
 pub enum ModuleError {
     #[error("error in {0}: {1}")]
     SingleModuleError(PathBuf, SingleModuleError),
+    /// the modules of the cycle where each one imports the next and the last imports the first
     #[error("there is a cyclic dependency")]
     CyclicDependency(Vec<PathBuf>),
 }
@@ -49,49 +99,63 @@ pub enum SingleModuleError {
     #[error("multiple errors")]
     MultipleParseError {
         file: String,
-        errors: Vec<(usize, String)>,
+        /// the position, length of the underline and explanation of each error
+        errors: Vec<(usize, usize, String)>,
     },
     #[error("{error}")]
     ParseError {
         file: String,
         pos: usize,
+        /// how many bytes from the position are underlined
+        len: usize,
         error: String,
     },
     #[error("file is incomplete")]
     Incomplete,
+    /// a problem that does not stop the module from running unless warnings are strict
+    #[error("warning: {0}")]
+    Warning(Box<SingleModuleError>),
 }
 
 impl ModuleError {
     fn convert_simple_parse_error<'a>(
         file_content: &'a str,
         err: &ParseError<'a>,
-    ) -> Option<(usize, String)> {
+    ) -> Option<(usize, usize, String)> {
         return match err {
             ParseError::NomError(input, _) => {
                 let pos = file_content.len() - input.len();
-                Some((pos, "unexpected token".to_string()))
+                Some((pos, 1, "unexpected token".to_string()))
             }
             ParseError::IrErrorKind(input, kind) => {
                 let pos = file_content.len() - input.len();
                 let error = format!("{}", kind);
-                Some((pos, error))
+                Some((pos, 1, error))
             }
             ParseError::ErrorKind(input, kind) => {
                 let pos = file_content.len() - input.len();
                 let error = format!("{}", kind);
-                Some((pos, error))
+                Some((pos, 1, error))
+            }
+            ParseError::Spanned(end, err) => {
+                let (pos, _, error) = Self::convert_simple_parse_error(file_content, err)?;
+                let len = (file_content.len() - end.len()).saturating_sub(pos);
+                Some((pos, len, error))
             }
             ParseError::Multiple(_) => None,
         };
     }
 
     pub fn with_parse_error<'a>(path: PathBuf, file_content: &'a str, err: ParseError<'a>) -> Self {
-        if let Some((pos, error)) = Self::convert_simple_parse_error(file_content.borrow(), &err) {
+        if let Some((pos, len, error)) =
+            Self::convert_simple_parse_error(file_content.borrow(), &err)
+        {
             ModuleError::SingleModuleError(
                 path,
                 SingleModuleError::ParseError {
                     file: file_content.to_string(),
                     pos,
+                    len,
                     error,
                 },
             )
@@ -120,7 +184,7 @@ impl ModuleError {
             }
 
             // sort the errors by position so that errors are ordered by line
-            res.sort_by_key(|(pos, _)| *pos);
+            res.sort_by_key(|(pos, _, _)| *pos);
 
             ModuleError::SingleModuleError(
                 path,
@@ -132,6 +196,13 @@ impl ModuleError {
         }
     }
 
+    pub fn is_warning(&self) -> bool {
+        matches!(
+            self,
+            ModuleError::SingleModuleError(_, SingleModuleError::Warning(_))
+        )
+    }
+
     pub fn with_nom_error<'a>(
         path: PathBuf,
         file_content: &'a str,
@@ -157,34 +228,25 @@ impl PrintableError for ModuleError {
 
         match self {
             ModuleError::CyclicDependency(paths) => {
-                for path in paths
+                let file_names: Vec<String> = paths
                     .iter()
-                    .map(PathBuf::as_path)
-                    .map(path_relative_to_current_dir)
-                {
-                    let lossy = path.to_string_lossy();
-                    let file_name = lossy.as_ref();
-                    print_unpositioned_error(writer, "part of a dependency cycle", file_name)?
+                    .chain(paths.first())
+                    .map(|path| {
+                        path_relative_to_current_dir(path.as_path())
+                            .to_string_lossy()
+                            .into_owned()
+                    })
+                    .collect();
+                if let Some(file_name) = file_names.first() {
+                    let explanation = format!("imports form a cycle: {}", file_names.join(" -> "));
+                    print_unpositioned_error(writer, explanation.as_str(), file_name)?
                 }
             }
             ModuleError::SingleModuleError(path, err) => {
                 let path = path_relative_to_current_dir(path.as_path());
                 let lossy = path.to_string_lossy();
                 let file_name = lossy.as_ref();
-                match err {
-                    SingleModuleError::IOError(_) | SingleModuleError::Incomplete => {
-                        print_unpositioned_error(writer, err.to_string().as_ref(), file_name)?
-                    }
-                    SingleModuleError::MultipleParseError { file, errors } => {
-                        for (pos, err) in errors.iter() {
-                            print_error(writer, file.as_str(), *pos, err.as_str(), file_name)?;
-                            write!(writer, "\n")?;
-                        }
-                    }
-                    SingleModuleError::ParseError { file, pos, error } => {
-                        print_error(writer, file.as_str(), *pos, error.as_str(), file_name)?
-                    }
-                }
+                err.print_error_in(writer, file_name)?
             }
         };
 
@@ -192,11 +254,44 @@ impl PrintableError for ModuleError {
     }
 }
 
+impl SingleModuleError {
+    fn print_error_in<W: std::fmt::Write>(
+        &self,
+        writer: &mut W,
+        file_name: &str,
+    ) -> Result<(), crate::util::error_printing::PrintError> {
+        match self {
+            SingleModuleError::IOError(_) | SingleModuleError::Incomplete => {
+                print_unpositioned_error(writer, self.to_string().as_ref(), file_name)?
+            }
+            SingleModuleError::MultipleParseError { file, errors } => {
+                for (pos, len, err) in errors.iter() {
+                    print_error_span(writer, file.as_str(), *pos, *len, err.as_str(), file_name)?;
+                    write!(writer, "\n")?;
+                }
+            }
+            SingleModuleError::ParseError {
+                file,
+                pos,
+                len,
+                error,
+            } => print_error_span(writer, file.as_str(), *pos, *len, error.as_str(), file_name)?,
+            SingleModuleError::Warning(err) => err.print_error_in(writer, file_name)?,
+        }
+        Ok(())
+    }
+}
+
 // TODO set up "pre-interpolated" sql type
 #[derive(Debug, Clone)]
 pub struct Module {
     pub front_matter: FrontMatter,
     pub sql: Vec<Vec<Interp>>,
+    /// the positions of the statements annotated with `-- @optional`
+    pub optional_statements: BTreeSet<usize>,
+    /// the position of the statement annotated with `-- @returns_rows`, whose rows are
+    /// returned instead of the last statement's
+    pub returned_statement: Option<usize>,
 }
 
 impl Module {
@@ -217,17 +312,30 @@ impl Module {
             })
             .transpose()?;
 
-        if matches!(
-            &self.front_matter.auth_settings,
-            Some(AuthSettings::VerifyToken(_))
-        ) && claim.is_none()
-        {
-            return Err(anyhow!("authentication is required"));
+        if let Some(AuthSettings::VerifyToken(_, roles)) = &self.front_matter.auth_settings {
+            let claim = claim
+                .as_ref()
+                .ok_or_else(|| anyhow!("authentication is required"))?;
+            if !roles.is_empty() && !has_role(claim, roles) {
+                Err(MissingRole)?
+            }
         }
 
         Ok(claim)
     }
 
+    /// the claims this module reads through `@auth.claim`
+    pub fn auth_params(&self) -> BTreeSet<&str> {
+        self.sql
+            .iter()
+            .flat_map(|statement| Interp::flatten(statement))
+            .filter_map(|interp| match interp {
+                Interp::AuthParam(param) => Some(param.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
     /// only modules that are single statements can be imported and reused inside
     /// of common table expression. We expose a utility function that identifies this.
     pub fn is_single_statement(&self) -> bool {
@@ -249,18 +357,112 @@ impl Module {
         ast: Ast<'a>,
         modules: &BTreeMap<P, M>,
     ) -> CResult<'a, Self> {
+        Ok(Self::new_with_warnings(ast, modules)?.0)
+    }
+
+    /// creates a new module along with the problems that do not stop it from running
+    pub fn new_with_warnings<'a, P: Borrow<Path> + Ord, M: Borrow<Module>>(
+        ast: Ast<'a>,
+        modules: &BTreeMap<P, M>,
+    ) -> CResult<'a, (Self, Vec<ParseError<'a>>)> {
         let Ast {
             file_loc,
             decorators,
             statements,
         } = ast;
 
-        let front_matter = FrontMatter::new(file_loc, decorators.into_inner(), modules)?;
+        let declared_params: Vec<SpanRef<'a, &'a str>> = decorators
+            .iter()
+            .filter_map(|decorator| match decorator.value {
+                Decorator::Param(param, _) => Some(decorator.with(param)),
+                _ => None,
+            })
+            .collect();
+        let mut front_matter = FrontMatter::new(file_loc, decorators.into_inner(), modules)?;
+        let starts: Vec<&'a str> = statements.iter().map(|statement| statement.start).collect();
+        let annotations: Vec<Vec<StatementAnnotation>> = statements
+            .iter()
+            .map(|statement| statement.annotations())
+            .collect();
+        let annotated = |annotation| {
+            annotations
+                .iter()
+                .enumerate()
+                .filter(move |(_, annotations)| annotations.contains(&annotation))
+                .map(|(idx, _)| idx)
+        };
+        let optional_statements: BTreeSet<usize> =
+            annotated(StatementAnnotation::Optional).collect();
+        let mut returning = annotated(StatementAnnotation::ReturnsRows);
+        let returned_statement = returning.next();
+        if let Some(idx) = returning.next() {
+            Err(ParseError::const_error(
+                starts[idx],
+                "only one statement can be annotated with @returns_rows",
+            ))?
+        }
+        if front_matter.stream {
+            if let Some(idx) = optional_statements.iter().next() {
+                Err(ParseError::const_error(
+                    starts[*idx],
+                    "streamed endpoints can not have optional statements",
+                ))?
+            }
+            if let Some(idx) = returned_statement {
+                Err(ParseError::const_error(
+                    starts[idx],
+                    "streamed endpoints always stream the rows of their last statement",
+                ))?
+            }
+        }
         let statements = Statements::new(&front_matter, statements)?;
-        Ok(Self {
-            front_matter,
-            sql: statements.0,
-        })
+        if front_matter.cache_ttl.is_some() {
+            let writing = statements
+                .0
+                .iter()
+                .zip(starts)
+                .find(|(statement, _)| !is_read_only(statement, &front_matter, modules));
+            if let Some((_, start)) = writing {
+                Err(ParseError::const_error(
+                    start,
+                    "cached endpoints can only read data but this statement may write. remove the @cache decorator",
+                ))?
+            }
+        }
+        front_matter.read_only = statements
+            .0
+            .iter()
+            .all(|statement| is_read_only(statement, &front_matter, modules));
+        let warnings = statements.unused_params(&declared_params);
+        Ok((
+            Self {
+                front_matter,
+                sql: statements.0,
+                optional_statements,
+                returned_statement,
+            },
+            warnings,
+        ))
+    }
+
+    /// combines the warnings of a module into a single error
+    fn warning<'a>(
+        path: &Path,
+        contents: &'a str,
+        mut warnings: Vec<ParseError<'a>>,
+    ) -> Option<ModuleError> {
+        let warning = match warnings.len() {
+            0 => return None,
+            1 => warnings.pop().unwrap(),
+            _ => ParseError::Multiple(warnings),
+        };
+        match ModuleError::with_parse_error(path.to_path_buf(), contents, warning) {
+            ModuleError::SingleModuleError(path, err) => Some(ModuleError::SingleModuleError(
+                path,
+                SingleModuleError::Warning(Box::new(err)),
+            )),
+            err => Some(err),
+        }
     }
 
     /// helper function for reading a file
@@ -284,6 +486,44 @@ impl Module {
         Ok(file_content)
     }
 
+    /// the endpoints a file declares. only the decorators are parsed so this is
+    /// much cheaper than importing the module
+    pub fn read_endpoints(path: &Path) -> Result<Vec<String>, ModuleError> {
+        let file_content = Self::read_file(path)?;
+        let endpoints = Decorators::parse(file_content.as_str())
+            .map(|(_, decorators)| {
+                decorators
+                    .iter()
+                    .filter_map(|decorator| match decorator.value {
+                        Decorator::Endpoint(endpoint) => Some(endpoint.to_string()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(endpoints)
+    }
+
+    /// the files and the endpoints a file imports. like `read_endpoints` only the
+    /// decorators are parsed
+    pub fn read_imports(path: &Path) -> Result<(Vec<PathBuf>, Vec<String>), ModuleError> {
+        let file_content = Self::read_file(path)?;
+        let imports = Decorators::parse(file_content.as_str())
+            .map(|(_, decorators)| {
+                let files = decorators
+                    .canonicalized_dependencies(path)
+                    .map(|dep| dep.value)
+                    .collect();
+                let endpoints = decorators
+                    .endpoint_dependencies()
+                    .map(|endpoint| endpoint.value.to_string())
+                    .collect();
+                (files, endpoints)
+            })
+            .unwrap_or_default();
+        Ok(imports)
+    }
+
     /// helper function for getting file content
     fn gen_file_contents<'a, M>(
         errors: &mut Vec<ModuleError>,
@@ -372,21 +612,55 @@ impl Module {
 
         // finally topologically sort by ast and complete the rest in topological order
         // currently asts maintain the order that paths came in from the argument
+        // endpoint imports depend on the location of the module that declares the endpoint
+        let endpoint_locations: BTreeMap<&str, PathBuf> = deps
+            .iter()
+            .flat_map(|map| map.iter())
+            .flat_map(|(path, module)| {
+                module
+                    .borrow()
+                    .front_matter
+                    .endpoints
+                    .iter()
+                    .map(move |endpoint| (endpoint.as_str(), path.to_path_buf()))
+            })
+            .chain(asts.iter().flat_map(|(path, ast)| {
+                ast.decorators
+                    .iter()
+                    .filter_map(move |decorator| match decorator.value {
+                        Decorator::Endpoint(endpoint) => Some((endpoint, path.clone())),
+                        _ => None,
+                    })
+            }))
+            .collect();
+
         let mut nodes: BTreeSet<PathBuf> = asts.keys().cloned().collect();
         let mut edges: Vec<(PathBuf, PathBuf)> = vec![];
         for (path, ast) in asts.iter() {
-            for dep in ast.canonicalized_dependencies() {
-                if !nodes.contains(&dep.value) {
-                    nodes.insert(dep.value.clone());
+            let endpoint_deps = ast
+                .decorators
+                .endpoint_dependencies()
+                .filter_map(|endpoint| endpoint_locations.get(endpoint.value).cloned());
+            for dep in ast
+                .canonicalized_dependencies()
+                .map(|dep| dep.value)
+                .chain(endpoint_deps)
+            {
+                // self imports are reported by the front matter instead of as a cycle
+                if &dep == path {
+                    continue;
+                }
+                if !nodes.contains(&dep) {
+                    nodes.insert(dep.clone());
                 }
-                edges.push((path.clone(), dep.value))
+                edges.push((path.clone(), dep))
             }
         }
 
         let (sorted, sorting_errors) = topological_sort(nodes.iter(), edges.iter());
-        if let Some(set) = sorting_errors {
+        if let Some(cycle) = sorting_errors {
             errors.push(ModuleError::CyclicDependency(
-                set.into_iter().map(|v| v.to_path_buf()).collect(),
+                cycle.into_iter().map(|v| v.to_path_buf()).collect(),
             ));
         };
 
@@ -401,10 +675,11 @@ impl Module {
             // filters out paths that are dependencies but do not need to be imported
             .filter_map(|path| Some((path, file_contents.get(path)?.as_str(), asts.remove(path)?)))
         {
-            match Module::new(ast, &modules)
+            match Module::new_with_warnings(ast, &modules)
                 .map_err(|err| ModuleError::with_parse_error(path.to_path_buf(), contents, err))
             {
-                Ok(res) => {
+                Ok((res, warnings)) => {
+                    errors.extend(Self::warning(path, contents, warnings));
                     modules.insert(path.to_path_buf(), MixedRef::Owned(res));
                 }
                 Err(err) => errors.push(err),
@@ -431,9 +706,45 @@ impl Module {
     }
 }
 
+/// checks the words of a statement and the modules it imports. this is conservative
+/// about words but can not see into functions, so functions that write are missed.
+fn is_read_only<P: Borrow<Path> + Ord, M: Borrow<Module>>(
+    statement: &[Interp],
+    front_matter: &FrontMatter,
+    modules: &BTreeMap<P, M>,
+) -> bool {
+    let interps = Interp::flatten(statement);
+    let mut words = interps
+        .iter()
+        .filter_map(|interp| match interp {
+            Interp::Literal(lit) => Some(lit.as_str()),
+            _ => None,
+        })
+        .flat_map(|lit| lit.split(|chr: char| !(chr.is_alphanumeric() || chr == '_')))
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase);
+
+    let reads = words
+        .next()
+        .is_some_and(|word| READ_STATEMENTS.contains(&word.as_str()));
+    if !reads || words.any(|word| WRITE_KEYWORDS.contains(&word.as_str())) {
+        return false;
+    }
+
+    interps.iter().all(|interp| match interp {
+        Interp::CallSite(func, _) => front_matter
+            .imports
+            .get(func)
+            .and_then(|(location, _)| modules.get(location.as_path()))
+            .is_some_and(|module| module.borrow().front_matter.read_only),
+        _ => true,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::util::temp_dir::temp_dir;
 
     #[test]
     fn module_parse_test() {
@@ -446,7 +757,7 @@ where id = @id
 AND @email = 'testing 123 @haha' 
 OR 0 = @id"#;
         let module = Module::from_str(path.clone(), test_str).unwrap();
-        assert_eq!(format!("{:?}", &module), "Module { front_matter: FrontMatter { location: \"\", endpoint: None, params: [\"email\", \"id\"], imports: {}, auth_settings: None }, sql: [[Literal(\"select * from users \\nwhere id = \"), Param(\"id\"), Literal(\" \\nAND \"), Param(\"email\"), Literal(\" = \\\'testing 123 @haha\\\' \\nOR 0 = \"), Param(\"id\")]] }");
+        assert_eq!(format!("{:?}", &module), "Module { front_matter: FrontMatter { location: \"\", endpoints: [], params: [\"email\", \"id\"], param_kinds: {}, imports: {}, auth_settings: None, stream: false, cache_ttl: None, subscribe: None, tags: [], on_conflict: None, returns: None, read_only: true }, sql: [[Literal(\"select * from users \\nwhere id = \"), Param(\"id\"), Literal(\" \\nAND \"), Param(\"email\"), Literal(\" = \\\'testing 123 @haha\\\' \\nOR 0 = \"), Param(\"id\")]], optional_statements: {}, returned_statement: None }");
 
         let test_str = r#"
 /* @param email 
@@ -460,7 +771,7 @@ OR 0 = @id"#;
         assert_eq!(
             format!("{:?}", &err)
             ,
-            "Multiple([ErrorKind(\"@id \\nAND @email = \\\'testing 123 @haha\\\' \\nOR 0 = @id\", UndefinedParameterError(\"id\")), ErrorKind(\"@id\", UndefinedParameterError(\"id\"))])"
+            "Multiple([Spanned(\" \\nAND @email = \\\'testing 123 @haha\\\' \\nOR 0 = @id\", ErrorKind(\"@id \\nAND @email = \\\'testing 123 @haha\\\' \\nOR 0 = @id\", UndefinedParameterError(\"id\"))), Spanned(\"\", ErrorKind(\"@id\", UndefinedParameterError(\"id\")))])"
         );
 
         let test_str = r#"
@@ -482,4 +793,505 @@ OR 0 = @id;
                 _ => true,
             }))
     }
+
+    #[test]
+    fn duplicate_param_test() {
+        let path = PathBuf::new();
+        let test_str = r#"
+-- @param email
+-- @param id
+-- @param email
+select * from users where id = @id and email = @email"#;
+        let err = Module::from_str(path.clone(), test_str).unwrap_err();
+        let positions = match ModuleError::with_parse_error(path.clone(), test_str, err) {
+            ModuleError::SingleModuleError(
+                _,
+                SingleModuleError::MultipleParseError { errors, .. },
+            ) => errors,
+            err => panic!("{}", err),
+        };
+        assert_eq!(
+            positions,
+            vec![
+                (11, 1, "parameter was first declared here".to_string()),
+                (40, 1, "parameter already declared".to_string()),
+            ]
+        );
+
+        let test_str = r#"
+-- @param email, id, email
+select * from users where id = @id and email = @email"#;
+        let err = Module::from_str(path.clone(), test_str).unwrap_err();
+        assert!(matches!(err, ParseError::Multiple(errors) if errors.len() == 2));
+    }
+
+    #[test]
+    fn import_arity_test() {
+        let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let addition_path = examples.join("simple_addition.sql").canonicalize().unwrap();
+        let addition_str = std::fs::read_to_string(&addition_path).unwrap();
+        let addition = Module::from_str(addition_path.clone(), addition_str.as_str()).unwrap();
+        let modules: BTreeMap<PathBuf, Module> =
+            vec![(addition_path, addition)].into_iter().collect();
+
+        let test_str = r#"
+-- @import addition from './simple_addition.sql'
+-- @param a
+select * from @addition(a)"#;
+        let (_, ast) = Ast::parse(examples.join("test.sql"), test_str).unwrap();
+        let err = Module::new(ast, &modules).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Checking failed at @addition(a) due to addition expects 2 arguments (summand1, summand2) but was given 1"
+        );
+
+        let test_str = r#"
+-- @import addition from './simple_addition.sql'
+-- @param a
+select * from @addition(a, 1)"#;
+        let (_, ast) = Ast::parse(examples.join("test.sql"), test_str).unwrap();
+        assert!(Module::new(ast, &modules).is_ok());
+    }
+
+    #[test]
+    fn endpoint_import_test() {
+        let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let count_path = examples.join("all_user_count.sql").canonicalize().unwrap();
+        let users_path = examples.join("all_users.sql").canonicalize().unwrap();
+        let (modules, errors) =
+            Module::from_paths::<Module>(&[count_path.as_path(), users_path.as_path()], None);
+        assert!(errors.is_empty());
+        assert_eq!(
+            modules[&count_path].front_matter.imports["all_users"].0,
+            users_path
+        );
+
+        // the endpoint has to be among the imported modules, the commands import the
+        // module's directory for this
+        let (_, errors) = Module::from_paths::<Module>(&[count_path.as_path()], None);
+        assert_eq!(
+            errors[0].to_string(),
+            format!(
+                "error in {}: no module declares the endpoint all_users",
+                count_path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn self_import_test() {
+        let path = temp_dir("self_import_test").join("self.sql");
+        for contents in &[
+            "-- @import self_ from './self.sql'\nselect 1",
+            "-- @endpoint selfImport\n-- @import self_ from endpoint:selfImport\nselect 1",
+        ] {
+            std::fs::write(&path, contents).unwrap();
+            let path = path.canonicalize().unwrap();
+            let (modules, errors) = Module::from_paths::<Module>(&[path.as_path()], None);
+            assert!(modules.is_empty());
+            assert_eq!(errors.len(), 1);
+            // the error points at the import instead of reporting a cycle
+            assert!(matches!(
+                &errors[0],
+                ModuleError::SingleModuleError(_, SingleModuleError::ParseError { file, pos, error, .. })
+                    if file[*pos..].starts_with("@import") && error == "module cannot import itself"
+            ));
+        }
+    }
+
+    #[test]
+    fn cyclic_dependency_test() {
+        let directory = temp_dir("cyclic_dependency_test");
+        for (name, import) in &[("a", "b"), ("b", "c"), ("c", "a")] {
+            std::fs::write(
+                directory.join(format!("{}.sql", name)),
+                format!(
+                    "-- @import {0} from './{0}.sql'\nselect * from @{0}()",
+                    import
+                ),
+            )
+            .unwrap();
+        }
+
+        let path = directory.join("b.sql").canonicalize().unwrap();
+        let (_, errors) = Module::from_paths::<Module>(&[path.as_path()], None);
+        let cycle = match &errors[..] {
+            [ModuleError::CyclicDependency(cycle)] => cycle,
+            _ => panic!("expected a single cycle but got {:?}", errors),
+        };
+        let names: Vec<_> = cycle
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["a.sql", "b.sql", "c.sql"]);
+
+        let mut buf = String::new();
+        errors[0].print_error(&mut buf).unwrap();
+        assert!(buf.contains("a.sql -> "));
+        assert!(buf.contains("b.sql -> "));
+        assert!(buf.trim_end().ends_with("a.sql"));
+    }
+
+    #[test]
+    fn unused_params_test() {
+        let test_str = "-- @param id, email\n-- @param name\nselect * from users where id = @id";
+        let (_, ast) = Ast::parse(PathBuf::new(), test_str).unwrap();
+        let (_, warnings) =
+            Module::new_with_warnings::<&Path, Module>(ast, &BTreeMap::new()).unwrap();
+        let unused: Vec<&str> = warnings
+            .iter()
+            .map(|warning| match warning {
+                // warnings point at the start of the parameter name
+                ParseError::ErrorKind(input, _) => input
+                    .split(|chr: char| !chr.is_alphanumeric())
+                    .next()
+                    .unwrap(),
+                _ => panic!("unexpected warning {}", warning),
+            })
+            .collect();
+        assert_eq!(unused, vec!["email", "name"]);
+    }
+
+    #[test]
+    fn positional_params_test() {
+        let path = PathBuf::new();
+        let module = Module::from_str(path.clone(), "select @1, @2").unwrap();
+        assert_eq!(
+            module.sql[0][1..],
+            [
+                Interp::PositionalParam(1),
+                Interp::Literal(", ".to_string()),
+                Interp::PositionalParam(2)
+            ]
+        );
+
+        let test_str = "-- @param id\nselect @id, @1";
+        let err = Module::from_str(path.clone(), test_str).unwrap_err();
+        match ModuleError::with_parse_error(path.clone(), test_str, err) {
+            ModuleError::SingleModuleError(_, SingleModuleError::ParseError { pos, error, .. }) => {
+                assert_eq!(pos, test_str.find("@1").unwrap());
+                assert_eq!(
+                    error,
+                    "positional parameters can not be mixed with named parameters"
+                );
+            }
+            err => panic!("{}", err),
+        }
+
+        assert!(Module::from_str(path, "select @0").is_err());
+    }
+
+    #[test]
+    fn unterminated_comment_test() {
+        let path = PathBuf::from("users.sql");
+        let test_str = "-- @param id\n/* @param name\n * @endpoint users\nselect @id, @name";
+        let err = Module::from_str(path.clone(), test_str).unwrap_err();
+        let mut res = String::new();
+        ModuleError::with_parse_error(path, test_str, err)
+            .print_error(&mut res)
+            .unwrap();
+        assert_eq!(
+            format!("\n{}", res.as_str()),
+            r#"
+ --> users.sql:2:1
+  |
+2 | /* @param name
+  | ^comment is unterminated
+"#
+        );
+    }
+
+    #[test]
+    fn stream_test() {
+        let module = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint export\n-- @auth verify\n-- @stream\nselect * from users",
+        )
+        .unwrap();
+        assert!(module.front_matter.stream);
+
+        let err = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint login\n-- @auth authorize 1d\n-- @stream\nselect * from users",
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("streamed endpoints can not set or clear auth tokens"));
+    }
+
+    #[test]
+    fn subscribe_test() {
+        let module = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint events\n-- @auth verify\n-- @subscribe user_events\nselect 1",
+        )
+        .unwrap();
+        assert_eq!(
+            module.front_matter.subscribe.as_deref(),
+            Some("user_events")
+        );
+
+        let err = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint events\n-- @stream\n-- @subscribe user_events\nselect 1",
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("subscribed endpoints can not be streamed or cached"));
+    }
+
+    #[test]
+    fn returns_test() {
+        let module = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint countUsers\n-- @returns scalar\nselect count(*) from users",
+        )
+        .unwrap();
+        assert_eq!(module.front_matter.returns, Some(Returns::Scalar));
+
+        let err = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint countUsers\n-- @returns all\nselect count(*) from users",
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("unknown return mode, expected scalar"));
+
+        let err = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint countUsers\n-- @stream\n-- @returns scalar\nselect count(*) from users",
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("streamed and subscribed endpoints can not unwrap their rows"));
+    }
+
+    #[test]
+    fn endpoint_aliases_test() {
+        let module = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint users.list, getUsers\n-- @endpoint listUsers\nselect * from users",
+        )
+        .unwrap();
+        assert_eq!(
+            module.front_matter.endpoints,
+            vec!["users.list", "getUsers", "listUsers"]
+        );
+
+        let err = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint getUsers, getUsers\nselect * from users",
+        )
+        .unwrap_err();
+        assert!(err.to_string().ends_with("endpoint already declared"));
+    }
+
+    #[test]
+    fn reserved_words_test() {
+        for sql in &[
+            "-- @param endif\nselect 1",
+            "-- @param if\nselect 1",
+            "-- @param auth\nselect @auth",
+            "-- @endpoint users.import\nselect 1",
+        ] {
+            let err = Module::from_str(PathBuf::new(), sql).unwrap_err();
+            assert!(err.to_string().contains("is a reserved word"), "{}", err);
+        }
+
+        // sql keywords never reach postgres as names so they are allowed
+        let module = Module::from_str(
+            PathBuf::new(),
+            "-- @param select, from\nselect * from users where id = @select and name = @from",
+        )
+        .unwrap();
+        assert_eq!(module.front_matter.params, vec!["select", "from"]);
+        assert_eq!(
+            module.sql[0]
+                .iter()
+                .filter(|interp| matches!(interp, Interp::Param(_)))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn undefined_parameter_underline_test() {
+        let path = PathBuf::from("users.sql");
+        let test_str = "-- @param id\nselect * from users where id = @user_id";
+        let err = Module::from_str(path.clone(), test_str).unwrap_err();
+        let mut res = String::new();
+        ModuleError::with_parse_error(path, test_str, err)
+            .print_error(&mut res)
+            .unwrap();
+        assert!(res
+            .ends_with("  |                                ^^^^^^^^undefined parameter user_id\n"));
+    }
+
+    #[test]
+    fn tags_test() {
+        // tags are not checked for reserved words since they never reach the sql
+        let module = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint getInvoices\n-- @tag billing\n-- @tag select\nselect * from invoices",
+        )
+        .unwrap();
+        assert_eq!(module.front_matter.tags, vec!["billing", "select"]);
+
+        let err = Module::from_str(
+            PathBuf::new(),
+            "-- @tag billing\n-- @tag billing\nselect * from invoices",
+        )
+        .unwrap_err();
+        assert!(err.to_string().ends_with("tag already declared"));
+    }
+
+    #[test]
+    fn optional_statements_test() {
+        let module = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint cleanup\n-- @param id\n-- @optional\ndelete from sessions where user_id = @id;\n\n-- best effort\n--   @optional\nnotify cleanup;\n-- optional\nselect @id",
+        )
+        .unwrap();
+        assert_eq!(module.front_matter.endpoints, vec!["cleanup"]);
+        assert_eq!(module.front_matter.params, vec!["id".to_string()]);
+        assert_eq!(
+            module.optional_statements.into_iter().collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+
+        let err = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint cleanup\n-- @stream\nselect 1;\n-- @optional\nselect 2",
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("streamed endpoints can not have optional statements"));
+    }
+
+    #[test]
+    fn returns_rows_test() {
+        let module = Module::from_str(
+            PathBuf::new(),
+            "-- @param id\n-- @returns_rows\ndelete from users where id = @id returning *;\n-- @optional\ndelete from sessions where user_id = @id",
+        )
+        .unwrap();
+        assert_eq!(module.returned_statement, Some(0));
+        assert_eq!(
+            module.optional_statements.into_iter().collect::<Vec<_>>(),
+            vec![1]
+        );
+
+        let err = Module::from_str(
+            PathBuf::new(),
+            "-- @returns_rows\nselect 1;\n-- @returns_rows\nselect 2",
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("only one statement can be annotated with @returns_rows"));
+
+        let err = Module::from_str(
+            PathBuf::new(),
+            "-- @stream\n-- @returns_rows\nselect 1;\nselect 2",
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("streamed endpoints always stream the rows of their last statement"));
+    }
+
+    #[test]
+    fn cache_test() {
+        let module = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint users\n-- @cache 30s\nwith active as (select * from users) select * from active",
+        )
+        .unwrap();
+        assert_eq!(module.front_matter.cache_ttl, Some(30));
+
+        for sql in &[
+            "-- @endpoint users\n-- @cache 30s\nselect * from users;\ndelete from users",
+            "-- @endpoint users\n-- @cache 30s\nwith gone as (delete from users returning *) select * from gone",
+            "-- @endpoint users\n-- @cache 30s\nselect * from users for update",
+        ] {
+            let err = Module::from_str(PathBuf::new(), sql).unwrap_err();
+            assert!(err.to_string().ends_with("remove the @cache decorator"), "{}", sql);
+        }
+
+        let err = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint login\n-- @auth authorize 1d\n-- @cache 30s\nselect * from users",
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("endpoints that set or clear auth tokens can not be cached"));
+    }
+
+    #[test]
+    fn read_only_test() {
+        let read_only = |sql: &str| {
+            Module::from_str(PathBuf::new(), sql)
+                .unwrap()
+                .front_matter
+                .read_only
+        };
+        assert!(read_only("select * from users;\nvalues (1)"));
+        assert!(read_only(
+            "with active as (select * from users) select * from active"
+        ));
+        assert!(!read_only("select * from users;\ndelete from users"));
+        assert!(!read_only(
+            "with gone as (delete from users returning *) select * from gone"
+        ));
+        assert!(!read_only("select * from users for update"));
+        assert!(!read_only("select * from users for share"));
+        assert!(!read_only("select * from users for key share"));
+        assert!(!read_only("select * into archived_users from users"));
+        assert!(!read_only("insert into users (id) values (1)"));
+
+        // imported modules have to be read only too
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let paths: Vec<PathBuf> = ["all_user_count.sql", "all_users.sql"]
+            .iter()
+            .map(|name| directory.join(name).canonicalize().unwrap())
+            .collect();
+        let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        let (modules, errors) = Module::from_paths::<Module>(&paths, None);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(modules[paths[0]].front_matter.read_only);
+    }
+
+    #[test]
+    fn auth_roles_test() {
+        let secret: Secret =
+            serde_json::from_str(r#"{"algorithm":"HS256","secret_key_base64":"dGVzdGluZw=="}"#)
+                .unwrap();
+        let module = Module::from_str(
+            PathBuf::new(),
+            "-- @endpoint admin\n-- @auth verify role=admin,owner\nselect 1",
+        )
+        .unwrap();
+        let bindings = |claims: serde_json::Value| {
+            let token = secret.encode(&claims, 60).unwrap();
+            module.get_auth_bindings(Some(&secret), Some(token.as_str()))
+        };
+
+        assert!(bindings(serde_json::json!({"role": "owner"})).is_ok());
+        assert!(bindings(serde_json::json!({"roles": ["viewer", "admin"]})).is_ok());
+        let err = bindings(serde_json::json!({"role": "viewer"})).unwrap_err();
+        assert!(err.is::<MissingRole>());
+        assert!(bindings(serde_json::json!({}))
+            .unwrap_err()
+            .is::<MissingRole>());
+        assert!(!module
+            .get_auth_bindings(Some(&secret), None)
+            .unwrap_err()
+            .is::<MissingRole>());
+    }
 }
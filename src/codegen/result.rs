@@ -12,6 +12,9 @@ pub enum ParseError<'a> {
     ErrorKind(&'a str, ErrorKind),
     #[error("Checking failed at {0} due to {1}")]
     IrErrorKind(&'a str, IrErrorKind),
+    /// an error that underlines everything up to the suffix instead of a single character
+    #[error("{1}")]
+    Spanned(&'a str, Box<ParseError<'a>>),
 }
 
 #[derive(Error, Debug, Clone)]
@@ -28,12 +31,14 @@ pub enum ErrorKind {
 pub enum IrErrorKind {
     #[error("{0}")]
     ConstError(&'static str),
-    #[error("{0} is a reserved words")]
+    #[error("{0} is a reserved word")]
     ReservedWordError(String),
     #[error("function {0} does not exist")]
     UndefinedFunctionError(String),
-    #[error("this module expects {0} arguments not {1} arguments")]
-    WrongNumberArgumentsError(usize, usize),
+    #[error("no module declares the endpoint {0}")]
+    UndefinedEndpointError(String),
+    #[error("{0} expects {} arguments ({}) but was given {2}", .1.len(), .1.join(", "))]
+    WrongNumberArgumentsError(String, Vec<String>, usize),
 }
 
 impl<'a> ParseError<'a> {
@@ -43,6 +48,10 @@ impl<'a> ParseError<'a> {
     pub fn error_kind(input: &'a str, kind: ErrorKind) -> ParseError<'a> {
         ParseError::ErrorKind(input, kind)
     }
+    /// underlines the error until the end of the span
+    pub fn spanning(self, end: &'a str) -> ParseError<'a> {
+        ParseError::Spanned(end, Box::new(self))
+    }
 }
 
 impl<'a> nom::error::ParseError<&'a str> for ParseError<'a> {
@@ -1,7 +1,7 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 /// returns a topologically sorted vector of input value references
-/// returns None if the graph contains a cycle
+/// along with one of the cycles, in edge order, if the graph contains any
 pub fn topological_sort<
     'a,
     T: Ord + 'a,
@@ -10,7 +10,7 @@ pub fn topological_sort<
 >(
     nodes: N,
     edges: E,
-) -> (Vec<&'a T>, Option<BTreeSet<&'a T>>) {
+) -> (Vec<&'a T>, Option<Vec<&'a T>>) {
     let mut parent_of_relations = BTreeSet::new();
     let mut child_of_relations = BTreeSet::new();
     let mut all_nodes: BTreeSet<_> = nodes.collect();
@@ -88,17 +88,25 @@ pub fn topological_sort<
         // some edges where not traversed despite all nodes with no
         // outbound edges having been removed. This means there must exist
         // at least one cycle in the remaining subgraph.
-
-        let cycle = parent_of_relations
-            .into_iter()
-            .flat_map(|(v1, v2)| vec![v1, v2].into_iter())
-            .chain(
-                child_of_relations
-                    .into_iter()
-                    .flat_map(|(v1, v2)| vec![v1, v2].into_iter()),
-            )
-            .collect();
-        return (res, Some(cycle));
+        //
+        // every node of a remaining edge still has a remaining outbound edge so
+        // following them from any node has to come back around to a visited node.
+        let mut path = vec![];
+        let mut visited = BTreeMap::new();
+        let mut node = child_of_relations.iter().next().map(|edge| edge.0);
+        while let Some(current) = node {
+            if let Some(&idx) = visited.get(current) {
+                path.drain(..idx);
+                break;
+            }
+            visited.insert(current, path.len());
+            path.push(current);
+            node = child_of_relations
+                .range((current, min)..=(current, max))
+                .next()
+                .map(|edge| edge.1);
+        }
+        return (res, Some(path));
     } else {
         return (res, None);
     }
@@ -152,16 +160,22 @@ mod tests {
         let nodes = (1..=2).into_iter().collect::<Vec<_>>();
         let val = [(1, 2), (2, 1)].iter();
         let res = topological_sort(nodes.iter(), val);
-        assert_eq!(res.1, Some([1, 2].iter().collect()));
+        assert_eq!(res.1, Some(vec![&1, &2]));
 
         let nodes = (1..=3).into_iter().collect::<Vec<_>>();
         let val = [(1, 2), (2, 1), (2, 3)].iter();
         let res = topological_sort(nodes.iter(), val);
-        assert_eq!(res.1, Some([1, 2].iter().collect()));
+        assert_eq!(res.1, Some(vec![&1, &2]));
 
         let nodes = (1..=4).into_iter().collect::<Vec<_>>();
         let val = [(1, 2), (2, 3), (3, 1), (3, 4)].iter();
         let res = topological_sort(nodes.iter(), val);
-        assert_eq!(res.1, Some([1, 2, 3].iter().collect()));
+        assert_eq!(res.1, Some(vec![&1, &2, &3]));
+
+        // nodes that only depend on the cycle are left out of it
+        let nodes = (1..=4).collect::<Vec<_>>();
+        let val = [(1, 2), (2, 3), (3, 2), (4, 3)].iter();
+        let res = topological_sort(nodes.iter(), val);
+        assert_eq!(res.1, Some(vec![&2, &3]));
     }
 }
@@ -0,0 +1,164 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use clap::Clap;
+use futures::{stream, StreamExt};
+
+use crate::{
+    binding::{Binding, Payload},
+    config::Config,
+    engine::{Importer, UpfrontImporter},
+    query::RunOptions,
+    util::error_printing::PrintableError,
+};
+
+use super::{Command, Opts};
+
+/// run an endpoint many times against the database and report its latencies
+#[derive(Clap)]
+pub struct Bench {
+    /// the endpoint to run
+    endpoint: String,
+
+    /// the payload as a json string or path to a file containing the payload
+    #[clap(short, long, default_value = "{}")]
+    payload: String,
+
+    /// the auth claims as a json string or path to a file containing the auth claims
+    #[clap(short, long)]
+    auth: Option<String>,
+
+    /// directory containing the modules
+    #[clap(short, long, default_value = ".")]
+    directory: String,
+
+    #[clap(short, long, default_value = "sql")]
+    extension: String,
+
+    /// how many times the endpoint is run
+    #[clap(short = 'n', long, default_value = "100")]
+    requests: usize,
+
+    /// how many runs happen at the same time, each on its own connection
+    #[clap(short, long, default_value = "1")]
+    concurrency: usize,
+
+    /// commit every run instead of rolling it back, so that writes persist
+    #[clap(long)]
+    commit: bool,
+}
+
+impl Command for Bench {
+    fn run_command(&self, opt: &Opts) -> anyhow::Result<()> {
+        if self.concurrency == 0 {
+            Err(anyhow!("concurrency must be at least 1"))?
+        }
+        let importer = match UpfrontImporter::new(
+            self.directory.as_str(),
+            self.extension.as_str(),
+            opt.strict,
+        ) {
+            Ok(importer) => importer,
+            Err(errors) => {
+                let mut buffer = String::new();
+                errors.as_slice().print_error(&mut buffer)?;
+                eprint!("{}", buffer);
+                return Err(anyhow!("{} module(s) failed to import", errors.len()));
+            }
+        };
+        let module = importer
+            .get_module_from_endpoint(self.endpoint.as_str())
+            .with_context(|| format!("no module declares the endpoint {}", self.endpoint))?;
+
+        let config = Config::read_config(opt.config.as_ref(), opt.config_search())
+            .context("config is needed to find postgres_url")?;
+        let (Payload(bindings), auth_bindings) = super::read_input::<_, BTreeMap<String, Binding>>(
+            self.payload.as_str(),
+            self.auth.as_deref(),
+        )?;
+
+        let options = RunOptions::from_config(&config, !self.commit);
+        let (requests, concurrency) = (self.requests, self.concurrency);
+        let (runs, elapsed) = super::block_on("bench", async move {
+            let pool =
+                crate::server::init::connect_to_db(&config, Some(concurrency as u32)).await?;
+            let start = Instant::now();
+            let runs: Vec<anyhow::Result<Duration>> = stream::iter(0..requests)
+                .map(|_| async {
+                    let start = Instant::now();
+                    crate::query::run_query(
+                        module.as_ref(),
+                        &importer,
+                        &pool,
+                        &bindings,
+                        auth_bindings.as_ref(),
+                        options,
+                    )
+                    .await
+                    .map(|_| start.elapsed())
+                })
+                .buffer_unordered(concurrency)
+                .collect()
+                .await;
+            anyhow::Result::<_>::Ok((runs, start.elapsed()))
+        })?;
+
+        let mut latencies = vec![];
+        let mut errors: BTreeMap<String, usize> = BTreeMap::new();
+        for run in runs {
+            match run {
+                Ok(latency) => latencies.push(latency),
+                Err(err) => *errors.entry(err.to_string()).or_default() += 1,
+            }
+        }
+        latencies.sort();
+
+        println!(
+            "ran {} {} times with a concurrency of {} in {:.2}s ({:.1} runs/s)",
+            self.endpoint,
+            requests,
+            concurrency,
+            elapsed.as_secs_f64(),
+            requests as f64 / elapsed.as_secs_f64()
+        );
+        for (name, percent) in &[("p50", 50), ("p95", 95), ("p99", 99), ("max", 100)] {
+            if let Some(latency) = percentile(latencies.as_slice(), *percent) {
+                println!("{}: {:.2}ms", name, latency.as_secs_f64() * 1000.0);
+            }
+        }
+
+        let failed: usize = errors.values().sum();
+        for (error, count) in errors.iter() {
+            println!("{} failed with: {}", count, error);
+        }
+        if failed != 0 {
+            Err(anyhow!("{} of {} runs failed", failed, requests))?
+        }
+        Ok(())
+    }
+}
+
+/// the nearest rank percentile of sorted latencies
+fn percentile(sorted: &[Duration], percent: usize) -> Option<Duration> {
+    let rank = (sorted.len() * percent).div_ceil(100);
+    sorted.get(rank.max(1) - 1).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_test() {
+        let latencies: Vec<_> = (1..=20).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 50), Some(Duration::from_millis(10)));
+        assert_eq!(percentile(&latencies, 95), Some(Duration::from_millis(19)));
+        assert_eq!(percentile(&latencies, 99), Some(Duration::from_millis(20)));
+        assert_eq!(percentile(&latencies, 100), Some(Duration::from_millis(20)));
+        assert_eq!(percentile(&latencies, 0), Some(Duration::from_millis(1)));
+        assert_eq!(percentile(&[], 50), None);
+    }
+}
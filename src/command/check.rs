@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+
+use clap::Clap;
+use sqlx::Executor;
+
+use crate::{
+    config::Config,
+    engine::UpfrontImporter,
+    query,
+    util::error_printing::{print_unpositioned_error, PrintableError},
+};
+
+use super::{Command, Opts};
+
+/// check that every module in a directory compiles, and optionally that every
+/// statement prepares against the database
+#[derive(Clap)]
+pub struct Check {
+    /// directory to check
+    directory: String,
+
+    #[clap(short, long, default_value = "sql")]
+    extension: String,
+
+    /// prepare each statement against this database to catch schema errors. nothing
+    /// is executed
+    #[clap(long)]
+    database_url: Option<String>,
+}
+
+impl Command for Check {
+    fn run_command(&self, opt: &Opts) -> anyhow::Result<()> {
+        let importer = match UpfrontImporter::new(
+            self.directory.as_str(),
+            self.extension.as_str(),
+            opt.strict,
+        ) {
+            Ok(importer) => importer,
+            Err(errors) => {
+                let mut buffer = String::new();
+                errors.as_slice().print_error(&mut buffer)?;
+                eprint!("{}", buffer);
+                return Err(anyhow!("{} module(s) failed to import", errors.len()));
+            }
+        };
+
+        // only validate the config when there is one since checking modules does not need it
+        let config_path = opt.config.clone().or_else(|| {
+            opt.config_search()
+                .then(|| Config::find_config_file().ok())
+                .flatten()
+        });
+        let max_import_depth = match config_path {
+            Some(config_path) => {
+                Config::read_config_or_print_error(Some(config_path), false)?
+                    .server
+                    .max_import_depth
+            }
+            None => query::DEFAULT_MAX_IMPORT_DEPTH,
+        };
+
+        let database_url = match self.database_url.as_ref() {
+            Some(database_url) => database_url.clone(),
+            None => {
+                info!("all modules imported succesfully");
+                return Ok(());
+            }
+        };
+
+        let failures = super::block_on("check", async move {
+            prepare_all(&importer, database_url.as_str(), max_import_depth).await
+        })?;
+
+        if !failures.is_empty() {
+            let mut buffer = String::new();
+            for (location, explanation) in failures.iter() {
+                print_unpositioned_error(
+                    &mut buffer,
+                    explanation.as_str(),
+                    &location.to_string_lossy(),
+                )?;
+                buffer.push('\n');
+            }
+            eprint!("{}", buffer);
+            return Err(anyhow!("{} statement(s) failed to prepare", failures.len()));
+        }
+
+        info!("all statements prepared succesfully");
+        Ok(())
+    }
+}
+
+/// prepares every statement of every module and collects the ones postgres rejects
+async fn prepare_all(
+    importer: &UpfrontImporter,
+    database_url: &str,
+    max_import_depth: usize,
+) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let pool = crate::server::init::connect_to_url(database_url, Some(1)).await?;
+    let mut conn = pool.acquire().await?;
+
+    let mut failures = vec![];
+    for (location, module) in importer.get_all_modules() {
+        for (idx, statement) in module.sql.iter().enumerate() {
+            let built = query::build_query_statement(
+                &module,
+                importer,
+                statement.as_slice(),
+                None,
+                max_import_depth,
+            );
+            let (stmt, _) = match built {
+                Ok(stmt) => stmt,
+                Err(err) => {
+                    failures.push((location.clone(), format!("query_{}: {}", idx, err)));
+                    continue;
+                }
+            };
+
+            // PREPARE only plans the statement so nothing is executed
+            let prepare = format!("PREPARE justsql_check AS {}", stmt);
+            match conn.execute(prepare.as_str()).await {
+                Ok(_) => {
+                    conn.execute("DEALLOCATE justsql_check").await?;
+                }
+                Err(err) => failures.push((location.clone(), format!("query_{}: {}", idx, err))),
+            }
+        }
+    }
+
+    drop(conn);
+    pool.close().await;
+    Ok(failures)
+}
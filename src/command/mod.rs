@@ -1,11 +1,62 @@
 use anyhow::Context;
 use clap::Clap;
 use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use crate::{
+    binding::{Binding, Payload},
+    codegen::Module,
+    engine::{Importer, ModuleCollection, UpfrontImporter},
+    util::{error_printing::ColorChoice, logging::LogFormat},
+};
+
+mod bench;
+mod check;
+mod init;
 mod peek;
 mod print;
 mod run;
+mod schema;
 mod server;
+mod watch;
+
+/// the module along with every module in its directory, so its `endpoint:` imports
+/// resolve against the modules the server would serve from that directory
+pub fn module_paths(module: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let module = module
+        .canonicalize()
+        .with_context(|| format!("could not find {}", module.display()))?;
+    let directory = module
+        .parent()
+        .ok_or_else(|| anyhow!("{} has no directory", module.display()))?;
+    let extension = module
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("sql");
+    let (mut paths, _) =
+        ModuleCollection::find_files(directory.to_string_lossy().as_ref(), extension, false);
+    if !paths.contains(&module) {
+        paths.push(module);
+    }
+    Ok(paths)
+}
+
+/// imports the module that run, peek and print are given
+pub fn import_module(module: &str, strict: bool) -> anyhow::Result<(UpfrontImporter, Arc<Module>)> {
+    let location = Path::new(module).canonicalize()?;
+    let paths = module_paths(location.as_path())?;
+    let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+    let importer = UpfrontImporter::from_paths_or_print_error(paths.as_slice(), strict)
+        .ok_or_else(|| anyhow!("importing sql failed"))?;
+    let module = importer.get_module_from_location(location.as_path())?;
+    Ok((importer, module))
+}
 
 pub fn read_input<A: DeserializeOwned, B: DeserializeOwned>(
     input: &str,
@@ -18,6 +69,42 @@ pub fn read_input<A: DeserializeOwned, B: DeserializeOwned>(
     Ok((input, auth_input))
 }
 
+/// reads a payload given either as a single json string or json file path, or as
+/// `key=value` arguments. values are read as json when they parse and as strings
+/// otherwise, so `id=5` binds an int while `name=bob` binds a string
+pub fn read_payload(args: &[String]) -> anyhow::Result<Payload> {
+    if let [input] = args {
+        if key_value(input).is_none() {
+            return read_json_or_json_file(input).context("could not read input json");
+        }
+    }
+    let mut bindings = BTreeMap::new();
+    for arg in args {
+        let (key, value) = key_value(arg).ok_or_else(|| {
+            anyhow!(
+                "{} is not a key=value argument, a json payload can not be combined with them",
+                arg
+            )
+        })?;
+        let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.into()));
+        let binding =
+            Binding::from_json(value).with_context(|| format!("could not bind {}", key))?;
+        if bindings.insert(key.to_string(), binding).is_some() {
+            Err(anyhow!("{} is given more than once", key))?
+        }
+    }
+    Ok(Payload(bindings))
+}
+
+/// splits `key=value` when the key could be the name of a parameter
+fn key_value(arg: &str) -> Option<(&str, &str)> {
+    let (key, value) = arg.split_once('=')?;
+    if key.is_empty() || !key.chars().all(|chr| chr.is_alphanumeric() || chr == '_') {
+        return None;
+    }
+    Some((key, value))
+}
+
 pub fn read_json_or_json_file<T: DeserializeOwned>(data: &str) -> anyhow::Result<T> {
     serde_json::from_str(data)
         .with_context(|| "input is not a json")
@@ -28,16 +115,59 @@ pub fn read_json_or_json_file<T: DeserializeOwned>(data: &str) -> anyhow::Result
         .with_context(|| "input is not a json nor a readable json file path")
 }
 
+/// prints the output or writes it to the given file, creating its parent directories
+pub fn write_output(output: Option<&Path>, contents: &str) -> anyhow::Result<()> {
+    let path = match output {
+        Some(path) => path,
+        None => {
+            print!("{}", contents);
+            return Ok(());
+        }
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("could not create directory {}", parent.display()))?;
+    }
+    std::fs::write(path, contents)
+        .with_context(|| format!("could not write output to {}", path.display()))
+}
+
+/// runs the future on a new actix system named after the command. sqlx runs on the actix
+/// runtime so the queries must be driven by an actix system
+pub fn block_on<F: Future + 'static>(name: &str, future: F) -> F::Output {
+    actix_rt::System::new(name).block_on(future)
+}
+
 /// This doc string acts as a help message when the user runs '--help'
 /// as do all doc strings on fields
 #[derive(Clap)]
-#[clap(version = "0.2.0", author = "Shalom Yiblet <shalom.yiblet@gmail.com>")]
+#[clap(version = crate::VERSION, author = "Shalom Yiblet <shalom.yiblet@gmail.com>")]
 pub struct Opts {
-    /// Set the file path where justsql will read the configs from. If this is left unset,
-    /// justsql will recursively look for a `justsql.config.yaml` in current and parent
-    /// directories.
-    #[clap(short, long)]
+    /// Set the file path where justsql will read the configs from. The format is inferred
+    /// from the extension: .yaml or .yml, .json or .toml. If this is left unset, justsql will
+    /// recursively look in the current and parent directories for the first of
+    /// `justsql.config.yaml`, `justsql.config.yml`, `justsql.config.json` and
+    /// `justsql.config.toml`.
+    #[clap(short, long, env = "JUSTSQL_CONFIG")]
     config: Option<std::path::PathBuf>,
+    /// Do not look for a `justsql.config.yaml` in the current and parent directories when
+    /// no config is given, so that a parent project's config is never picked up.
+    #[clap(long)]
+    no_config_search: bool,
+    /// Set the format of the logs. Either `text` or `json`, which prints one json object
+    /// per line.
+    #[clap(long, default_value = "text")]
+    pub log_format: LogFormat,
+    /// Colorize errors. Either `auto`, `always` or `never`. `auto` only colors errors when
+    /// stderr is a terminal and the logs are text.
+    #[clap(long, default_value = "auto")]
+    pub color: ColorChoice,
+    /// How many lines before and after the line of an error are printed.
+    #[clap(long, default_value = "0")]
+    pub error_context: usize,
+    /// Treat warnings, like unused parameters or unreachable modules, as errors.
+    #[clap(long)]
+    pub strict: bool,
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
@@ -46,13 +176,22 @@ impl Opts {
     pub fn run(&self) -> anyhow::Result<()> {
         self.subcmd.run_command(self)
     }
+
+    /// whether the config is looked for in parent directories when none is given
+    pub fn config_search(&self) -> bool {
+        !self.no_config_search
+    }
 }
 
 #[derive(Clap)]
 pub enum SubCommand {
+    Bench(bench::Bench),
+    Check(check::Check),
+    Init(init::Init),
     Peek(peek::Peek),
     Print(print::Print),
     Run(run::Run),
+    Schema(schema::Schema),
     Server(server::Server),
 }
 
@@ -63,10 +202,62 @@ pub trait Command {
 impl Command for SubCommand {
     fn run_command(&self, opt: &Opts) -> anyhow::Result<()> {
         match self {
+            SubCommand::Bench(bench) => bench.run_command(opt),
+            SubCommand::Check(check) => check.run_command(opt),
+            SubCommand::Init(init) => init.run_command(opt),
             SubCommand::Peek(peek) => peek.run_command(opt),
             SubCommand::Print(print) => print.run_command(opt),
             SubCommand::Run(run) => run.run_command(opt),
+            SubCommand::Schema(schema) => schema.run_command(opt),
             SubCommand::Server(server) => server.run_command(opt),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn read_payload_test() {
+        let Payload(bindings) = read_payload(&args(&[
+            "id=5",
+            "name=bob",
+            "admin=true",
+            "ids=[1,2]",
+            "nick=",
+        ]))
+        .unwrap();
+        assert_eq!(bindings["id"], Binding::Int(5));
+        assert_eq!(bindings["name"], Binding::String("bob".into()));
+        assert_eq!(bindings["admin"], Binding::Bool(true));
+        assert_eq!(
+            bindings["ids"],
+            Binding::Array(vec![Binding::Int(1), Binding::Int(2)])
+        );
+        assert_eq!(bindings["nick"], Binding::String("".into()));
+
+        // a json payload may contain = without being read as key=value
+        let Payload(bindings) = read_payload(&args(&[r#"{"query": "a=b"}"#])).unwrap();
+        assert_eq!(bindings["query"], Binding::String("a=b".into()));
+
+        assert!(read_payload(&args(&[r#"{"id": 5}"#, "name=bob"])).is_err());
+        assert!(read_payload(&args(&["id=5", "id=6"])).is_err());
+    }
+
+    #[test]
+    fn import_module_test() {
+        // all_user_count imports the all_users endpoint that is declared next to it
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/all_user_count.sql");
+        let (importer, module) = import_module(path.to_str().unwrap(), false).unwrap();
+        let all_users = importer.get_module_from_endpoint("all_users").unwrap();
+        assert_eq!(
+            module.front_matter.imports["all_users"].0,
+            all_users.front_matter.location
+        );
+    }
+}
@@ -1,7 +1,10 @@
-use std::path::Path;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use super::{Command, Opts};
-use crate::engine::{Importer, UpfrontImporter};
+use crate::{binding::Payload, query::RunOptions};
 use anyhow::Context;
 use clap::Clap;
 
@@ -11,8 +14,10 @@ pub struct Peek {
     /// location of the sql file
     module: String,
 
-    /// the payload as a json string or path to a file containing the payload
-    json: String,
+    /// the payload as a json string, a path to a file containing the payload or
+    /// `key=value` arguments such as `id=5 name=bob`
+    #[clap(required = true)]
+    payload: Vec<String>,
 
     /// the auth claims as a json string or path to a file containing the auth claims
     #[clap(short, long)]
@@ -21,46 +26,78 @@ pub struct Peek {
     /// show only the first output
     #[clap(short, long)]
     first: bool,
+
+    /// show the rows of every statement, named by their position, instead of only the
+    /// rows of the last one
+    #[clap(long, conflicts_with = "first")]
+    all_statements: bool,
+
+    /// write the results to this file instead of stdout
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+    /// run the module again every time it or one of its imports changes
+    #[clap(short, long)]
+    watch: bool,
+
+    /// milliseconds to wait for file changes to settle before running again in watch mode
+    #[clap(long, default_value = "250")]
+    watch_debounce_ms: u64,
 }
 
 impl Command for Peek {
     fn run_command(&self, opt: &Opts) -> anyhow::Result<()> {
-        let importer = UpfrontImporter::from_paths_or_print_error(&[self.module.as_ref()])
-            .ok_or_else(|| anyhow!("importing sql failed"))?;
-
-        tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?
-            .block_on(async {
-                let config = crate::config::Config::read_config(opt.config.as_ref())
-                    .context("config is needed to find postgres_url")?;
+        if !self.watch {
+            return self.run_once(opt);
+        }
+        super::watch::watch_module(
+            Path::new(self.module.as_str()),
+            Duration::from_millis(self.watch_debounce_ms),
+            || self.run_once(opt),
+        )
+    }
+}
 
-                let (bindings, auth_bindings) =
-                    super::read_input(self.json.as_str(), self.auth.as_ref().map(String::as_str))?;
+impl Peek {
+    fn run_once(&self, opt: &Opts) -> anyhow::Result<()> {
+        let (importer, module) = super::import_module(self.module.as_str(), opt.strict)?;
 
-                let pool = crate::server::init::connect_to_db(&config, Some(1)).await?;
+        let config = crate::config::Config::read_config(opt.config.as_ref(), opt.config_search())
+            .context("config is needed to find postgres_url")?;
 
-                let module = importer.get_module_from_location(
-                    Path::new(self.module.as_str()).canonicalize()?.as_path(),
-                )?;
-                let res = crate::query::run_query(
-                    module.as_ref(),
-                    &importer,
-                    &pool,
-                    &bindings,
-                    auth_bindings.as_ref(),
-                    true,
-                )
-                .await?;
+        let Payload(bindings) = super::read_payload(self.payload.as_slice())?;
+        let auth_bindings = self
+            .auth
+            .as_deref()
+            .map(super::read_json_or_json_file)
+            .transpose()
+            .context("could not read input json")?;
 
-                if self.first {
-                    println!("{}", serde_json::to_string_pretty(&res[0])?);
-                } else {
-                    println!("{}", serde_json::to_string_pretty(&res)?);
-                }
-                Ok::<_, anyhow::Error>(())
-            })?;
+        let options = RunOptions {
+            all_statements: self.all_statements,
+            ..RunOptions::from_config(&config, true)
+        };
+        let mut res = super::block_on("peek", async move {
+            let pool = crate::server::init::connect_to_db(&config, Some(1)).await?;
+            crate::query::run_statements(
+                module.as_ref(),
+                &importer,
+                &pool,
+                &bindings,
+                auth_bindings.as_ref(),
+                options,
+            )
+            .await
+        })?;
 
-        Ok(())
+        if self.all_statements {
+            let output = format!("{}\n", serde_json::to_string_pretty(&res)?);
+            return super::write_output(self.output.as_deref(), output.as_str());
+        }
+        let res = res.pop().map(|result| result.rows).unwrap_or_default();
+        let output = match res.first() {
+            Some(row) if self.first => serde_json::to_string_pretty(row)?,
+            _ => serde_json::to_string_pretty(&res)?,
+        };
+        super::write_output(self.output.as_deref(), format!("{}\n", output).as_str())
     }
 }
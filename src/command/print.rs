@@ -1,11 +1,15 @@
-use std::{collections::BTreeMap, path::Path};
+use std::{borrow::Cow, collections::BTreeMap};
 
 use clap::Clap;
+use sqlx::postgres::PgValueFormat;
 
 use crate::{
-    binding::Binding,
-    engine::{Importer, UpfrontImporter},
+    binding::{Binding, Payload},
+    codegen::{Module, ParamType},
+    config::Config,
+    engine::UpfrontImporter,
     query,
+    row_type::convert_row,
 };
 
 use super::{read_json_or_json_file, Command, Opts};
@@ -22,21 +26,131 @@ pub struct Print {
     /// the auth claims as a json string or path to a file containing the auth claims
     #[clap(short, long)]
     auth: Option<String>,
+
+    /// print the query plan of each statement. the plan is fetched from the database
+    /// when a payload is given, otherwise the EXPLAIN statements are printed
+    #[clap(short, long)]
+    explain: bool,
+}
+
+/// prints out a statement indented under its header
+fn print_statement(header: &str, stmt: &str) {
+    println!("{}", header);
+    for lines in stmt.split('\n').filter(|line| line.trim() != "") {
+        println!("    {}", lines);
+    }
+    println!(";");
+}
+
+impl Print {
+    fn explain(
+        &self,
+        config: Option<Config>,
+        importer: &UpfrontImporter,
+        module: &Module,
+        payload: Option<&BTreeMap<String, Binding>>,
+        auth_claims: Option<&BTreeMap<String, Binding>>,
+        max_import_depth: usize,
+    ) -> anyhow::Result<()> {
+        let statements = module
+            .sql
+            .iter()
+            .map(|statement| {
+                query::build_query_statement(
+                    module,
+                    importer,
+                    statement.as_slice(),
+                    payload,
+                    max_import_depth,
+                )
+            })
+            .collect::<anyhow::Result<Vec<(String, Vec<ParamType>)>>>()?;
+
+        let bindings = match payload {
+            Some(bindings) => bindings,
+            None => {
+                for (stmt, _) in statements.iter() {
+                    print_statement("EXPLAIN (FORMAT JSON)", stmt.as_str());
+                }
+                return Ok(());
+            }
+        };
+
+        let assume_null_if_missing =
+            matches!(config.as_ref(), Some(config) if config.assume_null_if_missing);
+
+        let explain_statements = statements
+            .iter()
+            .map(|(stmt, params)| {
+                let bound_params = query::bind_params(
+                    params.as_slice(),
+                    bindings,
+                    auth_claims,
+                    assume_null_if_missing,
+                )?;
+                Ok((
+                    format!("EXPLAIN (FORMAT JSON) {}", stmt),
+                    bound_params
+                        .into_iter()
+                        .map(Cow::into_owned)
+                        .collect::<Vec<_>>(),
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let plans = super::block_on("explain", async move {
+            let config = config.ok_or_else(|| anyhow!("no config was read"))?;
+            let pool = crate::server::init::connect_to_db(&config, Some(1)).await?;
+            let explain_statements = explain_statements
+                .iter()
+                .map(|(stmt, bindings)| {
+                    (stmt.clone(), bindings.iter().map(Cow::Borrowed).collect())
+                })
+                .collect();
+
+            let mut tx = pool.begin().await?;
+            let mut plans = vec![];
+            for query in query::build_queries(&explain_statements)? {
+                let mut plan = convert_row(
+                    query.fetch_one(&mut tx).await?,
+                    Default::default(),
+                    PgValueFormat::Binary,
+                )?;
+                plans.push(plan.remove("QUERY PLAN"));
+            }
+            tx.rollback().await?;
+            Ok::<_, anyhow::Error>(plans)
+        });
+
+        match plans {
+            Ok(plans) => {
+                for (idx, plan) in plans.iter().enumerate() {
+                    println!("-- query_{}", idx);
+                    println!("{}", serde_json::to_string_pretty(plan)?);
+                }
+            }
+            Err(err) => {
+                warn!("could not explain the query using the database: {}", err);
+                for (stmt, _) in statements.iter() {
+                    print_statement("EXPLAIN (FORMAT JSON)", stmt.as_str());
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Command for Print {
     // TODO split up this function
-    fn run_command(&self, _opt: &Opts) -> anyhow::Result<()> {
-        let importer = UpfrontImporter::from_paths_or_print_error(&[self.module.as_ref()])
-            .ok_or_else(|| anyhow!("importing sql failed"))?;
-        let module = importer
-            .get_module_from_location(Path::new(self.module.as_str()).canonicalize()?.as_path())?;
-
+    fn run_command(&self, opt: &Opts) -> anyhow::Result<()> {
+        let (importer, module) = super::import_module(self.module.as_str(), opt.strict)?;
         let payload = self
             .json
             .as_ref()
-            .map(|payload| read_json_or_json_file::<BTreeMap<String, Binding>>(payload.as_str()))
-            .transpose()?;
+            .map(|payload| read_json_or_json_file::<Payload>(payload.as_str()))
+            .transpose()?
+            .map(|Payload(bindings)| bindings);
 
         let auth_claims = self
             .auth
@@ -44,18 +158,50 @@ impl Command for Print {
             .map(|payload| read_json_or_json_file::<BTreeMap<String, Binding>>(payload.as_str()))
             .transpose()?;
 
-        for (idx, statement) in module.sql.iter().enumerate() {
-            println!("PREPARE query_{} AS", idx);
-            let (stmt, params) =
-                query::build_query_statement(&module, &importer, statement.as_slice())?;
-            for lines in stmt.split('\n').filter(|line| line.trim() != "") {
-                println!("    {}", lines);
+        // printing does not need a config so the defaults are used without one
+        let config = match Config::read_config(opt.config.as_ref(), opt.config_search()) {
+            Ok(config) => Some(config),
+            Err(err) => {
+                warn!("printing with the default settings: {:#}", err);
+                None
             }
-            println!(";");
+        };
+        let max_import_depth = config
+            .as_ref()
+            .map_or(query::DEFAULT_MAX_IMPORT_DEPTH, |config| {
+                config.server.max_import_depth
+            });
+        let assume_null_if_missing =
+            matches!(config.as_ref(), Some(config) if config.assume_null_if_missing);
+
+        if self.explain {
+            return self.explain(
+                config,
+                &importer,
+                module.as_ref(),
+                payload.as_ref(),
+                auth_claims.as_ref(),
+                max_import_depth,
+            );
+        }
+
+        for (idx, statement) in module.sql.iter().enumerate() {
+            let (stmt, params) = query::build_query_statement(
+                &module,
+                &importer,
+                statement.as_slice(),
+                payload.as_ref(),
+                max_import_depth,
+            )?;
+            print_statement(format!("PREPARE query_{} AS", idx).as_str(), stmt.as_str());
 
             if let Some(bindings) = payload.as_ref() {
-                let bound_params =
-                    query::bind_params(params.as_slice(), &bindings, auth_claims.as_ref())?;
+                let bound_params = query::bind_params(
+                    params.as_slice(),
+                    &bindings,
+                    auth_claims.as_ref(),
+                    assume_null_if_missing,
+                )?;
                 print!("EXECUTE query_{}(", idx);
                 for (idx, arg) in bound_params.iter().cloned().enumerate() {
                     if idx == 0 {
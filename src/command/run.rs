@@ -1,18 +1,49 @@
-use std::path::Path;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 use super::{Command, Opts};
-use crate::engine::{Importer, UpfrontImporter};
+use crate::{
+    binding::Payload,
+    config::Config,
+    query::RunOptions,
+    row_type::{Row, RowType},
+};
 use anyhow::Context;
 use clap::Clap;
+use serde_json::Value;
 
-/// run a query  
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(anyhow!("output format must be either 'json' or 'csv'")),
+        }
+    }
+}
+
+/// run a query
 #[derive(Clap)]
 pub struct Run {
     /// location of the sql file
     module: String,
 
-    /// the payload as a json string or path to a file containing the payload
-    json: String,
+    /// the payload as a json string, a path to a file containing the payload or
+    /// `key=value` arguments such as `id=5 name=bob`
+    #[clap(required = true)]
+    payload: Vec<String>,
 
     /// the auth claims as a json string or path to a file containing the auth claims
     #[clap(short, long)]
@@ -21,45 +52,216 @@ pub struct Run {
     /// show only the first output
     #[clap(short, long)]
     first: bool,
+
+    /// show the rows of every statement, named by their position, instead of only the
+    /// rows of the last one
+    #[clap(long, conflicts_with = "first")]
+    all_statements: bool,
+
+    /// either `json` or `csv`. csv is only supported for modules with a single statement
+    #[clap(long, default_value = "json")]
+    format: OutputFormat,
+
+    /// write the results to this file instead of stdout
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+    /// run the module again every time it or one of its imports changes
+    #[clap(short, long)]
+    watch: bool,
+
+    /// milliseconds to wait for file changes to settle before running again in watch mode
+    #[clap(long, default_value = "250")]
+    watch_debounce_ms: u64,
+    /// commit to a database that is not on this machine without asking first
+    #[clap(short, long)]
+    yes: bool,
 }
 
 impl Command for Run {
     fn run_command(&self, opt: &Opts) -> anyhow::Result<()> {
-        let importer = UpfrontImporter::from_paths_or_print_error(&[self.module.as_ref()])
-            .ok_or_else(|| anyhow!("importing sql failed"))?;
-
-        tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?
-            .block_on(async {
-                let config = crate::config::Config::read_config(opt.config.as_ref())
-                    .context("config is needed to find postgres_url")?;
-
-                let (bindings, auth_bindings) =
-                    super::read_input(self.json.as_str(), self.auth.as_ref().map(String::as_str))?;
-                let pool = crate::server::init::connect_to_db(&config, Some(1)).await?;
-
-                let module = importer.get_module_from_location(
-                    Path::new(self.module.as_str()).canonicalize()?.as_path(),
-                )?;
-                let res = crate::query::run_query(
-                    module.as_ref(),
-                    &importer,
-                    &pool,
-                    &bindings,
-                    auth_bindings.as_ref(),
-                    false,
-                )
-                .await?;
-
-                if self.first {
-                    println!("{}", serde_json::to_string_pretty(&res[0])?);
-                } else {
-                    println!("{}", serde_json::to_string_pretty(&res)?);
-                }
-                Ok::<_, anyhow::Error>(())
-            })?;
-
-        Ok(())
+        if !self.yes {
+            let config = Config::read_config(opt.config.as_ref(), opt.config_search())
+                .context("config is needed to find postgres_url")?;
+            confirm_remote(&config)?;
+        }
+        if !self.watch {
+            return self.run_once(opt);
+        }
+        eprintln!(
+            "every run commits its changes, use `justsql peek --watch` to iterate on modules that write"
+        );
+        super::watch::watch_module(
+            Path::new(self.module.as_str()),
+            Duration::from_millis(self.watch_debounce_ms),
+            || self.run_once(opt),
+        )
+    }
+}
+
+impl Run {
+    fn run_once(&self, opt: &Opts) -> anyhow::Result<()> {
+        let (importer, module) = super::import_module(self.module.as_str(), opt.strict)?;
+
+        let config = Config::read_config(opt.config.as_ref(), opt.config_search())
+            .context("config is needed to find postgres_url")?;
+
+        let Payload(bindings) = super::read_payload(self.payload.as_slice())?;
+        let auth_bindings = self
+            .auth
+            .as_deref()
+            .map(super::read_json_or_json_file)
+            .transpose()
+            .context("could not read input json")?;
+
+        if self.format == OutputFormat::Csv && self.all_statements {
+            Err(anyhow!("csv output can not be used with --all-statements"))?
+        }
+        if self.format == OutputFormat::Csv && !module.is_single_statement() {
+            Err(anyhow!(
+                "csv output needs a module with a single statement but {} has {}",
+                self.module,
+                module.sql.len()
+            ))?
+        }
+
+        let options = RunOptions {
+            all_statements: self.all_statements,
+            ..RunOptions::from_config(&config, false)
+        };
+        let mut res = super::block_on("run", async move {
+            let pool = crate::server::init::connect_to_db(&config, Some(1)).await?;
+            crate::query::run_statements(
+                module.as_ref(),
+                &importer,
+                &pool,
+                &bindings,
+                auth_bindings.as_ref(),
+                options,
+            )
+            .await
+        })?;
+
+        if self.all_statements {
+            let output = format!("{}\n", serde_json::to_string_pretty(&res)?);
+            return super::write_output(self.output.as_deref(), output.as_str());
+        }
+        let res = res.pop().map(|result| result.rows).unwrap_or_default();
+        let res = if self.first {
+            &res[..res.len().min(1)]
+        } else {
+            &res[..]
+        };
+        let output = match (self.format, res) {
+            (OutputFormat::Csv, rows) => to_csv(rows)?,
+            (OutputFormat::Json, [row]) if self.first => {
+                format!("{}\n", serde_json::to_string_pretty(row)?)
+            }
+            (OutputFormat::Json, rows) => format!("{}\n", serde_json::to_string_pretty(rows)?),
+        };
+
+        super::write_output(self.output.as_deref(), output.as_str())
+    }
+}
+
+/// asks on the terminal before committing to a database that is not on this machine
+fn confirm_remote(config: &Config) -> anyhow::Result<()> {
+    let database = &config.database;
+    if database.is_local()? {
+        return Ok(());
+    }
+    let environment = database
+        .environment
+        .as_ref()
+        .and_then(|environment| environment.value())
+        .map(|environment| format!("the {} database at ", environment))
+        .unwrap_or_default();
+    eprint!(
+        "run commits its changes to {}{}, continue? [y/N] ",
+        environment,
+        database.host()?.unwrap_or_default()
+    );
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(anyhow!(
+            "aborted, pass --yes to run against this database without confirming"
+        )),
+    }
+}
+
+/// writes the rows as csv with a header made from the column names
+fn to_csv(rows: &[Row]) -> anyhow::Result<String> {
+    let mut buf = String::new();
+    let header = match rows.first() {
+        Some(row) => row
+            .keys()
+            .map(String::as_str)
+            .map(csv_field)
+            .collect::<Vec<_>>(),
+        None => return Ok(buf),
+    };
+    buf.push_str(header.join(",").as_str());
+    buf.push_str("\r\n");
+
+    for row in rows {
+        let fields = row
+            .values()
+            .map(|value| Ok(csv_field(to_csv_cell(value)?.as_str())))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        buf.push_str(fields.join(",").as_str());
+        buf.push_str("\r\n");
+    }
+    Ok(buf)
+}
+
+/// the scalar form of a value. nulls are empty and arrays or objects are json encoded
+fn to_csv_cell(value: &RowType) -> anyhow::Result<String> {
+    let cell = match serde_json::to_value(value)? {
+        Value::Null => String::new(),
+        Value::String(string) => string,
+        value @ Value::Array(_) | value @ Value::Object(_) => serde_json::to_string(&value)?,
+        value => value.to_string(),
+    };
+    Ok(cell)
+}
+
+/// quotes a field if it contains a delimiter, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::row_type::Category;
+
+    #[test]
+    fn to_csv_test() {
+        let row: Row = vec![
+            ("id".to_string(), RowType::Int4(Category::Value(Some(1)))),
+            (
+                "name".to_string(),
+                RowType::Text(Category::Value(Some("Doe, \"Jane\"".into()))),
+            ),
+            (
+                "tags".to_string(),
+                RowType::Text(Category::Array(Some(vec![Some("a".into()), None]))),
+            ),
+            ("missing".to_string(), RowType::Bool(Category::Value(None))),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            to_csv(&[row]).unwrap(),
+            "id,name,tags,missing\r\n1,\"Doe, \"\"Jane\"\"\",\"[\"\"a\"\",null]\",\r\n"
+        );
+        assert_eq!(to_csv(&[]).unwrap(), "");
     }
 }
@@ -0,0 +1,169 @@
+use std::{collections::BTreeSet, path::PathBuf};
+
+use clap::Clap;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    codegen::{Arg, Interp, Module, ParamKind},
+    engine::{Importer, UpfrontImporter},
+    util::error_printing::PrintableError,
+};
+
+use super::{Command, Opts};
+
+/// print the json schema of an endpoint's payload
+#[derive(Clap)]
+pub struct Schema {
+    /// the endpoint to describe
+    endpoint: String,
+
+    /// directory of the modules
+    #[clap(default_value = ".")]
+    directory: String,
+
+    #[clap(short, long, default_value = "sql")]
+    extension: String,
+
+    /// write the schema to this file instead of stdout
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+impl Command for Schema {
+    fn run_command(&self, opt: &Opts) -> anyhow::Result<()> {
+        let importer = match UpfrontImporter::new(
+            self.directory.as_str(),
+            self.extension.as_str(),
+            opt.strict,
+        ) {
+            Ok(importer) => importer,
+            Err(errors) => {
+                let mut buffer = String::new();
+                errors.as_slice().print_error(&mut buffer)?;
+                eprint!("{}", buffer);
+                return Err(anyhow!("{} module(s) failed to import", errors.len()));
+            }
+        };
+
+        let module = importer.get_module_from_endpoint(self.endpoint.as_str())?;
+        let schema = payload_schema(self.endpoint.as_str(), &module);
+        let output = format!("{}\n", serde_json::to_string_pretty(&schema)?);
+        super::write_output(self.output.as_deref(), output.as_str())
+    }
+}
+
+/// the json schema of the payload a module accepts. a parameter is optional when
+/// every use of it is inside of its own `@if` fragment
+pub fn payload_schema(endpoint: &str, module: &Module) -> Value {
+    let mut required = BTreeSet::new();
+    let mut positions = 0;
+    for statement in module.sql.iter() {
+        collect_required(statement, &BTreeSet::new(), &mut required, &mut positions);
+    }
+
+    let mut schema = json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": endpoint,
+    });
+    if positions != 0 {
+        schema["type"] = json!("array");
+        schema["minItems"] = json!(positions);
+        return schema;
+    }
+
+    let front_matter = &module.front_matter;
+    let properties: Map<String, Value> = front_matter
+        .params
+        .iter()
+        .map(|param| {
+            let property = match front_matter.param_kinds.get(param) {
+                Some(ParamKind::Json) => json!({ "description": "bound as a single json value" }),
+                None => json!({}),
+            };
+            (param.clone(), property)
+        })
+        .collect();
+    schema["type"] = json!("object");
+    schema["properties"] = Value::Object(properties);
+    schema["required"] = json!(front_matter
+        .params
+        .iter()
+        .filter(|param| required.contains(param.as_str()))
+        .collect::<Vec<_>>());
+    schema
+}
+
+/// collects the parameters used outside of the `@if` fragments they guard along with
+/// the highest positional parameter
+fn collect_required<'a>(
+    interps: &'a [Interp],
+    guards: &BTreeSet<&'a str>,
+    required: &mut BTreeSet<&'a str>,
+    positions: &mut usize,
+) {
+    for interp in interps {
+        match interp {
+            Interp::Param(param) if !guards.contains(param.as_str()) => {
+                required.insert(param.as_str());
+            }
+            Interp::CallSite(_, args) => {
+                required.extend(args.iter().filter_map(|arg| match arg {
+                    Arg::Param(param) if !guards.contains(param.as_str()) => Some(param.as_str()),
+                    _ => None,
+                }))
+            }
+            Interp::PositionalParam(position) => *positions = (*positions).max(*position),
+            Interp::Conditional(param, fragment) => {
+                let mut guards = guards.clone();
+                guards.insert(param.as_str());
+                collect_required(fragment, &guards, required, positions);
+            }
+            Interp::Foreach(param, fragment) => {
+                if !guards.contains(param.as_str()) {
+                    required.insert(param.as_str());
+                }
+                collect_required(fragment, guards, required, positions);
+            }
+            Interp::Param(_) | Interp::Field(_, _) | Interp::Literal(_) | Interp::AuthParam(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_schema_test() {
+        let module = Module::from_str(
+            PathBuf::new(),
+            "-- @param id, status, filter: json\nselect * from users where id = @id and data @> @filter @if(status) and status = @status @endif",
+        )
+        .unwrap();
+        assert_eq!(
+            payload_schema("users", &module),
+            json!({
+                "$schema": "http://json-schema.org/draft-07/schema#",
+                "title": "users",
+                "type": "object",
+                "properties": {
+                    "id": {},
+                    "status": {},
+                    "filter": { "description": "bound as a single json value" },
+                },
+                "required": ["id", "filter"],
+            })
+        );
+
+        let module = Module::from_str(PathBuf::new(), "select @2, @1").unwrap();
+        assert_eq!(
+            payload_schema("pair", &module),
+            json!({
+                "$schema": "http://json-schema.org/draft-07/schema#",
+                "title": "pair",
+                "type": "array",
+                "minItems": 2,
+            })
+        );
+    }
+}
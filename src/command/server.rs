@@ -1,13 +1,16 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use actix_web::{middleware, web, App, HttpServer};
 use clap::Clap;
 
 use crate::{
     config::Config,
-    engine::{Evaluator, UpfrontImporter, WatchingImporter},
-    server::routes,
-    util::error_printing::PrintableError,
+    engine::{Evaluator, LazyImporter, UpfrontImporter, WatchingImporter},
+    server::{cache::ResponseCache, init::ReplicaPool, metrics::Metrics, routes, subscribe},
+    util::{
+        error_printing::PrintableError,
+        logging::{LogFormat, JSON_ACCESS_LOG_FORMAT},
+    },
 };
 
 use super::{Command, Opts};
@@ -29,42 +32,97 @@ pub struct Server {
 
     #[clap(short, long)]
     watch: bool,
+
+    /// milliseconds to wait for file changes to settle before reloading in watch mode.
+    /// 100 to 2000 works for most editors, raise it on network filesystems
+    #[clap(long, default_value = "250")]
+    watch_debounce_ms: u64,
+
+    /// compile modules on first use and keep at most this many in memory.
+    /// edited files are compiled again when next used but new files need a restart
+    #[clap(long, conflicts_with = "watch")]
+    lazy_modules: Option<usize>,
 }
 
 impl Command for Server {
     fn run_command(&self, opt: &Opts) -> anyhow::Result<()> {
         let clone = self.clone();
-        actix_rt::System::new("server").block_on(run_server(opt.config.clone(), clone))?;
+        actix_rt::System::new("server").block_on(run_server(
+            opt.config.clone(),
+            opt.config_search(),
+            opt.log_format,
+            opt.strict,
+            clone,
+        ))?;
         Ok(())
     }
 }
 
-fn create_evaluator(directory: &str, extension: &str, watch: bool) -> anyhow::Result<Evaluator> {
-    if watch {
-        let importer = WatchingImporter::new(directory, extension)?;
-        Ok(Evaluator::with_importer(importer))
-    } else {
-        match UpfrontImporter::new(directory, extension) {
-            Err(errors) => {
-                let mut buffer = String::new();
-                for error in errors {
-                    error.print_error(&mut buffer)?;
-                    eprint!("{}\n", buffer);
-                    buffer.clear();
-                }
-                return Err(anyhow!("failed to import some sql files"));
+/// watches the directory for changes when given a debounce interval and compiles
+/// modules on demand when given a capacity
+fn create_evaluator(
+    directory: &str,
+    extension: &str,
+    watch_debounce: Option<Duration>,
+    lazy_capacity: Option<usize>,
+    strict: bool,
+) -> anyhow::Result<Evaluator> {
+    if let Some(debounce) = watch_debounce {
+        let importer = WatchingImporter::new(directory, extension, strict, debounce)?;
+        return Ok(Evaluator::with_importer(importer));
+    }
+    let importer = match lazy_capacity {
+        Some(capacity) => {
+            LazyImporter::new(directory, extension, strict, capacity).map(Evaluator::with_importer)
+        }
+        None => UpfrontImporter::new(directory, extension, strict).map(Evaluator::with_importer),
+    };
+    match importer {
+        Err(errors) => {
+            let mut buffer = String::new();
+            for error in errors {
+                error.print_error(&mut buffer)?;
+                eprint!("{}\n", buffer);
+                buffer.clear();
             }
-            Ok(importer) => Ok(Evaluator::with_importer(importer)),
+            Err(anyhow!("failed to import some sql files"))
         }
+        Ok(evaluator) => Ok(evaluator),
     }
 }
 
-pub async fn run_server(config_path: Option<PathBuf>, cmd: Server) -> anyhow::Result<()> {
+pub async fn run_server(
+    config_path: Option<PathBuf>,
+    config_search: bool,
+    log_format: LogFormat,
+    strict: bool,
+    cmd: Server,
+) -> anyhow::Result<()> {
     // import all files
-    let evaluator = create_evaluator(cmd.directory.as_str(), cmd.extension.as_str(), cmd.watch)?;
+    let evaluator = create_evaluator(
+        cmd.directory.as_str(),
+        cmd.extension.as_str(),
+        cmd.watch
+            .then(|| Duration::from_millis(cmd.watch_debounce_ms)),
+        cmd.lazy_modules,
+        strict,
+    )?;
 
-    let config = Config::read_config(config_path)?;
-    let pool = crate::server::init::connect_to_db(&config, None).await?;
+    let config = Config::read_config_or_print_error(config_path, config_search)?;
+    if config.server.debug_sql {
+        warn!("server.debug_sql is set, query responses include their generated sql, do not use it in production");
+    }
+    if config.server.log_bodies {
+        warn!("server.log_bodies is set, payloads and results are logged, do not use it in production");
+    }
+    let pool =
+        crate::server::init::connect_to_db_with_retry(&config, Some(cmd.max_connections)).await?;
+    let replica =
+        crate::server::init::connect_to_replica_with_retry(&config, Some(cmd.max_connections))
+            .await?
+            .map(|pool| web::Data::new(ReplicaPool(pool)));
+    let metrics = web::Data::new(Metrics::new(config.server.metrics_enabled));
+    let cache = web::Data::new(ResponseCache::new(config.server.cache_capacity));
     let config = Arc::new(config);
 
     for endpoint in evaluator.importer.get_all_endpoints()? {
@@ -74,15 +132,36 @@ pub async fn run_server(config_path: Option<PathBuf>, cmd: Server) -> anyhow::Re
     let listen_loc = format!("0.0.0.0:{}", cmd.port);
     info!("server listening on {}", listen_loc);
     HttpServer::new(move || {
-        App::new()
-            .wrap(middleware::Logger::default())
+        let logger = match log_format {
+            LogFormat::Text => middleware::Logger::default(),
+            LogFormat::Json => middleware::Logger::new(JSON_ACCESS_LOG_FORMAT),
+        };
+        let app = App::new();
+        // read only endpoints are sent to the replica when there is one
+        let app = match replica.as_ref() {
+            Some(replica) => app.app_data(replica.clone()),
+            None => app,
+        };
+        // compresses responses, compressed request bodies are decoded by the json extractor
+        app.wrap(logger)
             .wrap(middleware::Compress::default())
             .wrap(config.cors.cors())
             .data(config.clone())
             .data(pool.clone())
             .data(evaluator.clone())
+            .app_data(routes::json_config(config.server.max_body_bytes))
+            .app_data(metrics.clone())
+            .app_data(cache.clone())
             .route("/api/v1/auth", web::post().to(routes::auth_query))
             .route("/api/v1/query", web::post().to(routes::run_queries))
+            .route("/api/v1/stream", web::post().to(routes::stream_query))
+            .route(
+                "/api/v1/subscribe/{endpoint}",
+                web::get().to(subscribe::subscribe),
+            )
+            .route("/api/v1/endpoints", web::get().to(routes::endpoints))
+            .route("/metrics", web::get().to(routes::metrics))
+            .route("/version", web::get().to(routes::version))
     })
     .bind(listen_loc)?
     .run()
@@ -0,0 +1,111 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::mpsc::channel,
+    time::Duration,
+};
+
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+
+use crate::engine::{Importer, UpfrontImporter};
+
+/// calls `run` once and then again every time the module or one of its imports
+/// changes. a failed run is reported and the files are watched until they are fixed
+pub fn watch_module<F>(module: &Path, debounce: Duration, mut run: F) -> anyhow::Result<()>
+where
+    F: FnMut() -> anyhow::Result<()>,
+{
+    let module = module.canonicalize()?;
+    let mut files = BTreeSet::new();
+    files.insert(module.clone());
+    loop {
+        if let Err(err) = run() {
+            eprintln!("Error: {:?}", err);
+        }
+        // a module that fails to import keeps watching the files of its last import
+        if let Some(imported) = imported_files(module.as_path()) {
+            files = imported;
+        }
+        eprintln!("waiting for changes to {}", module.display());
+        wait_for_change(&files, debounce)?;
+    }
+}
+
+/// the module and the modules it imports, directly or through other imports. its
+/// directory is imported as well so imports by endpoint name are found
+fn imported_files(module: &Path) -> Option<BTreeSet<PathBuf>> {
+    let paths = super::module_paths(module).ok()?;
+    let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+    let importer = UpfrontImporter::from_paths(paths.as_slice(), false).ok()?;
+    let mut files = BTreeSet::new();
+    let mut pending = vec![module.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        if !files.insert(path.clone()) {
+            continue;
+        }
+        if let Ok(imported) = importer.get_module_from_location(path.as_path()) {
+            pending.extend(
+                imported
+                    .front_matter
+                    .imports
+                    .values()
+                    .map(|(location, _)| location.clone()),
+            );
+        }
+    }
+    Some(files)
+}
+
+/// blocks until one of the files is written, created, renamed or removed
+fn wait_for_change(files: &BTreeSet<PathBuf>, debounce: Duration) -> anyhow::Result<()> {
+    let (tx, rx) = channel();
+    let mut watcher = watcher(tx, debounce)?;
+    // editors often save by replacing the file, so the directories are watched instead
+    let directories: BTreeSet<&Path> = files.iter().filter_map(|path| path.parent()).collect();
+    for directory in directories {
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
+    }
+
+    loop {
+        let changed = match rx.recv()? {
+            DebouncedEvent::Error(err, _) => Err(err)?,
+            DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::Chmod(path)
+            | DebouncedEvent::Remove(path) => vec![path],
+            DebouncedEvent::Rename(from, to) => vec![from, to],
+            DebouncedEvent::NoticeWrite(_)
+            | DebouncedEvent::NoticeRemove(_)
+            | DebouncedEvent::Rescan => vec![],
+        };
+        if changed.iter().any(|path| files.contains(path)) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::temp_dir::temp_dir;
+
+    #[test]
+    fn wait_for_change_test() {
+        let directory = temp_dir("watch_test");
+        let module = directory.join("module.sql");
+        std::fs::write(&module, "select 1").unwrap();
+
+        let files: BTreeSet<PathBuf> = std::iter::once(module.clone()).collect();
+        let writer = std::thread::spawn(move || {
+            // unrelated files in the same directory are ignored
+            std::thread::sleep(Duration::from_millis(200));
+            std::fs::write(directory.join("other.sql"), "select 2").unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+            std::fs::write(&module, "select 3").unwrap();
+        });
+        let start = std::time::Instant::now();
+        wait_for_change(&files, Duration::from_millis(50)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        writer.join().unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 #[derive(Deserialize, Serialize)]
 pub struct AuthClaims<A> {
@@ -6,9 +7,17 @@ pub struct AuthClaims<A> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub iss: Option<String>,
 
+    /// audience
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+
     /// expiration date in seconds since epoch (utc)
     pub exp: u64,
 
+    /// token id used to revoke the token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<Uuid>,
+
     /// additional claims
     #[serde(flatten)]
     pub claims: A,
@@ -0,0 +1,99 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use uuid::Uuid;
+
+/// a store of revoked token ids. backends only need to remember an id until the
+/// token it belongs to expires
+pub trait DenylistBackend: Send + Sync + fmt::Debug {
+    /// revokes the token with id `jti` which expires at `exp` (seconds since epoch)
+    fn deny(&self, jti: Uuid, exp: u64) -> anyhow::Result<()>;
+    fn is_denied(&self, jti: &Uuid) -> anyhow::Result<bool>;
+}
+
+/// an in-process denylist. revocations are lost on restart and are not shared
+/// between multiple server instances
+#[derive(Debug, Default)]
+pub struct MemoryDenylist(Mutex<HashMap<Uuid, u64>>);
+
+impl DenylistBackend for MemoryDenylist {
+    fn deny(&self, jti: Uuid, exp: u64) -> anyhow::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut denied = self
+            .0
+            .lock()
+            .map_err(|_| anyhow!("denylist lock is poisoned"))?;
+        // expired tokens are rejected anyway so there is no need to keep them around
+        denied.retain(|_, exp| *exp > now);
+        denied.insert(jti, exp);
+        Ok(())
+    }
+
+    fn is_denied(&self, jti: &Uuid) -> anyhow::Result<bool> {
+        let denied = self
+            .0
+            .lock()
+            .map_err(|_| anyhow!("denylist lock is poisoned"))?;
+        Ok(denied.contains_key(jti))
+    }
+}
+
+/// shared handle to the denylist backend
+#[derive(Debug, Clone)]
+pub struct Denylist(Arc<dyn DenylistBackend>);
+
+impl Denylist {
+    #[allow(dead_code)]
+    pub fn with_backend<B: DenylistBackend + 'static>(backend: B) -> Self {
+        Self(Arc::new(backend))
+    }
+}
+
+impl std::ops::Deref for Denylist {
+    type Target = dyn DenylistBackend;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl Default for Denylist {
+    fn default() -> Self {
+        Self(Arc::new(MemoryDenylist::default()))
+    }
+}
+
+/// the denylist is runtime state rather than configuration so it never affects equality
+impl PartialEq for Denylist {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_denylist_test() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let denylist = Denylist::default();
+        let (expired, revoked) = (Uuid::new_v4(), Uuid::new_v4());
+
+        denylist.deny(expired, now - 10).unwrap();
+        assert!(denylist.is_denied(&expired).unwrap());
+
+        // expired entries are dropped on the next insertion
+        denylist.deny(revoked, now + 100).unwrap();
+        assert!(!denylist.is_denied(&expired).unwrap());
+        assert!(denylist.is_denied(&revoked).unwrap());
+        assert!(!denylist.is_denied(&Uuid::new_v4()).unwrap());
+    }
+}
@@ -1,8 +1,11 @@
 mod auth;
 mod config;
+mod denylist;
 mod env_value;
 mod secret;
 
 pub use auth::AuthClaims;
-pub use config::{Config, Cookie};
+pub(crate) use config::CONFIG_FILE_NAMES;
+pub use config::{Config, Cookie, Server};
+pub use denylist::Denylist;
 pub use secret::{Secret, SecretKey, SecretKind};
@@ -9,20 +9,51 @@ use std::{
 use crate::binding::Binding;
 use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use uuid::Uuid;
 
-use super::{env_value::EnvValue, AuthClaims};
+use super::{env_value::EnvValue, AuthClaims, Denylist};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Secret {
+    /// tokens are signed with this algorithm and tokens signed with any other are rejected
     pub algorithm: Algorithm,
     #[serde(flatten)]
     #[serde(with = "secret_kind_serde")]
     pub kind: SecretKind,
 
+    /// issuer set on and required of tokens. tokens are issued by justsql when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<EnvValue<String>>,
+
+    /// audience set on and required of tokens. the audience is not checked when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<EnvValue<String>>,
+
+    /// only re-issue verified tokens once they are in the last quarter of their lifetime
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sliding_refresh: bool,
+
+    /// set each claim as the transaction local setting `justsql.<claim>` so row level
+    /// security policies can read them with `current_setting`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub set_local_claims: bool,
+
     #[serde(skip)] // TODO store keys directly instead
     file_locs: BTreeMap<PathBuf, Vec<u8>>,
+
+    /// the directory of the config that relative key files are looked up in
+    #[serde(skip)]
+    directory: PathBuf,
+
+    /// ids of tokens revoked before their expiration
+    #[serde(skip)]
+    pub denylist: Denylist,
 }
 
+/// with sliding refresh tokens are re-issued once 1 / SLIDING_REFRESH_FRACTION of
+/// their lifetime remains
+const SLIDING_REFRESH_FRACTION: u64 = 4;
+
 fn get_val<'a, T: Clone + DeserializeOwned>(
     val: &'a EnvValue<T>,
     name: &str,
@@ -36,10 +67,19 @@ fn get_val<'a, T: Clone + DeserializeOwned>(
 impl Secret {
     pub fn encode<A: Serialize>(&self, claims: &A, exp: u64) -> anyhow::Result<String> {
         let token = jsonwebtoken::encode(
-            &jsonwebtoken::Header::default(),
+            &jsonwebtoken::Header::new(self.algorithm),
             &AuthClaims {
-                iss: Some("justsql".to_owned()),
+                iss: Some(match self.issuer.as_ref() {
+                    Some(issuer) => get_val(issuer, "issuer")?.into_owned(),
+                    None => "justsql".to_owned(),
+                }),
+                aud: self
+                    .audience
+                    .as_ref()
+                    .map(|audience| get_val(audience, "audience").map(Cow::into_owned))
+                    .transpose()?,
                 exp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + exp,
+                jti: Some(Uuid::new_v4()),
                 claims,
             },
             &self.encoding_key()?,
@@ -49,11 +89,62 @@ impl Secret {
 
     pub fn decode(&self, token: &str) -> anyhow::Result<AuthClaims<BTreeMap<String, Binding>>> {
         let decoding_key = self.decoding_key()?;
-        let data =
-            jsonwebtoken::decode(token, &decoding_key, &jsonwebtoken::Validation::default())?;
+        let mut validation = jsonwebtoken::Validation::new(self.algorithm);
+        if let Some(issuer) = self.issuer.as_ref() {
+            validation.iss = Some(get_val(issuer, "issuer")?.into_owned());
+        }
+        if let Some(audience) = self.audience.as_ref() {
+            validation.set_audience(&[get_val(audience, "audience")?.as_str()]);
+        }
+        let data = jsonwebtoken::decode::<AuthClaims<BTreeMap<String, Binding>>>(
+            token,
+            &decoding_key,
+            &validation,
+        )?;
+        if let Some(jti) = data.claims.jti.as_ref() {
+            if self.denylist.is_denied(jti)? {
+                return Err(anyhow!("token has been revoked"));
+            }
+        }
         Ok(data.claims)
     }
 
+    /// revokes a valid token so that it fails to decode from now on
+    pub fn revoke(&self, token: &str) -> anyhow::Result<()> {
+        let claims = self.decode(token)?;
+        let jti = claims
+            .jti
+            .ok_or_else(|| anyhow!("token does not have an id and cannot be revoked"))?;
+        self.denylist.deny(jti, claims.exp)
+    }
+
+    /// whether a verified token that expires at `exp` should be re-issued with a
+    /// fresh expiration `lifetime` seconds from now
+    pub fn should_refresh(&self, exp: u64, lifetime: u64) -> anyhow::Result<bool> {
+        if !self.sliding_refresh {
+            return Ok(true);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let remaining = exp.saturating_sub(now);
+        Ok(remaining.saturating_mul(SLIDING_REFRESH_FRACTION) <= lifetime)
+    }
+
+    /// key files given relative to the config are read from the config's directory
+    pub fn set_directory(&mut self, directory: &Path) {
+        self.directory = directory.to_path_buf();
+    }
+
+    /// the path a key file is read from. relative paths are looked up next to the
+    /// config first and in the current directory otherwise, as they were before
+    fn key_file(&self, path: &Path) -> PathBuf {
+        let next_to_config = self.directory.join(path);
+        if next_to_config.exists() {
+            next_to_config
+        } else {
+            path.to_path_buf()
+        }
+    }
+
     fn get_file_contents<'a>(&'a self, path: &Path) -> anyhow::Result<&'a [u8]> {
         let file_contents = self
             .file_locs
@@ -69,9 +160,7 @@ impl Secret {
                 SecretKey::FromFile(file) => {
                     let file_contents =
                         self.get_file_contents(get_val(file, "secret_key file name")?.as_path())?;
-                    let decoded = base64::decode(file_contents);
-                    let contents = decoded.as_ref().map_or(file_contents, |val| val.as_slice());
-                    Ok(EncodingKey::from_secret(contents))
+                    Ok(EncodingKey::from_secret(file_contents))
                 }
                 SecretKey::Base64(val) => {
                     let val = get_val(val, "base64 value")?;
@@ -118,9 +207,7 @@ impl Secret {
                 SecretKey::FromFile(file) => {
                     let file_contents =
                         self.get_file_contents(get_val(file, "secret_key file name")?.as_path())?;
-                    let decoded = base64::decode(file_contents);
-                    let contents = decoded.as_ref().map_or(file_contents, |val| val.as_slice());
-                    Ok(DecodingKey::from_secret(contents).into_static())
+                    Ok(DecodingKey::from_secret(file_contents).into_static())
                 }
                 SecretKey::Base64(val) => {
                     let val = get_val(val, "base64 value")?;
@@ -158,25 +245,53 @@ impl Secret {
         }
     }
 
-    pub fn post_process(&mut self) -> anyhow::Result<()> {
+    /// every problem with the secret that post processing would run into
+    pub fn problems(&self) -> Vec<anyhow::Error> {
+        let mut problems = vec![];
         if self.is_symmetric_algorithm() != matches!(self.kind, SecretKind::Symmetric { .. }) {
-            Err(anyhow!(
-                "algorithm requires symmetric secret but was given assymetric key(s), either change the algorithm
-                to HS512, HS384, or HS256 or use put your key in secret_key_base64"
-            ))?
+            problems.push(anyhow!(
+                "auth.algorithm does not match the kind of key. symmetric keys require HS512, HS384, or HS256 and other algorithms require an encoding and decoding key"
+            ));
         }
-        if matches!(
-            self.kind,
-            SecretKind::Symmetric {
-                secret: SecretKey::FromFile(_)
+        for secret in self.secret_keys() {
+            match secret {
+                SecretKey::FromFile(file) => match file.value() {
+                    Some(path) => match std::fs::read(self.key_file(path.as_path())) {
+                        Ok(contents) => {
+                            let is_symmetric = matches!(self.kind, SecretKind::Symmetric { .. });
+                            if is_symmetric
+                                && base64::decode(String::from_utf8_lossy(&contents).trim())
+                                    .is_err()
+                            {
+                                problems.push(anyhow!(
+                                    "secret_key_from_file at {:?} must contain a base64 encoded key",
+                                    path.as_os_str()
+                                ));
+                            }
+                        }
+                        Err(err) => problems.push(anyhow!(
+                            "could not read key file at {:?}: {}",
+                            path.as_os_str(),
+                            err
+                        )),
+                    },
+                    None => problems.push(anyhow!("could not get key file name")),
+                },
+                SecretKey::Base64(val) => match val.value() {
+                    Some(val) => {
+                        if base64::decode(val.as_str()).is_err() {
+                            problems.push(anyhow!("auth key is not valid base64"));
+                        }
+                    }
+                    None => problems.push(anyhow!("could not get base64 key value")),
+                },
             }
-        ) {
-            Err(anyhow!(
-                "cannot pull secret_key from file pass it through secret_key_base64"
-            ))?
         }
+        problems
+    }
 
-        let secrets: Vec<&SecretKey> = match &self.kind {
+    fn secret_keys(&self) -> Vec<&SecretKey> {
+        match &self.kind {
             SecretKind::Symmetric { secret } => vec![secret],
             SecretKind::Assymmetric {
                 encoding: Some(encoding),
@@ -186,9 +301,18 @@ impl Secret {
                 encoding: None,
                 decoding,
             } => vec![decoding],
-        };
+        }
+    }
 
-        let file_locs: std::io::Result<BTreeMap<PathBuf, Vec<u8>>> = secrets
+    pub fn post_process(&mut self) -> anyhow::Result<()> {
+        if self.is_symmetric_algorithm() != matches!(self.kind, SecretKind::Symmetric { .. }) {
+            Err(anyhow!(
+                "algorithm requires symmetric secret but was given assymetric key(s), either change the algorithm
+                to HS512, HS384, or HS256 or use put your key in secret_key_base64"
+            ))?
+        }
+        let file_locs: std::io::Result<BTreeMap<PathBuf, Vec<u8>>> = self
+            .secret_keys()
             .into_iter()
             .filter_map(|secret: &SecretKey| match secret {
                 SecretKey::FromFile(from_file) => from_file.value(),
@@ -196,13 +320,33 @@ impl Secret {
             })
             .map(|path| {
                 let mut vec = vec![];
-                let mut file = std::fs::File::open(path.as_path())?;
+                let mut file = std::fs::File::open(self.key_file(path.as_path()))?;
                 file.read_to_end(&mut vec)?;
                 Ok((path.into_owned(), vec))
             })
             .collect();
 
         self.file_locs = file_locs?;
+
+        // symmetric keys are stored base64 encoded just like secret_key_base64
+        if let SecretKind::Symmetric {
+            secret: SecretKey::FromFile(file),
+        } = &self.kind
+        {
+            let path = get_val(file, "secret_key file name")?;
+            let contents = self
+                .file_locs
+                .get_mut(path.as_path())
+                .ok_or_else(|| anyhow!("could not find file at {:?}", path.as_os_str()))?;
+            let decoded =
+                base64::decode(String::from_utf8_lossy(contents).trim()).map_err(|_| {
+                    anyhow!(
+                        "secret_key_from_file at {:?} must contain a base64 encoded key",
+                        path.as_os_str()
+                    )
+                })?;
+            *contents = decoded;
+        }
         Ok(())
     }
 
@@ -346,6 +490,7 @@ mod secret_kind_serde {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::util::temp_dir::temp_dir;
 
     #[test]
     fn secret_serde_test() {
@@ -354,7 +499,13 @@ mod tests {
             kind: SecretKind::Symmetric {
                 secret: SecretKey::Base64(EnvValue::Value("testing".to_string())),
             },
+            issuer: None,
+            audience: None,
+            sliding_refresh: false,
+            set_local_claims: false,
             file_locs: Default::default(),
+            directory: Default::default(),
+            denylist: Default::default(),
         };
 
         let data = serde_json::to_string(&secret).unwrap();
@@ -366,4 +517,123 @@ mod tests {
         let reverse = serde_json::from_str(data.as_str()).unwrap();
         assert_eq!(&secret, &reverse);
     }
+
+    #[test]
+    fn sliding_refresh_test() {
+        let mut secret: Secret =
+            serde_json::from_str(r#"{"algorithm":"HS256","secret_key_base64":"testing"}"#).unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        // without sliding refresh tokens are always re-issued
+        assert!(secret.should_refresh(now + 100, 100).unwrap());
+
+        secret.sliding_refresh = true;
+        assert!(!secret.should_refresh(now + 100, 100).unwrap());
+        assert!(!secret.should_refresh(now + 30, 100).unwrap());
+        assert!(secret.should_refresh(now + 20, 100).unwrap());
+        assert!(secret.should_refresh(now.saturating_sub(10), 100).unwrap());
+
+        let data = r#"{"algorithm":"HS256","secret_key_base64":"testing","sliding_refresh":true}"#;
+        let reverse: Secret = serde_json::from_str(data).unwrap();
+        assert!(reverse.sliding_refresh);
+        assert_eq!(serde_json::to_string(&reverse).unwrap(), data);
+    }
+
+    #[test]
+    fn revoke_test() {
+        let secret: Secret =
+            serde_json::from_str(r#"{"algorithm":"HS256","secret_key_base64":"dGVzdGluZw=="}"#)
+                .unwrap();
+        let claims = serde_json::json!({"id": 1});
+        let (token, other) = (
+            secret.encode(&claims, 100).unwrap(),
+            secret.encode(&claims, 100).unwrap(),
+        );
+
+        assert!(secret.decode(token.as_str()).unwrap().jti.is_some());
+        secret.revoke(token.as_str()).unwrap();
+        assert!(secret.decode(token.as_str()).is_err());
+        assert!(secret.decode(other.as_str()).is_ok());
+    }
+
+    #[test]
+    fn issuer_audience_test() {
+        let default: Secret =
+            serde_json::from_str(r#"{"algorithm":"HS256","secret_key_base64":"dGVzdGluZw=="}"#)
+                .unwrap();
+        let service_a: Secret = serde_json::from_str(
+            r#"{"algorithm":"HS256","secret_key_base64":"dGVzdGluZw==","issuer":"auth","audience":"a"}"#,
+        )
+        .unwrap();
+        let service_b: Secret = serde_json::from_str(
+            r#"{"algorithm":"HS256","secret_key_base64":"dGVzdGluZw==","issuer":"auth","audience":"b"}"#,
+        )
+        .unwrap();
+        let claims = serde_json::json!({"id": 1});
+
+        let token = default.encode(&claims, 100).unwrap();
+        assert_eq!(
+            default.decode(token.as_str()).unwrap().iss.as_deref(),
+            Some("justsql")
+        );
+        assert!(service_a.decode(token.as_str()).is_err());
+
+        let token = service_a.encode(&claims, 100).unwrap();
+        let decoded = service_a.decode(token.as_str()).unwrap();
+        assert_eq!(decoded.iss.as_deref(), Some("auth"));
+        assert_eq!(decoded.aud.as_deref(), Some("a"));
+        assert!(service_b.decode(token.as_str()).is_err());
+    }
+
+    #[test]
+    fn algorithm_test() {
+        let hs256: Secret =
+            serde_json::from_str(r#"{"algorithm":"HS256","secret_key_base64":"dGVzdGluZw=="}"#)
+                .unwrap();
+        let hs512: Secret =
+            serde_json::from_str(r#"{"algorithm":"HS512","secret_key_base64":"dGVzdGluZw=="}"#)
+                .unwrap();
+        let claims = serde_json::json!({"id": 1});
+
+        // tokens are signed with the configured algorithm and only that algorithm verifies
+        let token = hs512.encode(&claims, 100).unwrap();
+        let header = jsonwebtoken::decode_header(token.as_str()).unwrap();
+        assert_eq!(header.alg, Algorithm::HS512);
+        assert!(hs512.decode(token.as_str()).is_ok());
+        assert!(hs256.decode(token.as_str()).is_err());
+
+        let token = hs256.encode(&claims, 100).unwrap();
+        assert!(hs512.decode(token.as_str()).is_err());
+    }
+
+    #[test]
+    fn secret_key_from_file_test() {
+        let path = temp_dir("secret_key_from_file_test").join("secret_key");
+        std::fs::write(&path, "dGVzdGluZw==\n").unwrap();
+
+        let config = format!(
+            r#"{{"algorithm":"HS256","secret_key_from_file":{:?}}}"#,
+            path.to_str().unwrap()
+        );
+        let mut from_file: Secret = serde_json::from_str(config.as_str()).unwrap();
+        from_file.post_process().unwrap();
+        let mut inline: Secret =
+            serde_json::from_str(r#"{"algorithm":"HS256","secret_key_base64":"dGVzdGluZw=="}"#)
+                .unwrap();
+        inline.post_process().unwrap();
+
+        // both hold the same key so their tokens are interchangeable
+        let token = from_file
+            .encode(&serde_json::json!({"id": 1}), 100)
+            .unwrap();
+        assert!(inline.decode(token.as_str()).is_ok());
+
+        std::fs::write(&path, "not base64!").unwrap();
+        let mut invalid: Secret = serde_json::from_str(config.as_str()).unwrap();
+        assert!(invalid.post_process().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
 }
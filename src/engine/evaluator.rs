@@ -1,12 +1,42 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 
-use crate::{codegen::Module, query};
+use crate::{binding::Binding, codegen::Module, query};
 
-use super::importer::Importer;
+use super::importer::{modified, Importer};
 
 #[derive(Debug, Clone)]
 pub struct Evaluator {
     pub(crate) importer: Arc<dyn Importer>,
+    /// the claims of importers without a statement cache, see `auth_claims`
+    collected_claims: Arc<Mutex<Option<CollectedClaims>>>,
+}
+
+/// the claims read by the modules along with what they were collected from
+#[derive(Debug)]
+struct CollectedClaims {
+    endpoints: Vec<String>,
+    /// the modification times of every module's file when the claims were collected
+    modified: Vec<(PathBuf, Option<SystemTime>)>,
+    claims: Arc<BTreeSet<String>>,
+    /// whether every module could be imported
+    complete: bool,
+}
+
+impl CollectedClaims {
+    fn is_fresh(&self, endpoints: &[String]) -> bool {
+        self.complete
+            && self.endpoints == endpoints
+            && self
+                .modified
+                .iter()
+                .all(|(location, time)| modified(location.as_path()) == *time)
+    }
 }
 
 impl Evaluator {
@@ -16,6 +46,7 @@ impl Evaluator {
     {
         Self {
             importer: Arc::new(importer),
+            collected_claims: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -24,13 +55,162 @@ impl Evaluator {
         Ok(module)
     }
 
-    pub fn evaluate_endpoint<'a, 'b: 'a, A>(
+    /// every claim read through `@auth.claim` by the endpoints and the modules they import.
+    /// importers with a statement cache only collect them again after their modules change,
+    /// the others once a module's file changes. modules that fail to import are skipped
+    /// since they can not be run, and the claims are collected again until they import
+    pub fn auth_claims(&self) -> anyhow::Result<Arc<BTreeSet<String>>> {
+        let endpoints = self.importer.get_all_endpoints()?;
+        if let Some(cache) = self.importer.statement_cache() {
+            return cache.auth_claims(|| Ok(self.collect_auth_claims(endpoints)?.claims));
+        }
+        let mut collected = self
+            .collected_claims
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        match collected.as_ref() {
+            Some(collected) if collected.is_fresh(endpoints.as_slice()) => {
+                Ok(collected.claims.clone())
+            }
+            _ => {
+                let claims = self.collect_auth_claims(endpoints)?;
+                Ok(collected.insert(claims).claims.clone())
+            }
+        }
+    }
+
+    fn collect_auth_claims(&self, endpoints: Vec<String>) -> anyhow::Result<CollectedClaims> {
+        let mut claims = BTreeSet::new();
+        let mut visited: BTreeSet<PathBuf> = BTreeSet::new();
+        let mut complete = true;
+        let mut modules = vec![];
+        for endpoint in endpoints.iter() {
+            match self.importer.get_module_from_endpoint(endpoint) {
+                Ok(module) => modules.push(module),
+                Err(err) => {
+                    warn!("skipping the claims of endpoint {}: {:#}", endpoint, err);
+                    complete = false;
+                }
+            }
+        }
+        while let Some(module) = modules.pop() {
+            if !visited.insert(module.front_matter.location.clone()) {
+                continue;
+            }
+            claims.extend(module.auth_params().into_iter().map(String::from));
+            for (path, _) in module.front_matter.imports.values() {
+                match self.importer.get_module_from_location(path) {
+                    Ok(module) => modules.push(module),
+                    Err(err) => {
+                        warn!("skipping the claims of {}: {:#}", path.display(), err);
+                        complete = false;
+                    }
+                }
+            }
+        }
+        let modified = visited
+            .into_iter()
+            .map(|location| {
+                let time = modified(location.as_path());
+                (location, time)
+            })
+            .collect();
+        Ok(CollectedClaims {
+            endpoints,
+            modified,
+            claims: Arc::new(claims),
+            complete,
+        })
+    }
+
+    pub fn evaluate_endpoint<'a, 'b: 'a>(
         &'b self,
         endpoint: &str,
-        bindings: &'a BTreeMap<String, A>,
-        auth_bindings: Option<&'a BTreeMap<String, A>>,
-    ) -> anyhow::Result<Vec<(String, Vec<&'a A>)>> {
+        bindings: &'a BTreeMap<String, Binding>,
+        auth_bindings: Option<&'a BTreeMap<String, Binding>>,
+        max_import_depth: usize,
+        assume_null_if_missing: bool,
+    ) -> anyhow::Result<Vec<(String, Vec<Cow<'a, Binding>>)>> {
         let module = self.importer.get_module_from_endpoint(endpoint)?;
-        query::evaluate(&module, &self.importer, bindings, auth_bindings)
+        query::evaluate(
+            &module,
+            &self.importer,
+            bindings,
+            auth_bindings,
+            max_import_depth,
+            assume_null_if_missing,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{LazyImporter, UpfrontImporter};
+    use crate::util::temp_dir::temp_dir;
+
+    #[test]
+    fn auth_claims_test() {
+        let directory = temp_dir("auth_claims_test");
+        let refresh = directory.join("refresh.sql");
+        let team = directory.join("team.sql");
+        std::fs::write(
+            &team,
+            "-- @auth verify\nselect * from teams where org = @auth.org",
+        )
+        .unwrap();
+        std::fs::write(
+            &refresh,
+            "-- @endpoint refresh\n-- @auth verify 1d\n-- @import team from './team.sql'\nselect @auth.id as id from @team()",
+        )
+        .unwrap();
+        let importer = UpfrontImporter::from_paths(&[refresh.as_path()], false).unwrap();
+        let evaluator = Evaluator::with_importer(importer);
+
+        // the claims of imported modules are read as well
+        let claims: Vec<String> = evaluator.auth_claims().unwrap().iter().cloned().collect();
+        assert_eq!(claims, vec!["id", "org"]);
+    }
+
+    #[test]
+    fn lazy_auth_claims_test() {
+        let directory = temp_dir("lazy_auth_claims_test");
+        let me = directory.join("me.sql");
+        std::fs::write(
+            &me,
+            "-- @endpoint me\n-- @auth verify\nselect @auth.id as id",
+        )
+        .unwrap();
+        let broken = directory.join("broken.sql");
+        std::fs::write(
+            &broken,
+            "-- @endpoint broken\n-- @import missing from './missing.sql'\nselect 1",
+        )
+        .unwrap();
+        let importer = LazyImporter::new(directory.to_str().unwrap(), "sql", false, 8).unwrap();
+        let evaluator = Evaluator::with_importer(importer);
+
+        // a module that fails to import does not fail every refresh
+        let claims = evaluator.auth_claims().unwrap();
+        assert_eq!(claims.iter().collect::<Vec<_>>(), vec!["id"]);
+
+        // the claims are kept once every module imports and until a file changes
+        std::fs::write(&broken, "-- @endpoint broken\nselect 1").unwrap();
+        let claims = evaluator.auth_claims().unwrap();
+        assert!(Arc::ptr_eq(&claims, &evaluator.auth_claims().unwrap()));
+
+        std::fs::write(
+            &me,
+            "-- @endpoint me\n-- @auth verify\nselect @auth.id as id, @auth.org as org",
+        )
+        .unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&me)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let claims = evaluator.auth_claims().unwrap();
+        assert_eq!(claims.iter().collect::<Vec<_>>(), vec!["id", "org"]);
     }
 }
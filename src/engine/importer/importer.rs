@@ -1,11 +1,16 @@
 use std::{fmt, path::Path, sync::Arc};
 
-use crate::codegen::Module;
+use crate::{codegen::Module, query::StatementCache};
 
 pub trait Importer: Send + Sync + 'static + fmt::Debug {
     fn get_module_from_endpoint(&self, endpoint: &str) -> anyhow::Result<Arc<Module>>;
     fn get_module_from_location(&self, location: &Path) -> anyhow::Result<Arc<Module>>;
     fn get_all_endpoints(&self) -> anyhow::Result<Vec<String>>;
+
+    /// where statements built from this importer's modules are kept
+    fn statement_cache(&self) -> Option<&StatementCache> {
+        None
+    }
 }
 
 impl Importer for Arc<dyn Importer> {
@@ -20,4 +25,8 @@ impl Importer for Arc<dyn Importer> {
     fn get_all_endpoints(&self) -> anyhow::Result<Vec<String>> {
         self.as_ref().get_all_endpoints()
     }
+
+    fn statement_cache(&self) -> Option<&StatementCache> {
+        self.as_ref().statement_cache()
+    }
 }
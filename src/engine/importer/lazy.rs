@@ -0,0 +1,368 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use hashlink::LruCache;
+
+use crate::{
+    codegen::{Module, ModuleError},
+    util::error_printing::{without_color, PrintError, PrintableError},
+};
+
+use super::{
+    importer::Importer,
+    module_collection::{ModuleCollection, ModuleCollectionError},
+};
+
+/// compiles modules on demand and only keeps the most recently used ones in memory.
+///
+/// every lookup compares the modification time of the module's file and of the files
+/// it imports against the ones seen when it was compiled, so an edited file is
+/// compiled again the next time it is used. the endpoints are indexed once on start
+/// and only updated when a module is compiled again, new files need a restart.
+#[derive(Clone)]
+pub struct LazyImporter(Arc<LazyInternals>);
+
+struct LazyInternals {
+    endpoints: Mutex<BTreeMap<String, PathBuf>>,
+    modules: Mutex<LruCache<PathBuf, LazyEntry>>,
+    /// whether warnings stop a module from compiling
+    strict: bool,
+}
+
+struct LazyEntry {
+    module: Arc<Module>,
+    /// the modification times of the module and its imports when it was compiled
+    modified: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+/// the modification time of a file, if it can be read
+pub(crate) fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl LazyEntry {
+    fn new(module: Arc<Module>) -> Self {
+        let modified = std::iter::once(module.front_matter.location.clone())
+            .chain(
+                module
+                    .front_matter
+                    .imports
+                    .values()
+                    .map(|(location, _)| location.clone()),
+            )
+            .map(|location| {
+                let time = modified(location.as_path());
+                (location, time)
+            })
+            .collect();
+        LazyEntry { module, modified }
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.modified
+            .iter()
+            .all(|(location, time)| modified(location.as_path()) == *time)
+    }
+}
+
+impl fmt::Debug for LazyImporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LazyImporter")
+            .field("endpoints", &self.0.endpoints)
+            .field("compiled", &self.0.modules().len())
+            .finish()
+    }
+}
+
+impl LazyInternals {
+    fn modules(&self) -> MutexGuard<'_, LruCache<PathBuf, LazyEntry>> {
+        self.modules.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn endpoints(&self) -> MutexGuard<'_, BTreeMap<String, PathBuf>> {
+        self.endpoints.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn get(&self, location: &Path) -> anyhow::Result<Arc<Module>> {
+        match self.modules().get(location) {
+            Some(entry) if entry.is_fresh() => return Ok(entry.module.clone()),
+            Some(_) => debug!("compiling changed module {}", location.to_string_lossy()),
+            None => debug!("compiling module {}", location.to_string_lossy()),
+        }
+
+        // the lock is not held while compiling so other modules can be looked up in the
+        // meantime. requests that compile the same module at once each insert their own.
+        // the imports are compiled along with the module, which refreshes them too
+        let (uncompiled, compiled) = self.endpoint_imports(location);
+        let paths: Vec<&Path> = std::iter::once(location)
+            .chain(uncompiled.iter().map(PathBuf::as_path))
+            .collect();
+        let deps: BTreeMap<&Path, Arc<Module>> = compiled
+            .iter()
+            .map(|(path, module)| (path.as_path(), module.clone()))
+            .collect();
+        let (compiled, errors) = Module::from_paths(&paths, Some(&deps));
+        let (warnings, errors): (Vec<_>, Vec<_>) = if self.strict {
+            (vec![], errors)
+        } else {
+            errors.into_iter().partition(ModuleError::is_warning)
+        };
+        let mut buf = String::new();
+        for warning in warnings {
+            if warning.print_error(&mut buf).is_ok() {
+                warn!("\n{}", buf);
+            }
+            buf.clear();
+        }
+        if !errors.is_empty() {
+            // the error is sent back in the response so it can not hold terminal colors
+            without_color(|| {
+                errors.iter().try_for_each(|error| {
+                    error.print_error(&mut buf)?;
+                    buf.push('\n');
+                    Ok::<_, PrintError>(())
+                })
+            })?;
+            return Err(anyhow!("could not import module:\n\n{}", buf));
+        }
+
+        let mut compiled: BTreeMap<PathBuf, Arc<Module>> = compiled
+            .into_iter()
+            .map(|(path, module)| (path, Arc::new(module)))
+            .collect();
+        let module = compiled
+            .remove(location)
+            .ok_or_else(|| anyhow!("module does not exist"))?;
+        // the module goes in last so its imports can not evict it
+        let mut modules = self.modules();
+        for (path, module) in compiled
+            .into_iter()
+            .chain(std::iter::once((location.to_path_buf(), module.clone())))
+        {
+            for endpoint in module.front_matter.endpoints.iter() {
+                self.endpoints().insert(endpoint.clone(), path.clone());
+            }
+            modules.insert(path, LazyEntry::new(module));
+        }
+        Ok(module)
+    }
+}
+
+impl LazyInternals {
+    /// the modules imported with `endpoint:` by the module or the files it imports,
+    /// which `Module::from_paths` can not find on its own. fresh compiled modules are
+    /// returned as they are and the others need to be compiled along with the module
+    fn endpoint_imports(&self, location: &Path) -> (Vec<PathBuf>, BTreeMap<PathBuf, Arc<Module>>) {
+        let mut uncompiled = vec![];
+        let mut compiled = BTreeMap::new();
+        let mut visited = BTreeSet::new();
+        let mut paths = vec![location.to_path_buf()];
+        while let Some(path) = paths.pop() {
+            if !visited.insert(path.clone()) {
+                continue;
+            }
+            // files that can not be read are reported when the module is compiled
+            let (files, endpoints) = match Module::read_imports(path.as_path()) {
+                Ok(imports) => imports,
+                Err(_) => continue,
+            };
+            paths.extend(files);
+            for endpoint in endpoints {
+                let dep = match self.endpoints().get(&endpoint) {
+                    Some(dep) if dep != location => dep.clone(),
+                    _ => continue,
+                };
+                match self.modules().get(&dep) {
+                    Some(entry) if entry.is_fresh() => {
+                        compiled.insert(dep, entry.module.clone());
+                    }
+                    _ => {
+                        if !uncompiled.contains(&dep) {
+                            uncompiled.push(dep.clone());
+                        }
+                        paths.push(dep);
+                    }
+                }
+            }
+        }
+        (uncompiled, compiled)
+    }
+}
+
+impl Importer for LazyImporter {
+    fn get_module_from_endpoint(&self, endpoint: &str) -> anyhow::Result<Arc<Module>> {
+        let location = self
+            .0
+            .endpoints()
+            .get(endpoint)
+            .cloned()
+            .ok_or_else(|| anyhow!("module does not exist"))?;
+        let module = self.0.get(location.as_path())?;
+        if !module
+            .front_matter
+            .endpoints
+            .iter()
+            .any(|name| name == endpoint)
+        {
+            // the file was changed to no longer declare the endpoint
+            let mut endpoints = self.0.endpoints();
+            if endpoints.get(endpoint) == Some(&location) {
+                endpoints.remove(endpoint);
+            }
+            return Err(anyhow!("module does not exist"));
+        }
+        Ok(module)
+    }
+
+    fn get_module_from_location(&self, location: &Path) -> anyhow::Result<Arc<Module>> {
+        self.0.get(location)
+    }
+
+    fn get_all_endpoints(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.0.endpoints().keys().cloned().collect())
+    }
+}
+
+impl LazyImporter {
+    /// indexes the endpoints of every file in the directory without compiling them.
+    /// at most `capacity` compiled modules are kept in memory
+    pub fn new(
+        directory: &str,
+        extension: &str,
+        strict: bool,
+        capacity: usize,
+    ) -> Result<Self, Vec<ModuleCollectionError>> {
+        let (paths, mut errors) = ModuleCollection::find_files(directory, extension, false);
+        let mut endpoints = BTreeMap::new();
+        for path in paths {
+            let declared = match Module::read_endpoints(path.as_path()) {
+                Ok(declared) => declared,
+                Err(err) => {
+                    errors.push(err.into());
+                    continue;
+                }
+            };
+            for endpoint in declared {
+                match endpoints.entry(endpoint) {
+                    Entry::Occupied(entry) => {
+                        errors.push(ModuleCollectionError::AlreadyUsedEndpointError(
+                            path.clone(),
+                            entry.key().clone(),
+                        ))
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(path.clone());
+                    }
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        info!(
+            "indexed {} endpoints, modules are compiled on first use",
+            endpoints.len()
+        );
+        Ok(Self(Arc::new(LazyInternals {
+            endpoints: Mutex::new(endpoints),
+            modules: Mutex::new(LruCache::new(capacity)),
+            strict,
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::temp_dir::temp_dir;
+
+    #[test]
+    fn lazy_importer_test() {
+        let directory = temp_dir("lazy_importer_test");
+        let users = directory.join("users.sql");
+        std::fs::write(&users, "select * from users").unwrap();
+        std::fs::write(
+            directory.join("user_count.sql"),
+            "-- @endpoint userCount\n-- @import users from './users.sql'\nselect count(*) from @users()",
+        )
+        .unwrap();
+
+        let importer = LazyImporter::new(directory.to_str().unwrap(), "sql", false, 1).unwrap();
+        assert_eq!(importer.get_all_endpoints().unwrap(), vec!["userCount"]);
+        assert_eq!(importer.0.modules().len(), 0);
+
+        let module = importer.get_module_from_endpoint("userCount").unwrap();
+        assert_eq!(module.front_matter.imports.len(), 1);
+        // only the most recently compiled module is kept
+        assert_eq!(importer.0.modules().len(), 1);
+        assert!(Arc::ptr_eq(
+            &module,
+            &importer.get_module_from_endpoint("userCount").unwrap()
+        ));
+
+        // editing a module compiles it again on its next use
+        let users = users.canonicalize().unwrap();
+        importer.get_module_from_location(users.as_path()).unwrap();
+        std::fs::write(&users, "-- @param id\nselect * from users where id = @id").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&users)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let users_module = importer.get_module_from_location(users.as_path()).unwrap();
+        assert_eq!(users_module.front_matter.params, vec!["id"]);
+    }
+
+    #[test]
+    fn endpoint_import_test() {
+        let directory = temp_dir("lazy_importer_endpoint_import_test");
+        std::fs::write(
+            directory.join("users.sql"),
+            "-- @endpoint allUsers\nselect * from users",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("user_count.sql"),
+            "-- @endpoint userCount\n-- @import users from endpoint:allUsers\nselect count(*) from @users()",
+        )
+        .unwrap();
+
+        // the imported endpoint is compiled along with the module
+        let importer = LazyImporter::new(directory.to_str().unwrap(), "sql", false, 2).unwrap();
+        let module = importer.get_module_from_endpoint("userCount").unwrap();
+        assert_eq!(module.front_matter.imports.len(), 1);
+        assert_eq!(importer.0.modules().len(), 2);
+
+        // and is passed in as it is once it was compiled
+        let importer = LazyImporter::new(directory.to_str().unwrap(), "sql", false, 2).unwrap();
+        let users = importer.get_module_from_endpoint("allUsers").unwrap();
+        importer.get_module_from_endpoint("userCount").unwrap();
+        assert!(Arc::ptr_eq(
+            &users,
+            &importer.get_module_from_endpoint("allUsers").unwrap()
+        ));
+    }
+
+    #[test]
+    fn strict_test() {
+        let directory = temp_dir("lazy_importer_strict_test");
+        std::fs::write(
+            directory.join("unused.sql"),
+            "-- @endpoint unused\n-- @param id\nselect 1",
+        )
+        .unwrap();
+
+        // the unused parameter is only logged unless warnings are errors
+        let importer = LazyImporter::new(directory.to_str().unwrap(), "sql", false, 1).unwrap();
+        assert!(importer.get_module_from_endpoint("unused").is_ok());
+        let importer = LazyImporter::new(directory.to_str().unwrap(), "sql", true, 1).unwrap();
+        assert!(importer.get_module_from_endpoint("unused").is_err());
+    }
+}
@@ -1,9 +1,12 @@
 mod file_type;
 mod importer;
+mod lazy;
 pub mod module_collection;
 mod upfront;
 mod watching;
 
 pub use importer::Importer;
+pub(crate) use lazy::modified;
+pub use lazy::LazyImporter;
 pub use upfront::UpfrontImporter;
 pub use watching::WatchingImporter;
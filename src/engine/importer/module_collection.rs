@@ -1,5 +1,6 @@
 use crate::{
     codegen::{Module, ModuleError},
+    query::StatementCache,
     util::{
         error_printing::{print_unpositioned_error, PrintableError},
         path::path_relative_to_current_dir,
@@ -28,12 +29,45 @@ pub enum ModuleCollectionError {
     AlreadyUsedEndpointError(PathBuf, String),
     #[error("{0}")]
     ModuleError(#[from] ModuleError),
+    #[error("warning: module has no @endpoint and is not imported by any endpoint so it can not be reached")]
+    UnreachableModule(PathBuf),
+}
+
+impl ModuleCollectionError {
+    pub fn is_warning(&self) -> bool {
+        match self {
+            ModuleCollectionError::ModuleError(err) => err.is_warning(),
+            ModuleCollectionError::UnreachableModule(_) => true,
+            _ => false,
+        }
+    }
+
+    /// logs the warnings and returns the remaining errors. strict keeps warnings as errors
+    pub fn report_warnings(errors: Vec<Self>, strict: bool) -> Vec<Self> {
+        if strict {
+            return errors;
+        }
+        let (warnings, errors): (Vec<_>, Vec<_>) = errors
+            .into_iter()
+            .partition(ModuleCollectionError::is_warning);
+        let mut buf = String::new();
+        for warning in warnings {
+            match warning.print_error(&mut buf) {
+                Ok(_) => warn!("\n{}", buf),
+                Err(err) => warn!("display error when reporting warnings: {}", err),
+            }
+            buf.clear();
+        }
+        errors
+    }
 }
 
 #[derive(Default, Clone, Debug)]
 pub struct ModuleCollection {
     pub endpoints: im::OrdMap<String, Arc<Module>>,
     pub locations: im::OrdMap<PathBuf, Arc<Module>>,
+    /// the sql built from these modules, cleared on every change
+    pub statements: StatementCache,
 }
 
 impl PrintableError for ModuleCollectionError {
@@ -45,6 +79,7 @@ impl PrintableError for ModuleCollectionError {
             ModuleCollectionError::IOError(path, _)
             | ModuleCollectionError::NotAbsolutePath(path)
             | ModuleCollectionError::AlreadyUsedEndpointError(path, _)
+            | ModuleCollectionError::UnreachableModule(path)
             | ModuleCollectionError::ModuleNotFound(path) => {
                 // FIXME change relative pathing to current dir
                 let path = path_relative_to_current_dir(path.as_path());
@@ -76,14 +111,13 @@ impl ModuleCollection {
         (collection, errors)
     }
 
-    pub fn from_directory(
+    /// every file with the extension in the directory, canonicalized and in sorted order
+    pub fn find_files(
         directory: &str,
         extension: &str,
         follow_links: bool,
-    ) -> (Self, Vec<ModuleCollectionError>) {
+    ) -> (Vec<PathBuf>, Vec<ModuleCollectionError>) {
         let mut errors: Vec<ModuleCollectionError> = vec![];
-        let mut collection: ModuleCollection = Default::default();
-
         let path_bufs = walkdir::WalkDir::new(directory)
             .follow_links(follow_links)
             .into_iter()
@@ -111,6 +145,17 @@ impl ModuleCollection {
             .into_iter()
             .collect::<Vec<_>>();
 
+        (path_bufs, errors)
+    }
+
+    pub fn from_directory(
+        directory: &str,
+        extension: &str,
+        follow_links: bool,
+    ) -> (Self, Vec<ModuleCollectionError>) {
+        let (path_bufs, mut errors) = Self::find_files(directory, extension, follow_links);
+        let mut collection: ModuleCollection = Default::default();
+
         let paths: Vec<&Path> = path_bufs.iter().map(|p| p.borrow()).collect();
 
         let (modules, module_errors) = Module::from_paths::<Module>(paths.as_slice(), None);
@@ -121,10 +166,49 @@ impl ModuleCollection {
                 errors.push(err)
             }
         }
+        errors.extend(
+            collection
+                .unreachable_modules()
+                .into_iter()
+                .map(ModuleCollectionError::UnreachableModule),
+        );
 
         (collection, errors)
     }
 
+    /// modules without an endpoint that no endpoint imports, directly or through
+    /// other imports. the server can never run these
+    pub fn unreachable_modules(&self) -> Vec<PathBuf> {
+        let mut reachable = BTreeSet::new();
+        let mut locations: Vec<&Path> = self
+            .endpoints
+            .values()
+            .map(|module| module.front_matter.location.as_path())
+            .collect();
+        while let Some(location) = locations.pop() {
+            if !reachable.insert(location) {
+                continue;
+            }
+            if let Some(module) = self.locations.get(location) {
+                locations.extend(
+                    module
+                        .front_matter
+                        .imports
+                        .values()
+                        .map(|(location, _)| location.as_path()),
+                );
+            }
+        }
+
+        self.locations
+            .keys()
+            .filter(|location| !reachable.contains(location.as_path()))
+            .cloned()
+            .collect()
+    }
+
+    /// parses the file again along with any imports that are not in the collection yet.
+    /// modules that are already imported are reused instead of being read from disk
     pub fn import_module(&self, path: &Path) -> (BTreeMap<PathBuf, Module>, Vec<ModuleError>) {
         self.import_modules(&[path])
     }
@@ -163,6 +247,7 @@ impl ModuleCollection {
         location: PathBuf,
         module: Module,
     ) -> Result<(), ModuleCollectionError> {
+        self.statements.clear();
         self.transaction(|collection| {
             if !location.is_absolute() {
                 return Err(ModuleCollectionError::NotAbsolutePath(location));
@@ -170,8 +255,8 @@ impl ModuleCollection {
 
             let module = Arc::new(module);
 
-            // insert module endpoint
-            if let Some(endpoint) = module.front_matter.endpoint.as_ref() {
+            // insert module endpoints, the transaction drops them all if any is taken
+            for endpoint in module.front_matter.endpoints.iter() {
                 if collection.endpoints.contains_key(endpoint) {
                     return Err(ModuleCollectionError::AlreadyUsedEndpointError(
                         location,
@@ -209,17 +294,104 @@ impl ModuleCollection {
         } else {
             new_loc = location;
         }
+        self.statements.clear();
         // no need for transactions since this cannot fail
         let removed_arc = self.locations.remove(new_loc);
-        match removed_arc
-            .as_ref()
-            .and_then(|arc| arc.front_matter.endpoint.as_ref())
+        for endpoint in removed_arc
+            .iter()
+            .flat_map(|arc| arc.front_matter.endpoints.iter())
         {
-            Some(endpoint) => {
-                self.endpoints.remove(endpoint);
-                Ok(true)
-            }
-            None => Ok(removed_arc.is_some()),
+            self.endpoints.remove(endpoint);
         }
+        Ok(removed_arc.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::temp_dir::temp_dir;
+
+    #[test]
+    fn unreachable_modules_test() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let (collection, errors) =
+            ModuleCollection::from_directory(directory.to_str().unwrap(), "sql", false);
+        assert!(errors.iter().all(ModuleCollectionError::is_warning));
+        assert_eq!(
+            collection.unreachable_modules(),
+            vec![directory
+                .join("last_active_users.sql")
+                .canonicalize()
+                .unwrap()]
+        );
+
+        // strict keeps the warnings as errors
+        let (_, strict_errors) =
+            ModuleCollection::from_directory(directory.to_str().unwrap(), "sql", false);
+        assert_eq!(
+            ModuleCollectionError::report_warnings(strict_errors, true).len(),
+            1
+        );
+        assert!(ModuleCollectionError::report_warnings(errors, false).is_empty());
+    }
+
+    #[test]
+    fn import_module_reuses_dependencies_test() {
+        let directory = temp_dir("import_module_test");
+        let main = directory.join("main.sql");
+        let dependency = directory.join("dependency.sql");
+        std::fs::write(&dependency, "select 1").unwrap();
+        std::fs::write(
+            &main,
+            "-- @import dependency from './dependency.sql'\nselect * from @dependency()",
+        )
+        .unwrap();
+        let main = main.canonicalize().unwrap();
+        let (collection, errors) = ModuleCollection::from_paths(&[main.as_path()]);
+        assert!(errors.is_empty());
+        assert_eq!(collection.locations.len(), 2);
+
+        // reading the dependency again would fail to parse it
+        std::fs::write(&dependency, "-- @import").unwrap();
+        std::fs::write(
+            &main,
+            "-- @import dependency from './dependency.sql'\nselect 2 from @dependency()",
+        )
+        .unwrap();
+        let (modules, errors) = collection.import_module(main.as_path());
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(modules.keys().collect::<Vec<_>>(), vec![&main]);
+    }
+
+    #[test]
+    fn endpoint_aliases_test() {
+        let mut collection = ModuleCollection::default();
+        let module = |sql| Module::from_str(PathBuf::new(), sql).unwrap();
+        collection
+            .insert(
+                PathBuf::from("/users.sql"),
+                module("-- @endpoint users.list, getUsers\nselect * from users"),
+            )
+            .unwrap();
+        assert_eq!(
+            collection.endpoints.keys().collect::<Vec<_>>(),
+            vec!["getUsers", "users.list"]
+        );
+
+        // a taken alias rejects the whole module
+        let err = collection
+            .insert(
+                PathBuf::from("/admins.sql"),
+                module("-- @endpoint admins, getUsers\nselect * from admins"),
+            )
+            .unwrap_err();
+        assert!(
+            matches!(err, ModuleCollectionError::AlreadyUsedEndpointError(_, endpoint) if endpoint == "getUsers")
+        );
+        assert!(!collection.endpoints.contains_key("admins"));
+
+        collection.remove(Path::new("/users.sql")).unwrap();
+        assert!(collection.endpoints.is_empty());
     }
 }
@@ -1,6 +1,9 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use crate::{codegen::Module, util::error_printing::PrintableError};
+use crate::{codegen::Module, query::StatementCache, util::error_printing::PrintableError};
 
 use super::{
     importer::Importer,
@@ -11,7 +14,7 @@ use super::{
 pub struct UpfrontImporter(ModuleCollection);
 
 impl UpfrontImporter {
-    pub fn from_paths_or_print_error(paths: &[&Path]) -> Option<Self> {
+    pub fn from_paths_or_print_error(paths: &[&Path], strict: bool) -> Option<Self> {
         let paths_iter = paths.iter().map(|path| {
             path.canonicalize()
                 .map_err(|err| ModuleCollectionError::IOError(path.to_path_buf(), err))
@@ -30,7 +33,7 @@ impl UpfrontImporter {
             .iter()
             .map(|path| path.as_path())
             .collect::<Vec<_>>();
-        match Self::from_paths(paths.as_slice()) {
+        match Self::from_paths(paths.as_slice(), strict) {
             Ok(importer) => Some(importer),
             Err(err) => {
                 let mut buf = String::new();
@@ -41,8 +44,10 @@ impl UpfrontImporter {
         }
     }
 
-    pub fn from_paths(paths: &[&Path]) -> Result<Self, Vec<ModuleCollectionError>> {
+    /// imports the modules and their dependencies. strict fails on warnings too
+    pub fn from_paths(paths: &[&Path], strict: bool) -> Result<Self, Vec<ModuleCollectionError>> {
         let (collection, errors) = ModuleCollection::from_paths(paths);
+        let errors = ModuleCollectionError::report_warnings(errors, strict);
         if errors.len() != 0 {
             Err(errors)
         } else {
@@ -55,8 +60,14 @@ impl UpfrontImporter {
         }
     }
 
-    pub fn new(directory: &str, extension: &str) -> Result<Self, Vec<ModuleCollectionError>> {
+    /// imports every module in the directory. strict fails on warnings too
+    pub fn new(
+        directory: &str,
+        extension: &str,
+        strict: bool,
+    ) -> Result<Self, Vec<ModuleCollectionError>> {
         let (collection, errors) = ModuleCollection::from_directory(directory, extension, false);
+        let errors = ModuleCollectionError::report_warnings(errors, strict);
         if errors.len() != 0 {
             Err(errors)
         } else {
@@ -68,6 +79,15 @@ impl UpfrontImporter {
             Ok(Self(collection))
         }
     }
+
+    /// every imported module along with its canonicalized location
+    pub fn get_all_modules(&self) -> Vec<(PathBuf, Arc<Module>)> {
+        self.0
+            .locations
+            .iter()
+            .map(|(location, module)| (location.clone(), module.clone()))
+            .collect()
+    }
 }
 
 impl Importer for UpfrontImporter {
@@ -91,4 +111,8 @@ impl Importer for UpfrontImporter {
         let res = self.0.endpoints.keys().cloned().collect();
         Ok(res)
     }
+
+    fn statement_cache(&self) -> Option<&StatementCache> {
+        Some(&self.0.statements)
+    }
 }
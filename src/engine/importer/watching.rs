@@ -10,6 +10,7 @@ use thiserror::Error;
 
 use crate::{
     codegen::{Module, ModuleError},
+    query::StatementCache,
     util::{error_printing::PrintableError, path::path_relative_to_current_dir},
 };
 
@@ -57,11 +58,21 @@ impl Importer for WatchingImporter {
             .collect();
         Ok(res)
     }
+
+    fn statement_cache(&self) -> Option<&StatementCache> {
+        Some(&self.0.statements)
+    }
 }
 
 impl WatchingImporter {
-    pub fn new(directory: &str, extension: &str) -> anyhow::Result<Self> {
-        let internals = WatchingInternals::new(directory, extension)?;
+    /// changes are applied once no file has changed for the debounce interval
+    pub fn new(
+        directory: &str,
+        extension: &str,
+        strict: bool,
+        debounce: Duration,
+    ) -> anyhow::Result<Self> {
+        let internals = WatchingInternals::new(directory, extension, strict, debounce)?;
         Ok(Self(Arc::new(internals)))
     }
 }
@@ -83,35 +94,76 @@ pub enum EventError {
 #[derive(Debug)]
 struct WatchingInternals {
     collection: Arc<Mutex<ModuleCollection>>,
+    /// shared with the collection, which clears it whenever a module changes
+    statements: StatementCache,
     handle: JoinHandle<()>,
 }
 
 impl WatchingInternals {
-    pub fn new(directory: &str, extension: &str) -> anyhow::Result<Self> {
+    /// strict only applies to the first import, later changes only log their warnings
+    pub fn new(
+        directory: &str,
+        extension: &str,
+        strict: bool,
+        debounce: Duration,
+    ) -> anyhow::Result<Self> {
         let (collection, errors) = ModuleCollection::from_directory(directory, extension, false);
-        if errors.len() != 0 {
+        let errors = ModuleCollectionError::report_warnings(errors, strict);
+        if strict && errors.len() != 0 {
             let mut buf = String::new();
             for err in errors {
                 err.print_error(&mut buf)?;
             }
-        };
+            eprint!("{}", buf);
+            return Err(anyhow!("failed to import some sql files"));
+        }
+
+        // broken files are left out until they are fixed so the server can still start
+        let mut buf = String::new();
+        for err in errors.iter() {
+            match err.print_error(&mut buf) {
+                Ok(_) => warn!("could not import module:\n\n{}", buf),
+                Err(err) => warn!("display error when reporting issues: {}", err),
+            }
+            buf.clear();
+        }
+        if errors.is_empty() {
+            info!(
+                "succesfully imported {} endpoints, and {} modules",
+                collection.endpoints.len(),
+                collection.locations.len()
+            );
+        } else {
+            warn!(
+                "imported {} endpoints, and {} modules but {} module(s) failed to import",
+                collection.endpoints.len(),
+                collection.locations.len(),
+                errors.len()
+            );
+        }
 
+        let statements = collection.statements.clone();
         let collection = Arc::new(Mutex::new(collection));
-        let handle = Self::create_watcher(collection.clone(), directory, extension)?;
-        Ok(Self { collection, handle })
+        let handle = Self::create_watcher(collection.clone(), directory, extension, debounce)?;
+        Ok(Self {
+            collection,
+            statements,
+            handle,
+        })
     }
 
     fn create_watcher(
         collection: Arc<Mutex<ModuleCollection>>,
         directory: &str,
         extension: &str,
+        debounce: Duration,
     ) -> anyhow::Result<JoinHandle<()>> {
         // Create a channel to receive the events.
         let (tx, rx) = channel();
 
         // Create a watcher object, delivering debounced events.
         // The notification back-end is selected based on the platform.
-        let mut watcher = watcher(tx, Duration::from_millis(250))?;
+        let mut watcher = watcher(tx, debounce)?;
 
         // Add a path to be watched. All files and directories at that path and
         // below will be monitored for changes.
@@ -161,6 +213,20 @@ impl WatchingInternals {
     }
 }
 
+/// logs the warnings of a change and returns the errors that stop it from applying
+fn report_warnings(errors: Vec<ModuleError>) -> Vec<ModuleError> {
+    let (warnings, errors): (Vec<_>, Vec<_>) =
+        errors.into_iter().partition(ModuleError::is_warning);
+    let mut buf = String::new();
+    for warning in warnings {
+        if warning.print_error(&mut buf).is_ok() {
+            warn!("\n{}", buf);
+        }
+        buf.clear();
+    }
+    errors
+}
+
 fn listen_event(
     collection: &Mutex<ModuleCollection>,
     watcher: &mut INotifyWatcher,
@@ -216,6 +282,7 @@ fn listen_event(
                     .map_err(|_| EventError::AbortError(mutex_lock_error))?;
 
                 let (modules, errors) = guard.import_module(path.as_path());
+                let errors = report_warnings(errors);
 
                 guard.transaction::<_, ModuleCollectionError, _>(|collection| {
                     for (loc, module) in modules {
@@ -242,6 +309,7 @@ fn listen_event(
                         .lock()
                         .map_err(|_| EventError::AbortError(mutex_lock_error))?;
                     let (modules, errors) = guard.import_module(path.as_path());
+                    let errors = report_warnings(errors);
                     guard.transaction::<_, ModuleCollectionError, _>(|collection| {
                         for (loc, module) in modules {
                             collection.upsert(loc, module)?;
@@ -4,5 +4,5 @@ mod importer;
 pub use evaluator::Evaluator;
 pub use importer::{
     module_collection::{ModuleCollection, ModuleCollectionError},
-    Importer, UpfrontImporter, WatchingImporter,
+    Importer, LazyImporter, UpfrontImporter, WatchingImporter,
 };
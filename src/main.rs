@@ -16,14 +16,17 @@ mod row_type;
 mod server;
 mod util;
 
+/// the version of justsql, as reported by `--version` and `GET /version`
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 pub fn main() -> anyhow::Result<()> {
-    env_logger::init_from_env(
-        env_logger::Env::new().default_filter_or("actix_web=info,justsql=info"),
-    );
+    let opt: command::Opts = command::Opts::parse();
+    util::logging::init_logger(opt.log_format);
+    util::error_printing::init_color(opt.color, opt.log_format);
+    util::error_printing::init_context_lines(opt.error_context);
 
     if let Some(path) = dotenv::dotenv().ok() {
         info!("loaded .env file from {:?}", path.as_os_str())
     }
-    let opt: command::Opts = command::Opts::parse();
     opt.run()
 }
@@ -1,87 +1,311 @@
-use std::collections::BTreeMap;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
 
-use sqlx::{postgres::PgArguments, PgPool, Postgres};
+use futures::{channel::mpsc, SinkExt, TryStreamExt};
+use hashlink::LruCache;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{
+    pool::PoolConnection,
+    postgres::{PgArguments, PgRow, PgValueFormat},
+    Acquire, Execute, Executor, PgPool, Postgres, Transaction,
+};
 use std::fmt::Write;
+use thiserror::Error;
 
 use crate::{
     binding::Binding,
-    codegen::{Interp, Module, ParamType},
+    codegen::{Arg, Interp, Module, ParamKind, ParamType},
+    config::Config,
     engine::Importer,
-    row_type::{convert_row, RowType},
+    row_type::{convert_row, Row, UnknownTypeFallback},
 };
 
 /// maps params to bindings
-pub fn evaluate<'a, I: Importer, A>(
+pub fn evaluate<'a, I: Importer>(
     module: &Module,
     importer: &I,
-    bindings: &'a BTreeMap<String, A>,
-    auth_bindings: Option<&'a BTreeMap<String, A>>,
-) -> anyhow::Result<Vec<(String, Vec<&'a A>)>> {
-    module
-        .sql
-        .iter()
-        .map(|stmt| {
-            let (query, params) = build_query_statement(&module, importer, stmt.as_slice())?;
-            let binding = bind_params(params.as_slice(), bindings, auth_bindings)?;
+    bindings: &'a BTreeMap<String, Binding>,
+    auth_bindings: Option<&'a BTreeMap<String, Binding>>,
+    max_import_depth: usize,
+    assume_null_if_missing: bool,
+) -> anyhow::Result<Vec<(String, Vec<Cow<'a, Binding>>)>> {
+    (0..module.sql.len())
+        .map(|idx| {
+            let built = build_cached_statement(module, importer, idx, bindings, max_import_depth)?;
+            let (query, params, _) = built.as_ref();
+            let query = query.clone();
+            let binding = bind_params(
+                params.as_slice(),
+                bindings,
+                auth_bindings,
+                assume_null_if_missing,
+            )?;
             Ok((query, binding))
         })
         .collect::<anyhow::Result<Vec<_>>>()
 }
 
-/// maps params to bindings
-pub fn bind_params<'a, 'b, A>(
+/// maps params to bindings and writes them directly into the sql text
+/// so the statements can be run over the simple query protocol
+pub fn evaluate_inline<I: Importer>(
+    module: &Module,
+    importer: &I,
+    bindings: &BTreeMap<String, Binding>,
+    auth_bindings: Option<&BTreeMap<String, Binding>>,
+    max_import_depth: usize,
+    assume_null_if_missing: bool,
+) -> anyhow::Result<Vec<String>> {
+    (0..module.sql.len())
+        .map(|idx| {
+            let built = build_cached_statement(module, importer, idx, bindings, max_import_depth)?;
+            let (query, params, placeholders) = built.as_ref();
+            let binding = bind_params(
+                params.as_slice(),
+                bindings,
+                auth_bindings,
+                assume_null_if_missing,
+            )?;
+            inline_bindings(query.as_str(), placeholders.as_slice(), binding.as_slice())
+        })
+        .collect()
+}
+
+/// replaces each placeholder with its binding as a sql literal
+fn inline_bindings(
+    query: &str,
+    placeholders: &[(usize, usize)],
+    bindings: &[Cow<Binding>],
+) -> anyhow::Result<String> {
+    let mut buf = String::with_capacity(query.len());
+    let mut last = 0;
+    for (offset, idx) in placeholders {
+        let binding = bindings
+            .get(idx - 1)
+            .ok_or_else(|| anyhow!("no binding for placeholder ${}", idx))?;
+        buf.push_str(&query[last..*offset]);
+        buf.push_str(binding.to_sql_string()?.as_str());
+        last = offset + format!("${}", idx).len();
+    }
+    buf.push_str(&query[last..]);
+    Ok(buf)
+}
+
+/// a payload that does not cover a statement's parameters
+#[derive(Error, Debug)]
+pub enum MissingBinding {
+    #[error("parameter {0} does not exist")]
+    Param(String),
+    #[error("positional parameter @{0} does not exist")]
+    Positional(usize),
+    #[error("must have auth token")]
+    AuthToken,
+    #[error("auth claim {0} does not exist")]
+    Claim(String),
+    #[error("parameter {0} must be an array of 1 to {1} objects")]
+    Rows(String, usize),
+    #[error("parameter {0}[{1}].{2} does not exist")]
+    Field(String, usize, String),
+}
+
+/// maps params to bindings. parameters missing from the payload are bound as null
+/// when `assume_null_if_missing` is set, missing auth claims are always an error
+pub fn bind_params<'a, 'b>(
     params: &'b [ParamType],
-    bindings: &'a BTreeMap<String, A>,
-    auth_bindings: Option<&'a BTreeMap<String, A>>,
-) -> anyhow::Result<Vec<&'a A>> {
+    bindings: &'a BTreeMap<String, Binding>,
+    auth_bindings: Option<&'a BTreeMap<String, Binding>>,
+    assume_null_if_missing: bool,
+) -> anyhow::Result<Vec<Cow<'a, Binding>>> {
+    let missing = |err: MissingBinding| -> anyhow::Result<Cow<'a, Binding>> {
+        match assume_null_if_missing {
+            true => Ok(Cow::Owned(Binding::Null)),
+            false => Err(err.into()),
+        }
+    };
     params
         .iter()
         .cloned()
         .map(|param| match param {
-            ParamType::Param(param) => bindings
-                .get(param.as_str())
-                .ok_or_else(|| anyhow!("parameter {} does not exist", param)),
-            ParamType::Auth(param) => auth_bindings
-                .ok_or_else(|| anyhow!("must have auth token"))?
-                .get(param.as_str())
-                .ok_or_else(|| anyhow!("parameter {} does not exist", param)),
+            ParamType::Param(param) => match bindings.get(param.as_str()) {
+                Some(binding) => Ok(Cow::Borrowed(binding)),
+                None => missing(MissingBinding::Param(param)),
+            },
+            ParamType::Positional(position) => match bindings.get(position.to_string().as_str()) {
+                Some(binding) => Ok(Cow::Borrowed(binding)),
+                None => missing(MissingBinding::Positional(position)),
+            },
+            ParamType::Json(param) => match bindings.get(param.as_str()) {
+                Some(binding) => Ok(Cow::Owned(binding.to_json()?)),
+                None => missing(MissingBinding::Param(param)),
+            },
+            ParamType::Auth(path) => get_claim(
+                auth_bindings.ok_or(MissingBinding::AuthToken)?,
+                path.as_str(),
+            ),
+            ParamType::Field(param, row, field) => match bindings.get(param.as_str()) {
+                Some(Binding::Json(Value::Array(rows))) => {
+                    match rows.get(row).and_then(|value| value.get(field.as_str())) {
+                        Some(value) => Ok(Cow::Owned(Binding::from_json(value.clone())?)),
+                        None => missing(MissingBinding::Field(param, row, field)),
+                    }
+                }
+                _ => Err(MissingBinding::Rows(param, MAX_FOREACH_ROWS).into()),
+            },
         })
-        .collect::<anyhow::Result<_>>()
+        .collect()
+}
+
+/// looks up a dotted claim path such as `org.id` by indexing into nested json claims
+fn get_claim<'a>(
+    claims: &'a BTreeMap<String, Binding>,
+    path: &str,
+) -> anyhow::Result<Cow<'a, Binding>> {
+    let missing = || MissingBinding::Claim(path.to_string());
+    let mut segments = path.split('.');
+    let claim = claims
+        .get(segments.next().unwrap_or_default())
+        .ok_or_else(missing)?;
+
+    match claim {
+        Binding::Json(value) => {
+            let mut value = value;
+            for segment in segments {
+                value = value.get(segment).ok_or_else(missing)?;
+            }
+            Ok(Cow::Owned(Binding::from_json(value.clone())?))
+        }
+        _ if segments.next().is_none() => Ok(Cow::Borrowed(claim)),
+        _ => Err(missing().into()),
+    }
+}
+
+/// what a parameter name refers to inside of a module. parameters of imported modules
+/// can either be bound to the importing module's parameters or to literal arguments.
+#[derive(Debug, Clone)]
+enum ParamValue<'a> {
+    /// a parameter along with whether the payload has it, which decides whether the
+    /// `@if` fragments it guards are kept
+    Param(ParamType, bool),
+    Literal(&'a str),
 }
 
 /// generates the postgres sql query
-/// and the argument bindings in the exact right order
+/// and the argument bindings in the exact right order.
+/// `@if` fragments are left out when their parameter is not in the payload, without a
+/// payload every fragment is kept and every `@foreach` fragment is written once
 pub fn build_query_statement<'a, I: Importer>(
     module: &'a Module,
     importer: &'a I,
     statement: &'a [Interp],
+    payload: Option<&BTreeMap<String, Binding>>,
+    max_import_depth: usize,
 ) -> anyhow::Result<(String, Vec<ParamType>)> {
+    let (buf, params, _) = build_statement(module, importer, statement, payload, max_import_depth)?;
+    Ok((buf, params))
+}
+
+/// the byte offset of every `$n` placeholder in a statement along with its n
+type Placeholders = Vec<(usize, usize)>;
+
+/// imports nested deeper than this are rejected unless the config says otherwise
+pub const DEFAULT_MAX_IMPORT_DEPTH: usize = 32;
+
+/// the most rows a `@foreach` fragment is repeated for, which keeps a statement below
+/// the 65535 parameters postgres allows for rows with up to 65 fields
+pub const MAX_FOREACH_ROWS: usize = 1000;
+
+/// the state shared by every module that is inlined into a statement
+struct Inlining {
+    /// the number of the placeholder each parameter is bound to
+    mapping: BTreeMap<ParamType, usize>,
+    placeholders: Placeholders,
+    /// the names of the imports being inlined, outermost first
+    imports: Vec<String>,
+    max_import_depth: usize,
+    /// the number of rows of every array parameter, none when there is no payload
+    rows: Option<BTreeMap<String, usize>>,
+    /// the row each `@foreach` that is being written is at
+    current_rows: BTreeMap<String, usize>,
+}
+
+/// the number of rows of the module's parameters that are arrays of objects
+fn row_counts(module: &Module, payload: &BTreeMap<String, Binding>) -> BTreeMap<String, usize> {
+    module
+        .front_matter
+        .params
+        .iter()
+        .filter_map(|param| match payload.get(param.as_str()) {
+            Some(Binding::Json(Value::Array(rows))) if rows.iter().all(Value::is_object) => {
+                Some((param.clone(), rows.len()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// like `build_query_statement` but also returns where the placeholders were written
+fn build_statement<'a, I: Importer>(
+    module: &'a Module,
+    importer: &'a I,
+    statement: &'a [Interp],
+    payload: Option<&BTreeMap<String, Binding>>,
+    max_import_depth: usize,
+) -> anyhow::Result<(String, Vec<ParamType>, Placeholders)> {
     let mut buf = String::new();
-    let mut mapping = BTreeMap::new();
+    let mut inlining = Inlining {
+        mapping: BTreeMap::new(),
+        placeholders: vec![],
+        imports: vec![],
+        max_import_depth,
+        rows: payload.map(|payload| row_counts(module, payload)),
+        current_rows: BTreeMap::new(),
+    };
     let param_mapping = module
         .front_matter
         .params
         .iter()
-        .map(|param| (param.as_str(), ParamType::Param(param.clone())))
+        .map(|param| {
+            (
+                param.as_str(),
+                ParamValue::Param(
+                    match module.front_matter.param_kinds.get(param) {
+                        Some(ParamKind::Json) => ParamType::Json(param.clone()),
+                        None => ParamType::Param(param.clone()),
+                    },
+                    payload.is_none_or(|payload| payload.contains_key(param)),
+                ),
+            )
+        })
         .collect();
     build_query_statement_helper(
         module,
         importer,
         &mut buf,
-        &mut mapping,
+        &mut inlining,
         &param_mapping,
         statement.iter(),
     )?;
 
+    let Inlining {
+        mapping,
+        placeholders,
+        ..
+    } = inlining;
     let params = {
         // invert the btree
         let inv_mapping: BTreeMap<_, _> = mapping.into_iter().map(|tup| (tup.1, tup.0)).collect();
 
         // uses the fact that this is in sorted order and checks if the mappings
-        // where number going from 1 to len(mapping) + 1
+        // where numbered going from 1 to len(mapping)
         if inv_mapping
             .keys()
-            .zip(1..=(inv_mapping.len() + 1))
+            .zip(1..=inv_mapping.len())
             .any(|(v1, v2)| *v1 != v2)
         {
             Err(anyhow!("not all variable bindings were set"))?
@@ -90,7 +314,144 @@ pub fn build_query_statement<'a, I: Importer>(
         }
     };
 
-    Ok((buf, params))
+    Ok((buf, params, placeholders))
+}
+
+/// the sql, parameters and placeholders of a built statement
+type BuiltStatement = (String, Vec<ParamType>, Placeholders);
+
+/// how many built statements an importer keeps around
+const STATEMENT_CACHE_CAPACITY: usize = 4096;
+
+/// identifies a built statement. which `@if` fragments are kept only depends on
+/// which of the module's parameters are in the payload and how often `@foreach`
+/// fragments are repeated only on the number of rows of its array parameters
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct StatementKey {
+    location: PathBuf,
+    statement: usize,
+    present: Vec<String>,
+    rows: Vec<(String, usize)>,
+    max_import_depth: usize,
+}
+
+/// the statements an importer has already built. imports are inlined into the sql
+/// so any change to a module has to clear the whole cache
+#[derive(Clone)]
+pub struct StatementCache(Arc<Mutex<StatementCacheEntries>>);
+
+struct StatementCacheEntries {
+    /// bumped on every clear so statements built from modules that changed
+    /// in the meantime are not cached
+    generation: u64,
+    built: LruCache<StatementKey, Arc<BuiltStatement>>,
+    /// every claim the modules read through `@auth`, see `Evaluator::auth_claims`
+    auth_claims: Option<Arc<BTreeSet<String>>>,
+}
+
+impl Default for StatementCache {
+    fn default() -> Self {
+        StatementCache(Arc::new(Mutex::new(StatementCacheEntries {
+            generation: 0,
+            built: LruCache::new(STATEMENT_CACHE_CAPACITY),
+            auth_claims: None,
+        })))
+    }
+}
+
+impl fmt::Debug for StatementCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("StatementCache").field(&self.len()).finish()
+    }
+}
+
+impl StatementCache {
+    fn entries(&self) -> MutexGuard<'_, StatementCacheEntries> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn len(&self) -> usize {
+        self.entries().built.len()
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries();
+        entries.generation += 1;
+        entries.built.clear();
+        entries.auth_claims = None;
+    }
+
+    /// the claims read through `@auth`, collected once until the modules change
+    pub fn auth_claims(
+        &self,
+        collect: impl FnOnce() -> anyhow::Result<Arc<BTreeSet<String>>>,
+    ) -> anyhow::Result<Arc<BTreeSet<String>>> {
+        let generation = {
+            let entries = self.entries();
+            if let Some(claims) = entries.auth_claims.as_ref() {
+                return Ok(claims.clone());
+            }
+            entries.generation
+        };
+        let claims = collect()?;
+        let mut entries = self.entries();
+        if entries.generation == generation {
+            entries.auth_claims = Some(claims.clone());
+        }
+        Ok(claims)
+    }
+}
+
+/// builds the statement at `idx` of the module, reusing what the importer already
+/// built for it. modules the importer does not hold are built every time
+fn build_cached_statement<I: Importer>(
+    module: &Module,
+    importer: &I,
+    idx: usize,
+    payload: &BTreeMap<String, Binding>,
+    max_import_depth: usize,
+) -> anyhow::Result<Arc<BuiltStatement>> {
+    let build = || -> anyhow::Result<Arc<BuiltStatement>> {
+        let statement = module.sql[idx].as_slice();
+        let built = build_statement(module, importer, statement, Some(payload), max_import_depth)?;
+        Ok(Arc::new(built))
+    };
+    let cache = match importer.statement_cache() {
+        Some(cache) => cache,
+        None => return build(),
+    };
+
+    let key = StatementKey {
+        location: module.front_matter.location.clone(),
+        statement: idx,
+        present: module
+            .front_matter
+            .params
+            .iter()
+            .filter(|param| payload.contains_key(param.as_str()))
+            .cloned()
+            .collect(),
+        rows: row_counts(module, payload).into_iter().collect(),
+        max_import_depth,
+    };
+    let generation = {
+        let mut entries = cache.entries();
+        if let Some(built) = entries.built.get(&key) {
+            return Ok(built.clone());
+        }
+        entries.generation
+    };
+    // the generation is read first so a module replaced after this check clears the cache
+    let owned = matches!(
+        importer.get_module_from_location(&module.front_matter.location),
+        Ok(owned) if std::ptr::eq(owned.as_ref(), module)
+    );
+    let built = build()?;
+    let mut entries = cache.entries();
+    if owned && entries.generation == generation {
+        entries.built.insert(key, built.clone());
+    }
+    Ok(built)
 }
 
 // recursive function for inlining all imports
@@ -98,8 +459,8 @@ fn build_query_statement_helper<'a, I, M>(
     module: &Module,
     importer: &'a M,
     writer: &mut String,
-    mapping: &mut BTreeMap<ParamType, usize>,
-    param_mapping: &BTreeMap<&str, ParamType>,
+    inlining: &mut Inlining,
+    param_mapping: &BTreeMap<&str, ParamValue<'a>>,
     statement: I,
 ) -> anyhow::Result<()>
 where
@@ -111,21 +472,129 @@ where
             Interp::Literal(lit) => write!(writer, "{}", lit.as_str())?,
             Interp::AuthParam(param) => {
                 let param = ParamType::Auth(param.clone());
-                if !mapping.contains_key(&param) {
-                    let cur = mapping.len() + 1;
-                    mapping.insert(param.clone(), cur);
+                if !inlining.mapping.contains_key(&param) {
+                    let cur = inlining.mapping.len() + 1;
+                    inlining.mapping.insert(param.clone(), cur);
+                }
+                inlining
+                    .placeholders
+                    .push((writer.len(), inlining.mapping[&param]));
+                write!(writer, "${}", inlining.mapping[&param])?
+            }
+            Interp::PositionalParam(position) => {
+                let param = ParamType::Positional(*position);
+                if !inlining.mapping.contains_key(&param) {
+                    let cur = inlining.mapping.len() + 1;
+                    inlining.mapping.insert(param.clone(), cur);
                 }
-                write!(writer, "${}", mapping[&param])?
+                inlining
+                    .placeholders
+                    .push((writer.len(), inlining.mapping[&param]));
+                write!(writer, "${}", inlining.mapping[&param])?
             }
             Interp::Param(param) => {
-                let param_type = param_mapping.get(param.as_str()).ok_or_else(|| {
+                let param_type = match param_mapping.get(param.as_str()).ok_or_else(|| {
+                    anyhow!("could not map paramter {} to the right param type", param)
+                })? {
+                    ParamValue::Param(param_type, _) => param_type,
+                    // numbers are parenthesized like inlined bindings so `10-@n` called
+                    // with -1 can not turn into a `--` comment
+                    ParamValue::Literal(lit) if lit.starts_with('\'') => {
+                        write!(writer, "{}", lit)?;
+                        continue;
+                    }
+                    ParamValue::Literal(lit) => {
+                        write!(writer, "({})", lit)?;
+                        continue;
+                    }
+                };
+                if !inlining.mapping.contains_key(param_type) {
+                    let cur = inlining.mapping.len() + 1;
+                    inlining.mapping.insert(param_type.clone(), cur);
+                }
+                inlining
+                    .placeholders
+                    .push((writer.len(), inlining.mapping[param_type]));
+                write!(writer, "${}", inlining.mapping[param_type])?
+            }
+
+            Interp::Conditional(param, fragment) => {
+                let present = match param_mapping.get(param.as_str()).ok_or_else(|| {
+                    anyhow!("could not map paramter {} to the right param type", param)
+                })? {
+                    ParamValue::Param(_, present) => *present,
+                    ParamValue::Literal(_) => true,
+                };
+                if present {
+                    build_query_statement_helper(
+                        module,
+                        importer,
+                        writer,
+                        inlining,
+                        param_mapping,
+                        fragment.iter(),
+                    )?;
+                }
+            }
+
+            Interp::Foreach(param, fragment) => {
+                let root = match param_mapping.get(param.as_str()).ok_or_else(|| {
                     anyhow!("could not map paramter {} to the right param type", param)
+                })? {
+                    ParamValue::Param(ParamType::Param(root), _)
+                    | ParamValue::Param(ParamType::Json(root), _) => root.clone(),
+                    _ => Err(anyhow!("@foreach({}) must loop over a parameter", param))?,
+                };
+                let rows = match &inlining.rows {
+                    None => 1,
+                    Some(rows) => match rows.get(root.as_str()) {
+                        Some(rows) if (1..=MAX_FOREACH_ROWS).contains(rows) => *rows,
+                        _ => Err(MissingBinding::Rows(root.clone(), MAX_FOREACH_ROWS))?,
+                    },
+                };
+                let outer = inlining.current_rows.remove(root.as_str());
+                for row in 0..rows {
+                    if row != 0 {
+                        write!(writer, ", ")?;
+                    }
+                    inlining.current_rows.insert(root.clone(), row);
+                    build_query_statement_helper(
+                        module,
+                        importer,
+                        writer,
+                        inlining,
+                        param_mapping,
+                        fragment.iter(),
+                    )?;
+                }
+                inlining.current_rows.remove(root.as_str());
+                if let Some(outer) = outer {
+                    inlining.current_rows.insert(root, outer);
+                }
+            }
+
+            Interp::Field(param, field) => {
+                let root = match param_mapping.get(param.as_str()) {
+                    Some(ParamValue::Param(ParamType::Param(root), _))
+                    | Some(ParamValue::Param(ParamType::Json(root), _)) => root,
+                    _ => Err(anyhow!(
+                        "@{}.{} must be a field of a parameter",
+                        param,
+                        field
+                    ))?,
+                };
+                let row = *inlining.current_rows.get(root.as_str()).ok_or_else(|| {
+                    anyhow!("@{}.{} is used outside of its @foreach", param, field)
                 })?;
-                if !mapping.contains_key(param_type) {
-                    let cur = mapping.len() + 1;
-                    mapping.insert(param_type.clone(), cur);
+                let param = ParamType::Field(root.clone(), row, field.clone());
+                if !inlining.mapping.contains_key(&param) {
+                    let cur = inlining.mapping.len() + 1;
+                    inlining.mapping.insert(param.clone(), cur);
                 }
-                write!(writer, "${}", mapping[param_type])?
+                inlining
+                    .placeholders
+                    .push((writer.len(), inlining.mapping[&param]));
+                write!(writer, "${}", inlining.mapping[&param])?
             }
 
             Interp::CallSite(func, params) => {
@@ -141,7 +610,7 @@ where
                     })?
                 };
 
-                let new_param_mapping: BTreeMap<&str, ParamType> = {
+                let new_param_mapping: BTreeMap<&str, ParamValue> = {
                     if params.len() != imported_module.front_matter.params.len() {
                         Err(anyhow!(
                             "number of parameters to do not match for imported module {}",
@@ -154,19 +623,22 @@ where
                         .params
                         .iter()
                         .zip(params.iter())
-                        .map(
-                            |(new_param, old_param)| -> anyhow::Result<(&str, ParamType)> {
-                                let param_type =
-                                    param_mapping.get(old_param.as_str()).ok_or_else(|| {
+                        .map(|(new_param, arg)| -> anyhow::Result<(&str, ParamValue)> {
+                            let param_value = match arg {
+                                Arg::Param(old_param) => param_mapping
+                                    .get(old_param.as_str())
+                                    .ok_or_else(|| {
                                         anyhow!(
                                             "could not map paramter {} to the right param type",
                                             old_param
                                         )
-                                    })?;
+                                    })?
+                                    .clone(),
+                                Arg::Literal(lit) => ParamValue::Literal(lit.as_str()),
+                            };
 
-                                Ok((new_param.as_str(), param_type.clone()))
-                            },
-                        )
+                            Ok((new_param.as_str(), param_value))
+                        })
                         .collect::<anyhow::Result<_>>()?
                 };
 
@@ -174,19 +646,39 @@ where
                     let first_statement = imported_module.sql.get(0).ok_or_else(|| {
                         anyhow!("imported module {} should have one statement", func)
                     })?;
+                    if Interp::flatten(first_statement)
+                        .iter()
+                        .any(|interp| matches!(interp, Interp::PositionalParam(_)))
+                    {
+                        Err(anyhow!(
+                            "imported module {} can not use positional parameters",
+                            func
+                        ))?
+                    }
                     first_statement.iter()
                 };
 
+                // every path through a diamond of imports is inlined separately so
+                // the depth has to be limited for the sql to stay a reasonable size
+                inlining.imports.push(func.clone());
+                if inlining.imports.len() > inlining.max_import_depth {
+                    Err(anyhow!(
+                        "imports are nested more than {} deep: {}",
+                        inlining.max_import_depth,
+                        inlining.imports.join(" -> ")
+                    ))?
+                }
                 write!(writer, " ( /* start of import {} */\n", func)?;
                 build_query_statement_helper(
                     imported_module.as_ref(),
                     importer,
                     writer,
-                    mapping,
+                    inlining,
                     &new_param_mapping,
                     new_statement,
                 )?;
                 write!(writer, "\n) /* end of import {} */", func)?;
+                inlining.imports.pop();
             }
         }
     }
@@ -194,30 +686,211 @@ where
 }
 
 pub fn build_queries<'a>(
-    statements: &'a Vec<(String, Vec<&Binding>)>,
+    statements: &'a Vec<(String, Vec<Cow<Binding>>)>,
 ) -> anyhow::Result<Vec<sqlx::query::Query<'a, Postgres, PgArguments>>> {
-    let queries = statements
+    statements
         .iter()
         .map(|(statement, bindings)| {
             let mut query = sqlx::query(statement);
             for binding in bindings {
-                query = match *binding {
+                query = match binding.as_ref() {
                     Binding::String(val) => query.bind(val),
                     Binding::Float(val) => query.bind(val),
                     Binding::Bool(val) => query.bind(val),
                     Binding::Int(val) => query.bind(val),
                     Binding::Json(val) => query.bind(val),
+                    array @ Binding::Array(values) => match array.array_type() {
+                        Some("int") => query.bind(collect_array(values, |value| match value {
+                            Binding::Int(val) => Some(*val),
+                            _ => None,
+                        })),
+                        Some("float") => query.bind(collect_array(values, |value| match value {
+                            Binding::Float(val) => Some(*val),
+                            _ => None,
+                        })),
+                        Some("bool") => query.bind(collect_array(values, |value| match value {
+                            Binding::Bool(val) => Some(*val),
+                            _ => None,
+                        })),
+                        // empty arrays and arrays of nulls default to text[]
+                        Some("string") | None => {
+                            query.bind(collect_array(values, |value| match value {
+                                Binding::String(val) => Some(val.clone()),
+                                _ => None,
+                            }))
+                        }
+                        Some(other) => Err(anyhow!("cannot bind an array of {}", other))?,
+                    },
                     Binding::Null => {
                         let res: Option<String> = None;
                         query.bind(res)
                     }
                 };
             }
-            query
+            Ok(query)
         })
-        .collect();
+        .collect()
+}
+
+/// maps the elements of an array binding, where anything that isn't of the
+/// array's type is a null since `Binding::from_json` keeps arrays homogeneous
+fn collect_array<T>(values: &[Binding], get: impl Fn(&Binding) -> Option<T>) -> Vec<Option<T>> {
+    values.iter().map(get).collect()
+}
+
+/// how the statements of a module are run and how their rows are converted
+#[derive(Debug, Clone, Copy)]
+pub struct RunOptions {
+    /// whether to rollback the query at the end
+    pub rollback: bool,
+    /// whether to inline the bindings and use the simple query protocol
+    pub simple_protocol: bool,
+    pub unknown_type_fallback: UnknownTypeFallback,
+    /// whether to collect the rows of every statement instead of only the last
+    pub all_statements: bool,
+    /// whether to set the auth claims as transaction local settings before running
+    pub set_local_claims: bool,
+    /// how deeply imports can be nested inside of each other
+    pub max_import_depth: usize,
+    /// whether parameters missing from the payload are bound as null
+    pub assume_null_if_missing: bool,
+    /// waiting longer than this for a pooled connection is logged as a warning
+    pub slow_acquire: Duration,
+    /// records every wait for a pooled connection, only set when metrics are enabled
+    pub record_acquire: Option<AcquireRecorder>,
+}
+
+/// records how long a query waited for a connection from the pool
+pub type AcquireRecorder = fn(Duration);
+
+impl RunOptions {
+    pub fn from_config(config: &Config, rollback: bool) -> Self {
+        Self {
+            rollback,
+            simple_protocol: config.database.simple_protocol,
+            unknown_type_fallback: config.server.unknown_type_fallback,
+            all_statements: false,
+            set_local_claims: matches!(config.auth.as_ref(), Some(secret) if secret.set_local_claims),
+            max_import_depth: config.server.max_import_depth,
+            assume_null_if_missing: config.assume_null_if_missing,
+            slow_acquire: Duration::from_millis(config.server.slow_acquire_ms),
+            record_acquire: None,
+        }
+    }
+
+    /// the format postgres sends values in. the simple protocol sends text and the
+    /// extended protocol, which binds the parameters, sends binary
+    pub fn value_format(&self) -> PgValueFormat {
+        if self.simple_protocol {
+            PgValueFormat::Text
+        } else {
+            PgValueFormat::Binary
+        }
+    }
+}
+
+/// a statement that sets every claim as the transaction local setting `justsql.<claim>`.
+/// strings are set as is, nulls as empty strings and everything else as json. claims
+/// whose names can not be setting names are skipped. the values are inlined so the
+/// statement runs over both the simple and extended query protocols.
+pub fn set_local_claims_statement(claims: &BTreeMap<String, Binding>) -> anyhow::Result<String> {
+    let settings = claims
+        .iter()
+        .filter(|(key, _)| {
+            let valid = !key.is_empty()
+                && key
+                    .chars()
+                    .all(|chr| chr.is_ascii_alphanumeric() || chr == '_');
+            if !valid {
+                debug!("claim {} is not a valid setting name", key);
+            }
+            valid
+        })
+        .map(|(key, value)| {
+            let value = match value {
+                Binding::String(string) => string.clone(),
+                Binding::Null => String::new(),
+                value => match value.to_json()? {
+                    Binding::Json(json) => json.to_string(),
+                    _ => Err(anyhow!("claim {} can not be set", key))?,
+                },
+            };
+            Ok(format!(
+                "set_config({}, {}, true)",
+                Binding::String(format!("justsql.{}", key)).to_sql_string()?,
+                Binding::String(value).to_sql_string()?
+            ))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(format!("SELECT {}", settings.join(", ")))
+}
+
+/// sets the claims for the rest of the transaction when `options.set_local_claims` is set
+pub async fn set_local_claims(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    claims: Option<&BTreeMap<String, Binding>>,
+    options: RunOptions,
+) -> anyhow::Result<()> {
+    match claims {
+        Some(claims) if options.set_local_claims && !claims.is_empty() => {
+            tx.execute(set_local_claims_statement(claims)?.as_str())
+                .await?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// runs a statement and fetches its rows when `fetch` is set. statements annotated with
+/// `-- @optional` run inside of a savepoint so that a failure only rolls back the
+/// statement itself and the transaction carries on as if it returned no rows
+async fn run_statement<'q, E>(
+    tx: &mut Transaction<'_, Postgres>,
+    statement: E,
+    idx: usize,
+    optional: bool,
+    fetch: bool,
+) -> anyhow::Result<Vec<PgRow>>
+where
+    E: 'q + Execute<'q, Postgres>,
+{
+    async fn fetch_rows<'q, E>(
+        tx: &mut Transaction<'_, Postgres>,
+        statement: E,
+        fetch: bool,
+    ) -> sqlx::Result<Vec<PgRow>>
+    where
+        E: 'q + Execute<'q, Postgres>,
+    {
+        if fetch {
+            tx.fetch_all(statement).await
+        } else {
+            tx.execute(statement).await.map(|_| vec![])
+        }
+    }
+
+    if !optional {
+        return Ok(fetch_rows(tx, statement, fetch).await?);
+    }
+    let mut savepoint = tx.begin().await?;
+    match fetch_rows(&mut savepoint, statement, fetch).await {
+        Ok(rows) => {
+            savepoint.commit().await?;
+            Ok(rows)
+        }
+        Err(err) => {
+            savepoint.rollback().await?;
+            info!("optional statement query_{} failed: {}", idx, err);
+            Ok(vec![])
+        }
+    }
+}
 
-    Ok(queries)
+/// the rows of a statement of a module, named after its position like `query_0`
+#[derive(Serialize)]
+pub struct StatementRows {
+    pub statement: String,
+    pub rows: Vec<Row>,
 }
 
 pub async fn run_query<I>(
@@ -226,38 +899,873 @@ pub async fn run_query<I>(
     pool: &PgPool,
     bindings: &BTreeMap<String, Binding>,
     auth_bindings: Option<&BTreeMap<String, Binding>>,
-    // whether to rollback the query at the end
-    rollback: bool,
-) -> anyhow::Result<Vec<BTreeMap<String, RowType>>>
+    options: RunOptions,
+) -> anyhow::Result<Vec<Row>>
 where
     I: Importer,
 {
+    let options = RunOptions {
+        all_statements: false,
+        ..options
+    };
+    let mut results =
+        run_statements(module, importer, pool, bindings, auth_bindings, options).await?;
+    Ok(results.pop().map(|result| result.rows).unwrap_or_default())
+}
+
+/// runs every statement of the module in one transaction. only the rows of the last
+/// statement, or the one annotated with `-- @returns_rows`, are collected unless
+/// `options.all_statements` is set
+pub async fn run_statements<I>(
+    module: &Module,
+    importer: &I,
+    pool: &PgPool,
+    bindings: &BTreeMap<String, Binding>,
+    auth_bindings: Option<&BTreeMap<String, Binding>>,
+    options: RunOptions,
+) -> anyhow::Result<Vec<StatementRows>>
+where
+    I: Importer,
+{
+    if !options.simple_protocol && is_autocommit(module, auth_bindings, options) {
+        return run_autocommit(module, importer, pool, bindings, auth_bindings, options).await;
+    }
     async {
-        let mut tx = pool.begin().await?;
-        let statements = evaluate(module, importer, bindings, auth_bindings)?;
-        let queries = build_queries(&statements)?;
-        let mut query: Option<sqlx::query::Query<Postgres, PgArguments>> = None;
-
-        for cur in queries {
-            if let Some(cur_query) = query {
-                cur_query.execute(&mut tx).await?;
-            }
-            query = Some(cur);
+        let mut tx = begin(pool, options.slow_acquire, options.record_acquire).await?;
+        set_local_claims(&mut tx, auth_bindings, options).await?;
+        let results =
+            run_in_transaction(&mut tx, module, importer, bindings, auth_bindings, options).await?;
+        if options.rollback {
+            tx.rollback().await?;
+        } else {
+            tx.commit().await?;
         }
+        Ok(results)
+    }
+    .await
+}
 
-        let query = query.ok_or_else(|| anyhow!("module at endpoint did not have any queries"))?;
-        let results = query
-            .fetch_all(&mut tx)
+/// runs every statement of the module inside of a transaction that the caller commits.
+/// only the rows of the last statement, or the one annotated with `-- @returns_rows`,
+/// are collected unless `options.all_statements` is set. with `options.simple_protocol`
+/// the bindings are inlined and the statements run over the simple query protocol, which
+/// works behind connection poolers like PgBouncer that do not support prepared statements
+pub async fn run_in_transaction<I>(
+    tx: &mut Transaction<'_, Postgres>,
+    module: &Module,
+    importer: &I,
+    bindings: &BTreeMap<String, Binding>,
+    auth_bindings: Option<&BTreeMap<String, Binding>>,
+    options: RunOptions,
+) -> anyhow::Result<Vec<StatementRows>>
+where
+    I: Importer,
+{
+    if options.simple_protocol {
+        let statements = evaluate_inline(
+            module,
+            importer,
+            bindings,
+            auth_bindings,
+            options.max_import_depth,
+            options.assume_null_if_missing,
+        )?;
+        let queries = statements.iter().map(String::as_str).collect();
+        return run_each_statement(tx, module, queries, options).await;
+    }
+    let statements = evaluate(
+        module,
+        importer,
+        bindings,
+        auth_bindings,
+        options.max_import_depth,
+        options.assume_null_if_missing,
+    )?;
+    let queries = build_queries(&statements)?;
+    run_each_statement(tx, module, queries, options).await
+}
+
+/// runs the evaluated statements of the module one after the other
+async fn run_each_statement<'q, E>(
+    tx: &mut Transaction<'_, Postgres>,
+    module: &Module,
+    queries: Vec<E>,
+    options: RunOptions,
+) -> anyhow::Result<Vec<StatementRows>>
+where
+    E: 'q + Execute<'q, Postgres>,
+{
+    if queries.is_empty() {
+        Err(anyhow!("module at endpoint did not have any queries"))?
+    }
+
+    let returned = module.returned_statement.unwrap_or(queries.len() - 1);
+    let mut results = vec![];
+    for (idx, query) in queries.into_iter().enumerate() {
+        let optional = module.optional_statements.contains(&idx);
+        if idx != returned && !options.all_statements {
+            run_statement(tx, query, idx, optional, false).await?;
+            continue;
+        }
+        let rows = run_statement(tx, query, idx, optional, true)
             .await?
             .into_iter()
-            .map(convert_row)
-            .collect::<anyhow::Result<Vec<BTreeMap<String, RowType>>>>()?;
-        if rollback {
+            .map(|row| convert_row(row, options.unknown_type_fallback, options.value_format()))
+            .collect::<anyhow::Result<Vec<Row>>>()?;
+        results.push(StatementRows {
+            statement: format!("query_{}", idx),
+            rows,
+        });
+    }
+    Ok(results)
+}
+
+/// sends every row through the channel and stops at the first row that fails to be
+/// fetched or converted so the caller never commits a partially streamed endpoint.
+/// returns whether every row was sent before the receiver hung up
+async fn forward_rows<R, S, F>(
+    rows: &mut S,
+    convert: F,
+    sender: &mut mpsc::Sender<anyhow::Result<Row>>,
+) -> anyhow::Result<bool>
+where
+    S: futures::Stream<Item = Result<R, sqlx::Error>> + Unpin,
+    F: Fn(R) -> anyhow::Result<Row>,
+{
+    while let Some(row) = rows.try_next().await? {
+        let row = convert(row)?;
+        if sender.send(Ok(row)).await.is_err() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// runs the statements in a transaction and sends the rows of the last statement
+/// through the channel as they arrive instead of collecting them. the transaction
+/// is committed once every row was sent and is rolled back if a row fails or the
+/// receiver hangs up.
+/// statements that are already inlined are run over the simple query protocol.
+pub async fn stream_rows(
+    pool: PgPool,
+    statements: Vec<(String, Vec<Binding>)>,
+    auth_bindings: Option<BTreeMap<String, Binding>>,
+    options: RunOptions,
+    mut sender: mpsc::Sender<anyhow::Result<Row>>,
+) {
+    let res: anyhow::Result<()> = async {
+        let mut tx = begin(&pool, options.slow_acquire, options.record_acquire).await?;
+        set_local_claims(&mut tx, auth_bindings.as_ref(), options).await?;
+        let statements: Vec<(String, Vec<Cow<Binding>>)> = statements
+            .iter()
+            .map(|(statement, bindings)| {
+                (
+                    statement.clone(),
+                    bindings.iter().map(Cow::Borrowed).collect(),
+                )
+            })
+            .collect();
+        let (last, rest) = statements
+            .split_last()
+            .ok_or_else(|| anyhow!("module at endpoint did not have any queries"))?;
+        let mut queries = build_queries(&statements)?;
+        let last_query = queries.pop();
+
+        {
+            let mut rows = if options.simple_protocol {
+                for (statement, _) in rest {
+                    tx.execute(statement.as_str()).await?;
+                }
+                tx.fetch(last.0.as_str())
+            } else {
+                for query in queries {
+                    query.execute(&mut tx).await?;
+                }
+                last_query
+                    .ok_or_else(|| anyhow!("module at endpoint did not have any queries"))?
+                    .fetch(&mut tx)
+            };
+            let (fallback, format) = (options.unknown_type_fallback, options.value_format());
+            if !forward_rows(
+                &mut rows,
+                |row| convert_row(row, fallback, format),
+                &mut sender,
+            )
+            .await?
+            {
+                // the client went away so there is no one left to commit for
+                return Ok(());
+            }
+        }
+
+        if options.rollback {
             tx.rollback().await?;
         } else {
             tx.commit().await?;
         }
-        Ok(results)
+        Ok(())
+    }
+    .await;
+
+    if let Err(err) = res {
+        let _ = sender.send(Err(err)).await;
+    }
+}
+
+/// takes a connection from the pool and starts a transaction on it. the wait is
+/// recorded and logged when it is slow so operators can tell an exhausted pool apart
+/// from slow queries
+pub async fn begin(
+    pool: &PgPool,
+    slow_acquire: Duration,
+    record: Option<AcquireRecorder>,
+) -> anyhow::Result<Transaction<'static, Postgres>> {
+    let started = Instant::now();
+    let tx = pool.begin().await?;
+    record_acquire(pool, started.elapsed(), slow_acquire, record);
+    Ok(tx)
+}
+
+/// like `begin` but without starting a transaction
+async fn acquire(
+    pool: &PgPool,
+    slow_acquire: Duration,
+    record: Option<AcquireRecorder>,
+) -> anyhow::Result<PoolConnection<Postgres>> {
+    let started = Instant::now();
+    let conn = pool.acquire().await?;
+    record_acquire(pool, started.elapsed(), slow_acquire, record);
+    Ok(conn)
+}
+
+fn record_acquire(
+    pool: &PgPool,
+    elapsed: Duration,
+    slow_acquire: Duration,
+    record: Option<AcquireRecorder>,
+) {
+    if let Some(record) = record {
+        record(elapsed);
+    }
+    if elapsed > slow_acquire {
+        // connections can be returned between reading the size and the idle count
+        warn!(
+            "waited {}ms for a database connection, {} of {} connections are in use",
+            elapsed.as_millis(),
+            (pool.size() as usize).saturating_sub(pool.num_idle()),
+            pool.size()
+        );
+    }
+}
+
+/// a module with a single read only statement does not need a transaction, which saves
+/// the round trips for BEGIN and COMMIT. the transaction is still needed to roll back,
+/// to set the claims for the statement and for the savepoint of an optional statement
+fn is_autocommit(
+    module: &Module,
+    auth_bindings: Option<&BTreeMap<String, Binding>>,
+    options: RunOptions,
+) -> bool {
+    let sets_claims =
+        options.set_local_claims && auth_bindings.is_some_and(|claims| !claims.is_empty());
+    module.sql.len() == 1
+        && module.front_matter.read_only
+        && module.optional_statements.is_empty()
+        && !options.rollback
+        && !sets_claims
+}
+
+/// runs the only statement of a read only module directly on a pooled connection
+async fn run_autocommit<I>(
+    module: &Module,
+    importer: &I,
+    pool: &PgPool,
+    bindings: &BTreeMap<String, Binding>,
+    auth_bindings: Option<&BTreeMap<String, Binding>>,
+    options: RunOptions,
+) -> anyhow::Result<Vec<StatementRows>>
+where
+    I: Importer,
+{
+    let mut conn = acquire(pool, options.slow_acquire, options.record_acquire).await?;
+    let statements = evaluate(
+        module,
+        importer,
+        bindings,
+        auth_bindings,
+        options.max_import_depth,
+        options.assume_null_if_missing,
+    )?;
+    let query = build_queries(&statements)?
+        .pop()
+        .ok_or_else(|| anyhow!("module at endpoint did not have any queries"))?;
+    let rows = query
+        .fetch_all(&mut conn)
+        .await?
+        .into_iter()
+        .map(|row| convert_row(row, options.unknown_type_fallback, options.value_format()))
+        .collect::<anyhow::Result<Vec<Row>>>()?;
+    Ok(vec![StatementRows {
+        statement: "query_0".to_string(),
+        rows,
+    }])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{binding::Payload, util::temp_dir::temp_dir};
+
+    #[test]
+    fn evaluate_inline_test() {
+        let module = Module::from_str(
+            std::path::PathBuf::new(),
+            "-- @param name\nselect @name as name, '$1' as literal, @name as again",
+        )
+        .unwrap();
+        let importer = crate::engine::UpfrontImporter::from_paths(&[], false).unwrap();
+        let mut bindings = BTreeMap::new();
+        bindings.insert("name".to_string(), Binding::String("O'Brien".into()));
+        let statements = evaluate_inline(
+            &module,
+            &importer,
+            &bindings,
+            None,
+            DEFAULT_MAX_IMPORT_DEPTH,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            statements,
+            vec!["select 'O''Brien' as name, '$1' as literal, 'O''Brien' as again".to_string()]
+        );
+    }
+
+    #[test]
+    fn evaluate_inline_negative_test() {
+        let module = Module::from_str(
+            std::path::PathBuf::new(),
+            "-- @param x\n-- @param t\nselect * from items where 10-@x = -@x and tenant = @t",
+        )
+        .unwrap();
+        let importer = crate::engine::UpfrontImporter::from_paths(&[], false).unwrap();
+        let evaluate = |x: Binding| {
+            let mut bindings = BTreeMap::new();
+            bindings.insert("x".to_string(), x);
+            bindings.insert("t".to_string(), Binding::Int(7));
+            evaluate_inline(
+                &module,
+                &importer,
+                &bindings,
+                None,
+                DEFAULT_MAX_IMPORT_DEPTH,
+                false,
+            )
+            .unwrap()
+        };
+        // a bare negative number would start a `--` comment and drop the tenant filter
+        assert_eq!(
+            evaluate(Binding::Int(-5)),
+            vec!["select * from items where 10-(-5) = -(-5) and tenant = (7)".to_string()]
+        );
+        assert_eq!(
+            evaluate(Binding::Float(-2.5)),
+            vec!["select * from items where 10-(-2.5) = -(-2.5) and tenant = (7)".to_string()]
+        );
+    }
+
+    #[test]
+    fn negative_literal_argument_test() {
+        let directory = temp_dir("negative_literal_argument_test");
+        std::fs::write(
+            directory.join("inner.sql"),
+            "-- @param n\nselect 10-@n as v",
+        )
+        .unwrap();
+        let outer = directory.join("outer.sql");
+        std::fs::write(
+            &outer,
+            "-- @import inner from './inner.sql'\nselect * from @inner(-1) as inner where v = @inner(2.5).v or v = @inner('a').v",
+        )
+        .unwrap();
+        let importer =
+            crate::engine::UpfrontImporter::from_paths(&[outer.as_path()], false).unwrap();
+        let module = importer.get_module_from_location(outer.as_path()).unwrap();
+        let (statement, _) = build_query_statement(
+            &module,
+            &importer,
+            module.sql[0].as_slice(),
+            None,
+            DEFAULT_MAX_IMPORT_DEPTH,
+        )
+        .unwrap();
+        // a bare -1 would start a `--` comment that drops the rest of the line
+        assert!(statement.contains("select 10-(-1) as v"), "{}", statement);
+        assert!(statement.contains("select 10-(2.5) as v"), "{}", statement);
+        assert!(statement.contains("select 10-'a' as v"), "{}", statement);
+    }
+
+    #[test]
+    fn build_query_statement_test() {
+        let importer = crate::engine::UpfrontImporter::from_paths(&[], false).unwrap();
+        let build = |sql: &str| {
+            let module = Module::from_str(std::path::PathBuf::new(), sql).unwrap();
+            let statement = module.sql[0].clone();
+            build_query_statement(
+                &module,
+                &importer,
+                statement.as_slice(),
+                None,
+                DEFAULT_MAX_IMPORT_DEPTH,
+            )
+            .unwrap()
+        };
+        let param = |name: &str| ParamType::Param(name.to_string());
+
+        // placeholders are numbered by first use, not by declaration order
+        assert_eq!(
+            build("-- @param a, b\nselect @b, @a"),
+            ("select $1, $2".to_string(), vec![param("b"), param("a")])
+        );
+
+        // a reused parameter keeps its placeholder
+        assert_eq!(
+            build("-- @param a, b\nselect @a, @b, @a"),
+            (
+                "select $1, $2, $1".to_string(),
+                vec![param("a"), param("b")]
+            )
+        );
+
+        assert_eq!(
+            build("-- @auth verify\n-- @param a\nselect @auth.id, @a, @auth.id"),
+            (
+                "select $1, $2, $1".to_string(),
+                vec![ParamType::Auth("id".to_string()), param("a")]
+            )
+        );
+    }
+
+    #[test]
+    fn max_import_depth_test() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("examples/last_active_users.sql")
+            .canonicalize()
+            .unwrap();
+        let importer =
+            crate::engine::UpfrontImporter::from_paths(&[path.as_path()], false).unwrap();
+        let module = importer.get_module_from_location(path.as_path()).unwrap();
+        let build = |max_import_depth| {
+            build_query_statement(
+                &module,
+                &importer,
+                module.sql[0].as_slice(),
+                None,
+                max_import_depth,
+            )
+        };
+
+        assert!(build(1).is_ok());
+        assert_eq!(
+            build(0).unwrap_err().to_string(),
+            "imports are nested more than 0 deep: all_users"
+        );
+    }
+
+    #[test]
+    fn statement_cache_test() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("examples/last_active_users.sql")
+            .canonicalize()
+            .unwrap();
+        let importer =
+            crate::engine::UpfrontImporter::from_paths(&[path.as_path()], false).unwrap();
+        let cache = importer.statement_cache().unwrap();
+        let module = importer.get_module_from_location(path.as_path()).unwrap();
+        let bindings = BTreeMap::new();
+        let evaluate = |module: &Module| {
+            evaluate(
+                module,
+                &importer,
+                &bindings,
+                None,
+                DEFAULT_MAX_IMPORT_DEPTH,
+                false,
+            )
+            .unwrap()
+        };
+
+        let first = evaluate(&module);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(evaluate(&module), first);
+        assert_eq!(cache.len(), 1);
+
+        // modules the importer does not hold are never cached
+        let outside = Module::from_str(path.clone(), "select 1").unwrap();
+        evaluate(&outside);
+        assert_eq!(cache.len(), 1);
+
+        // any change to the collection clears it
+        let mut collection = crate::engine::ModuleCollection {
+            statements: cache.clone(),
+            ..Default::default()
+        };
+        collection.remove(path.as_path()).unwrap();
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn auth_claims_cache_test() {
+        let cache = StatementCache::default();
+        let collected = std::cell::Cell::new(0);
+        let collect = || {
+            collected.set(collected.get() + 1);
+            Ok(Arc::new(std::iter::once("id".to_string()).collect()))
+        };
+
+        let claims = cache.auth_claims(collect).unwrap();
+        assert_eq!(cache.auth_claims(collect).unwrap(), claims);
+        assert_eq!(collected.get(), 1);
+
+        // a change to the modules collects them again
+        cache.clear();
+        cache.auth_claims(collect).unwrap();
+        assert_eq!(collected.get(), 2);
+    }
+
+    #[test]
+    fn conditional_test() {
+        let module = Module::from_str(
+            std::path::PathBuf::new(),
+            "-- @param id, status\nselect * from users where id = @id @if(status) and status = @status @endif",
+        )
+        .unwrap();
+        let importer = crate::engine::UpfrontImporter::from_paths(&[], false).unwrap();
+
+        let bindings: BTreeMap<String, Binding> = serde_json::from_str(r#"{"id": 1}"#).unwrap();
+        let statements = evaluate(
+            &module,
+            &importer,
+            &bindings,
+            None,
+            DEFAULT_MAX_IMPORT_DEPTH,
+            false,
+        )
+        .unwrap();
+        assert_eq!(statements[0].0, "select * from users where id = $1 ");
+        assert_eq!(statements[0].1.len(), 1);
+
+        let bindings: BTreeMap<String, Binding> =
+            serde_json::from_str(r#"{"id": 1, "status": "active"}"#).unwrap();
+        let statements = evaluate(
+            &module,
+            &importer,
+            &bindings,
+            None,
+            DEFAULT_MAX_IMPORT_DEPTH,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            statements[0].0,
+            "select * from users where id = $1  and status = $2 "
+        );
+
+        // without a payload every fragment is kept
+        let (query, _) = build_query_statement(
+            &module,
+            &importer,
+            module.sql[0].as_slice(),
+            None,
+            DEFAULT_MAX_IMPORT_DEPTH,
+        )
+        .unwrap();
+        assert!(query.contains("status = $2"));
+    }
+
+    #[test]
+    fn positional_param_test() {
+        let module = Module::from_str(
+            std::path::PathBuf::new(),
+            "select * from users where id = @2 and name = @1 or id = @2",
+        )
+        .unwrap();
+        let importer = crate::engine::UpfrontImporter::from_paths(&[], false).unwrap();
+        let Payload(bindings) = serde_json::from_str(r#"["ann", 3]"#).unwrap();
+        let statements = evaluate(
+            &module,
+            &importer,
+            &bindings,
+            None,
+            DEFAULT_MAX_IMPORT_DEPTH,
+            false,
+        )
+        .unwrap();
+        let (query, bound) = &statements[0];
+        assert_eq!(
+            query,
+            "select * from users where id = $1 and name = $2 or id = $1"
+        );
+        assert_eq!(
+            bound.iter().map(Cow::as_ref).collect::<Vec<_>>(),
+            vec![&Binding::Int(3), &Binding::String("ann".into())]
+        );
+
+        let Payload(bindings) = serde_json::from_str(r#"["ann"]"#).unwrap();
+        let err = evaluate(
+            &module,
+            &importer,
+            &bindings,
+            None,
+            DEFAULT_MAX_IMPORT_DEPTH,
+            false,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "positional parameter @2 does not exist");
+    }
+
+    #[test]
+    fn json_param_test() {
+        let module = Module::from_str(
+            std::path::PathBuf::new(),
+            "-- @param filter: json, id\nselect * from events where data @> @filter and id = ANY(@id)",
+        )
+        .unwrap();
+        let importer = crate::engine::UpfrontImporter::from_paths(&[], false).unwrap();
+        let bindings: BTreeMap<String, Binding> =
+            serde_json::from_str(r#"{"filter": [1, 2], "id": [1, 2]}"#).unwrap();
+        let statements = evaluate(
+            &module,
+            &importer,
+            &bindings,
+            None,
+            DEFAULT_MAX_IMPORT_DEPTH,
+            false,
+        )
+        .unwrap();
+        let (query, bound) = &statements[0];
+        assert_eq!(
+            query,
+            "select * from events where data @> $1 and id = ANY($2)"
+        );
+        // the json parameter stays a single jsonb value rather than a sql array
+        assert_eq!(
+            bound.iter().map(Cow::as_ref).collect::<Vec<_>>(),
+            vec![
+                &Binding::Json(serde_json::json!([1, 2])),
+                &Binding::Array(vec![Binding::Int(1), Binding::Int(2)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn set_local_claims_statement_test() {
+        let claims: BTreeMap<String, Binding> = serde_json::from_str(
+            r#"{"user_id": 1, "name": "O'Brien", "org": {"id": 2}, "team": null, "bad-key": 3}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            set_local_claims_statement(&claims).unwrap(),
+            "SELECT set_config('justsql.name', 'O''Brien', true), \
+             set_config('justsql.org', '{\"id\":2}', true), \
+             set_config('justsql.team', '', true), \
+             set_config('justsql.user_id', '1', true)"
+        );
+    }
+
+    #[test]
+    fn bind_auth_path_test() {
+        let claims: BTreeMap<String, Binding> =
+            serde_json::from_str(r#"{"user_id": 1, "org": {"id": 2, "name": "acme"}}"#).unwrap();
+        let bindings = BTreeMap::new();
+        let params = vec![
+            ParamType::Auth("user_id".into()),
+            ParamType::Auth("org.id".into()),
+            ParamType::Auth("org.name".into()),
+        ];
+        let bound = bind_params(params.as_slice(), &bindings, Some(&claims), false).unwrap();
+        assert_eq!(
+            bound.iter().map(Cow::as_ref).collect::<Vec<_>>(),
+            vec![
+                &Binding::Int(1),
+                &Binding::Int(2),
+                &Binding::String("acme".into())
+            ]
+        );
+
+        for missing in &["org.missing", "user_id.id", "missing"] {
+            let err = bind_params(
+                &[ParamType::Auth(missing.to_string())],
+                &bindings,
+                Some(&claims),
+                false,
+            )
+            .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("auth claim {} does not exist", missing)
+            );
+        }
+    }
+
+    #[test]
+    fn foreach_test() {
+        let module = Module::from_str(
+            std::path::PathBuf::new(),
+            "-- @param rows\ninsert into users (email, name) values @foreach(rows) (@rows.email, @rows.name) @endforeach",
+        )
+        .unwrap();
+        let importer = crate::engine::UpfrontImporter::from_paths(&[], false).unwrap();
+        let evaluate = |payload: &str| {
+            let bindings: BTreeMap<String, Binding> = serde_json::from_str(payload).unwrap();
+            evaluate(
+                &module,
+                &importer,
+                &bindings,
+                None,
+                DEFAULT_MAX_IMPORT_DEPTH,
+                false,
+            )
+            .map(|statements| {
+                let (query, bindings) = &statements[0];
+                let bindings: Vec<_> = bindings
+                    .iter()
+                    .map(|binding| binding.as_ref().clone())
+                    .collect();
+                (query.clone(), bindings)
+            })
+        };
+
+        let (query, bindings) = evaluate(
+            r#"{"rows": [{"email": "a@b.c", "name": "a"}, {"email": "d@e.f", "name": null}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            "insert into users (email, name) values  ($1, $2) ,  ($3, $4) "
+        );
+        assert_eq!(
+            bindings,
+            vec![
+                Binding::String("a@b.c".into()),
+                Binding::String("a".into()),
+                Binding::String("d@e.f".into()),
+                Binding::Null,
+            ]
+        );
+
+        // rows must be a non empty array of objects that have every field
+        for payload in &[
+            r#"{"rows": []}"#,
+            r#"{"rows": [1, 2]}"#,
+            r#"{"rows": {"email": "a@b.c", "name": "a"}}"#,
+            r#"{"rows": [{"email": "a@b.c"}]}"#,
+        ] {
+            let err = evaluate(payload).unwrap_err();
+            assert!(
+                err.downcast_ref::<MissingBinding>().is_some(),
+                "{}",
+                payload
+            );
+        }
+        let rows = vec![serde_json::json!({"email": "a@b.c", "name": "a"}); MAX_FOREACH_ROWS + 1];
+        let payload = serde_json::json!({ "rows": rows }).to_string();
+        assert!(evaluate(payload.as_str()).is_err());
+    }
+
+    #[test]
+    fn autocommit_test() {
+        let options = RunOptions {
+            rollback: false,
+            simple_protocol: false,
+            unknown_type_fallback: UnknownTypeFallback::Error,
+            all_statements: false,
+            set_local_claims: true,
+            max_import_depth: DEFAULT_MAX_IMPORT_DEPTH,
+            assume_null_if_missing: false,
+            slow_acquire: Duration::from_secs(1),
+            record_acquire: None,
+        };
+        let module = |sql: &str| Module::from_str(std::path::PathBuf::new(), sql).unwrap();
+        let mut claims = BTreeMap::new();
+
+        assert!(is_autocommit(&module("select 1"), None, options));
+        assert!(is_autocommit(&module("select 1"), Some(&claims), options));
+        assert!(!is_autocommit(&module("select 1; select 2"), None, options));
+        assert!(!is_autocommit(&module("delete from users"), None, options));
+        assert!(!is_autocommit(
+            &module("-- @optional\nselect 1"),
+            None,
+            options
+        ));
+        let rollback = RunOptions {
+            rollback: true,
+            ..options
+        };
+        assert!(!is_autocommit(&module("select 1"), None, rollback));
+
+        // the claims are only set inside of a transaction
+        claims.insert("sub".to_string(), Binding::Int(1));
+        assert!(!is_autocommit(&module("select 1"), Some(&claims), options));
+    }
+
+    #[test]
+    fn assume_null_if_missing_test() {
+        let mut bindings = BTreeMap::new();
+        bindings.insert("id".to_string(), Binding::Int(1));
+        let params = [
+            ParamType::Param("id".into()),
+            ParamType::Param("name".into()),
+            ParamType::Positional(2),
+            ParamType::Json("tags".into()),
+        ];
+        assert!(bind_params(&params, &bindings, None, false).is_err());
+
+        // the payload is used first and only the missing params become null
+        let bound = bind_params(&params, &bindings, None, true).unwrap();
+        assert_eq!(
+            bound.iter().map(Cow::as_ref).collect::<Vec<_>>(),
+            vec![
+                &Binding::Int(1),
+                &Binding::Null,
+                &Binding::Null,
+                &Binding::Null
+            ]
+        );
+
+        // auth claims are never assumed
+        assert!(bind_params(&[ParamType::Auth("id".into())], &bindings, None, true).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn forward_rows_test() {
+        use crate::row_type::{Category, RowType};
+        use futures::StreamExt;
+
+        let convert = |id: i64| -> anyhow::Result<Row> {
+            if id == 2 {
+                Err(anyhow!("could not convert row {}", id))?
+            }
+            let mut row = Row::new();
+            row.insert("id".to_string(), RowType::Int8(Category::Value(Some(id))));
+            Ok(row)
+        };
+        let (mut sender, receiver) = mpsc::channel(8);
+        let mut rows = futures::stream::iter(vec![Ok(1), Ok(2), Ok(3)]);
+
+        // the rows after a failed one are never sent and the caller does not commit
+        let err = forward_rows(&mut rows, convert, &mut sender)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "could not convert row 2");
+        drop(sender);
+        let sent: Vec<_> = receiver.collect().await;
+        assert_eq!(sent.len(), 1);
+
+        let (mut sender, receiver) = mpsc::channel(8);
+        let mut rows = futures::stream::iter(vec![Ok(1), Ok(3)]);
+        assert!(forward_rows(&mut rows, convert, &mut sender).await.unwrap());
+        drop(sender);
+        assert_eq!(receiver.collect::<Vec<_>>().await.len(), 2);
     }
-    .await
 }
@@ -1,9 +1,12 @@
-use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
-use serde::Serialize;
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize, Serializer};
 use serde_json::Value;
-use sqlx::{postgres::PgValueRef, Decode, Postgres, Type, ValueRef};
-use sqlx::{Column, Row, TypeInfo};
-use std::collections::BTreeMap;
+use sqlx::{
+    postgres::{types::PgTimeTz, PgTypeKind, PgValueFormat, PgValueRef},
+    Decode, Postgres, Type, ValueRef,
+};
+use sqlx::{Column, Row as _, TypeInfo};
 
 // bool	BOOL
 // i8	  CHAR
@@ -48,11 +51,52 @@ pub enum Category<T> {
     Array(Option<Vec<Option<T>>>),
 }
 
+impl<T> Category<T> {
+    fn map<U>(self, f: impl Fn(T) -> U) -> Category<U> {
+        match self {
+            Category::Value(value) => Category::Value(value.map(f)),
+            Category::Array(array) => {
+                Category::Array(array.map(|array| array.into_iter().map(|v| v.map(&f)).collect()))
+            }
+        }
+    }
+}
+
+/// a time of day with its utc offset, serialized like `13:45:00.5+02:00`
+#[derive(Clone, PartialEq)]
+pub struct TimeTz(PgTimeTz<NaiveTime, FixedOffset>);
+
+impl Serialize for TimeTz {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!(
+            "{}{}",
+            self.0.time.format("%H:%M:%S%.f"),
+            self.0.offset
+        ))
+    }
+}
+
+/// what to return for columns of a type that can not be converted yet
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownTypeFallback {
+    #[default]
+    Error,
+    /// decodes the raw value as text. with the extended protocol postgres sends values
+    /// in their binary form, which is only text for types like citext or xml. other
+    /// types fail unless they are cast to text or the simple protocol is used
+    String,
+    Null,
+}
+
+/// a result row with its columns in the order they were selected
+pub type Row = IndexMap<String, RowType>;
+
 #[derive(Clone, PartialEq, Serialize)]
 #[serde(untagged)]
 pub enum RowType {
     Bool(Category<bool>),
-    Bytea(Category<Vec<u8>>),
+    Bytea(#[serde(serialize_with = "serialize_bytea")] Category<Vec<u8>>),
     Char(Category<i8>),
     Name(Category<String>),
     Int8(Category<i64>),
@@ -82,13 +126,15 @@ pub enum RowType {
     Timestamp(Category<NaiveDateTime>),
     Timestamptz(Category<DateTime<Utc>>),
     // Interval,
-    // Timetz,
+    Timetz(Category<TimeTz>),
     // Bit,
     // Varbit,
     // Numeric,
     // Record,
     Uuid(Category<uuid::Uuid>),
     Jsonb(Category<Value>),
+    /// user defined enums, sent as their labels
+    Enum(Category<String>),
     // Int4Range(),
     // NumRange,
     // TsRange,
@@ -99,6 +145,25 @@ pub enum RowType {
     // Money,
 }
 
+/// bytea is sent as base64 strings instead of arrays of numbers
+fn serialize_bytea<S>(value: &Category<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match value {
+        Category::Value(bytes) => bytes.as_ref().map(base64::encode).serialize(serializer),
+        Category::Array(array) => array
+            .as_ref()
+            .map(|array| {
+                array
+                    .iter()
+                    .map(|bytes| bytes.as_ref().map(base64::encode))
+                    .collect::<Vec<_>>()
+            })
+            .serialize(serializer),
+    }
+}
+
 fn try_get<'r, T>(value: PgValueRef<'r>) -> anyhow::Result<T>
 where
     T: Decode<'r, Postgres> + Type<Postgres>,
@@ -120,23 +185,63 @@ where
     })
 }
 
-pub fn convert_row(row: sqlx::postgres::PgRow) -> anyhow::Result<BTreeMap<String, RowType>> {
-    let map = row
-        .columns()
-        .iter()
-        .map(|col| -> anyhow::Result<_> {
-            let name = col.name();
-            let value_ref = row.try_get_raw(col.ordinal()).map_err(|err| {
-                anyhow!("could not get column {} due to {}", name, err.to_string())
-            })?;
+/// decodes the raw value as text without checking the column type first
+fn try_get_text(value: PgValueRef) -> anyhow::Result<Option<String>> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    <&str as Decode<Postgres>>::decode(value)
+        .map(|text| Some(text.to_string()))
+        .map_err(|err| anyhow!("failed to decode as text due to {}", err))
+}
 
-            Ok((name.to_string(), convert_value(value_ref)?))
-        })
-        .collect::<anyhow::Result<BTreeMap<_, _>>>()?;
+/// the name of a custom type that was not looked up
+const UNRESOLVED_TYPE: &str = "?";
+
+/// types whose binary form is the same as their text form
+const TEXT_BINARY_TYPES: [&str; 2] = ["citext", "xml"];
+
+/// whether a value of an unknown type can be read as text. values are text over the
+/// simple protocol, and in binary over the extended protocol where most types, like
+/// numeric or arrays, are not text
+fn readable_as_text(type_name: &str, format: PgValueFormat) -> bool {
+    format == PgValueFormat::Text || TEXT_BINARY_TYPES.contains(&type_name)
+}
+
+/// converts the columns of a row whose values were sent in the given format
+pub fn convert_row(
+    row: sqlx::postgres::PgRow,
+    fallback: UnknownTypeFallback,
+    format: PgValueFormat,
+) -> anyhow::Result<Row> {
+    let mut map = Row::with_capacity(row.columns().len());
+    for col in row.columns() {
+        let name = col.name();
+        let value_ref = row
+            .try_get_raw(col.ordinal())
+            .map_err(|err| anyhow!("could not get column {} due to {}", name, err.to_string()))?;
+        insert_column(&mut map, name, convert_value(value_ref, fallback, format)?)?;
+    }
     Ok(map)
 }
 
-fn convert_value(value_ref: PgValueRef) -> anyhow::Result<RowType> {
+/// adds a column to the row, failing instead of overwriting a column of the same name
+fn insert_column(row: &mut Row, name: &str, value: RowType) -> anyhow::Result<()> {
+    if row.contains_key(name) {
+        Err(anyhow!(
+            "column {} is selected more than once, alias the columns so that each name is unique",
+            name
+        ))?
+    }
+    row.insert(name.to_string(), value);
+    Ok(())
+}
+
+fn convert_value(
+    value_ref: PgValueRef,
+    fallback: UnknownTypeFallback,
+    format: PgValueFormat,
+) -> anyhow::Result<RowType> {
     use Category::{Array, Value};
     let type_info = value_ref.type_info();
     let row_type: RowType = match type_info.name() {
@@ -172,6 +277,8 @@ fn convert_value(value_ref: PgValueRef) -> anyhow::Result<RowType> {
         "TIMESTAMP[]" => RowType::Timestamp(Array(try_get(value_ref)?)),
         "TIMESTAMPTZ" => RowType::Timestamptz(Value(try_get(value_ref)?)),
         "TIMESTAMPTZ[]" => RowType::Timestamptz(Array(try_get(value_ref)?)),
+        "TIMETZ" => RowType::Timetz(Value::<PgTimeTz>(try_get(value_ref)?).map(TimeTz)),
+        "TIMETZ[]" => RowType::Timetz(Array::<PgTimeTz>(try_get(value_ref)?).map(TimeTz)),
         "UUID" => RowType::Uuid(Value(try_get(value_ref)?)),
         "UUID[]" => RowType::Uuid(Array(try_get(value_ref)?)),
         "VARCHAR" => RowType::Varchar(Value(try_get(value_ref)?)),
@@ -200,17 +307,40 @@ fn convert_value(value_ref: PgValueRef) -> anyhow::Result<RowType> {
         // "POINT" => {},
         // "POLYGON" => {},
         // "RECORD" => {},
-        // "TIMETZ" => {},
         // "TSRANGE" => {},
         // "TSTZRANGE" => {},
         // "VARBIT" => {},
         // "OID" => {},
         // "VOID" => {},
         // "UNKNOWN" => {},
-        _ => Err(anyhow!(
-            "type parsing for {} is not implemented yet",
-            type_info.name()
-        ))?,
+        // enums are named after the user's type, their values are decoded as the label text.
+        // over the simple protocol custom types are not looked up, they are named `?` and
+        // asking for their kind panics, so they go through the fallback
+        _ if type_info.name() != UNRESOLVED_TYPE
+            && matches!(type_info.kind(), PgTypeKind::Enum(_)) =>
+        {
+            RowType::Enum(Value(try_get_text(value_ref.clone()).map_err(|_| {
+                anyhow!("could not decode {} as text", type_info.name())
+            })?))
+        }
+        _ => match fallback {
+            UnknownTypeFallback::Error => Err(anyhow!(
+                "type parsing for {} is not implemented yet",
+                type_info.name()
+            ))?,
+            UnknownTypeFallback::String if !readable_as_text(type_info.name(), format) => {
+                Err(anyhow!(
+                    "{} is sent in binary and can not be read as text, cast it to text or set database.simple_protocol",
+                    type_info.name()
+                ))?
+            }
+            UnknownTypeFallback::String => {
+                RowType::Text(Value(try_get_text(value_ref.clone()).map_err(|_| {
+                    anyhow!("could not decode {} as text", type_info.name())
+                })?))
+            }
+            UnknownTypeFallback::Null => RowType::Text(Value(None)),
+        },
     };
 
     Ok(row_type)
@@ -331,4 +461,58 @@ mod tests {
             Some(r#""2023-11-14T22:13:20Z""#.to_string())
         );
     }
+
+    #[test]
+    fn duplicate_column_test() {
+        // the columns of `select a.id, b.id from users a join users b on a.manager = b.id`
+        let mut row = Row::new();
+        insert_column(&mut row, "id", RowType::Int4(Category::Value(Some(1)))).unwrap();
+        let err =
+            insert_column(&mut row, "id", RowType::Int4(Category::Value(Some(2)))).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("column id is selected more than once"));
+        assert_eq!(row.len(), 1);
+    }
+
+    #[test]
+    fn timetz_test() {
+        let time = TimeTz(PgTimeTz {
+            time: NaiveTime::from_hms_milli(13, 45, 0, 500),
+            offset: FixedOffset::east(2 * 3600),
+        });
+        assert_eq!(
+            serde_json::to_string(&RowType::Timetz(Category::Value(Some(time)))).unwrap(),
+            r#""13:45:00.500+02:00""#
+        );
+    }
+
+    #[test]
+    fn readable_as_text_test() {
+        assert!(readable_as_text("NUMERIC", PgValueFormat::Text));
+        assert!(readable_as_text("citext", PgValueFormat::Binary));
+        // binary numerics and arrays would be decoded as garbage
+        assert!(!readable_as_text("NUMERIC", PgValueFormat::Binary));
+        assert!(!readable_as_text("_mood", PgValueFormat::Binary));
+    }
+
+    #[test]
+    fn bytea_test() {
+        let bytes = vec![0u8, 1, 2, 254, 255];
+        let value =
+            serde_json::to_value(RowType::Bytea(Category::Value(Some(bytes.clone())))).unwrap();
+        let encoded = value.as_str().unwrap();
+        assert_eq!(encoded, "AAEC/v8=");
+        assert_eq!(base64::decode(encoded).unwrap(), bytes);
+
+        let array = RowType::Bytea(Category::Array(Some(vec![Some(bytes), None])));
+        assert_eq!(
+            serde_json::to_string(&array).unwrap(),
+            r#"["AAEC/v8=",null]"#
+        );
+        assert_eq!(
+            serde_json::to_string(&RowType::Bytea(Category::Value(None))).unwrap(),
+            "null"
+        );
+    }
 }
@@ -0,0 +1,145 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use crate::{binding::Binding, config::Server};
+
+const REDACTED: &str = "[redacted]";
+
+/// the bindings as a json object
+pub fn to_json(bindings: &BTreeMap<String, Binding>) -> Value {
+    Value::Object(
+        bindings
+            .iter()
+            .map(|(key, binding)| (key.clone(), binding.to_json_value().unwrap_or(Value::Null)))
+            .collect(),
+    )
+}
+
+/// the string and number values inside of the claims, nested claims included.
+/// these are replaced wherever they show up in a logged body
+pub fn claim_values(claims: &Value) -> Vec<Value> {
+    match claims {
+        Value::Array(values) => values.iter().flat_map(claim_values).collect(),
+        Value::Object(values) => values.values().flat_map(claim_values).collect(),
+        Value::String(_) | Value::Number(_) => vec![claims.clone()],
+        Value::Null | Value::Bool(_) => vec![],
+    }
+}
+
+fn redact(value: Value, secrets: &[Value]) -> Value {
+    match value {
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(|value| redact(value, secrets))
+                .collect(),
+        ),
+        Value::Object(values) => Value::Object(
+            values
+                .into_iter()
+                .map(|(key, value)| (key, redact(value, secrets)))
+                .collect(),
+        ),
+        value if secrets.contains(&value) => Value::String(REDACTED.to_string()),
+        value => value,
+    }
+}
+
+/// every value of the body replaced, only its shape is kept. used for the payloads of
+/// auth endpoints since those hold credentials such as passwords instead of claims
+pub fn redact_all(value: Value) -> Value {
+    match value {
+        Value::Array(values) => Value::Array(values.into_iter().map(redact_all).collect()),
+        Value::Object(values) => Value::Object(
+            values
+                .into_iter()
+                .map(|(key, value)| (key, redact_all(value)))
+                .collect(),
+        ),
+        Value::Null => Value::Null,
+        _ => Value::String(REDACTED.to_string()),
+    }
+}
+
+/// cuts the text off after `max_len` characters
+fn truncate(text: String, max_len: usize) -> String {
+    match text.char_indices().nth(max_len) {
+        Some((end, _)) => format!(
+            "{}... ({} more characters)",
+            &text[..end],
+            text[end..].chars().count()
+        ),
+        None => text,
+    }
+}
+
+/// logs a payload or result when `server.log_bodies` is set. every value of the
+/// request's claims is replaced, the cookie itself is never passed in here
+pub fn log_body<T: Serialize>(
+    server: &Server,
+    request_id: Uuid,
+    endpoint: &str,
+    kind: &str,
+    body: &T,
+    secrets: &[Value],
+) {
+    if !server.log_bodies {
+        return;
+    }
+    let body = match serde_json::to_value(body) {
+        Ok(body) => redact(body, secrets).to_string(),
+        Err(err) => format!("<could not serialize: {}>", err),
+    };
+    info!(
+        "request {} {} of endpoint {}: {}",
+        request_id,
+        kind,
+        endpoint,
+        truncate(body, server.log_bodies_max_len)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn redact_test() {
+        let claims = json!({"id": 5, "email": "a@b.c", "admin": true, "org": {"id": "acme"}});
+        let secrets = claim_values(&claims);
+        assert_eq!(secrets.len(), 3);
+        assert_eq!(
+            redact(
+                json!([{"id": 5, "email": "a@b.c", "count": 6, "orgs": ["acme", "other"]}]),
+                &secrets
+            ),
+            json!([{"id": REDACTED, "email": REDACTED, "count": 6, "orgs": [REDACTED, "other"]}])
+        );
+    }
+
+    #[test]
+    fn redact_all_test() {
+        assert_eq!(
+            redact_all(
+                json!({"email": "a@b.c", "password": "hunter2", "remember": true, "codes": [1, null]})
+            ),
+            json!({"email": REDACTED, "password": REDACTED, "remember": REDACTED, "codes": [REDACTED, null]})
+        );
+    }
+
+    #[test]
+    fn truncate_test() {
+        assert_eq!(truncate("hello".to_string(), 5), "hello");
+        assert_eq!(
+            truncate("héllo".to_string(), 2),
+            "hé... (3 more characters)"
+        );
+        assert_eq!(
+            truncate("hééééé".to_string(), 1),
+            "h... (5 more characters)"
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use hashlink::LruCache;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{binding::Binding, row_type::Row};
+
+/// identifies a cached response. payload and claim maps are ordered so their
+/// debug form is a stable serialization
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    endpoint: String,
+    payload: String,
+    /// the claims of the token so that users never see each other's rows
+    subject: Option<String>,
+}
+
+impl CacheKey {
+    pub fn new(
+        endpoint: &str,
+        payload: &BTreeMap<String, Binding>,
+        auth_bindings: Option<&BTreeMap<String, Binding>>,
+    ) -> Self {
+        CacheKey {
+            endpoint: endpoint.to_string(),
+            payload: format!("{:?}", payload),
+            subject: auth_bindings.map(|claims| format!("{:?}", claims)),
+        }
+    }
+}
+
+/// when the rows expire and the rows themselves
+type CacheEntry = (Instant, Arc<Vec<Row>>);
+
+/// an in process lru cache of the rows returned by `@cache` endpoints
+pub struct ResponseCache {
+    entries: Mutex<LruCache<CacheKey, CacheEntry>>,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> Self {
+        ResponseCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// the cached rows unless they have expired
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<Vec<Row>>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+        match entries.get(key) {
+            Some((expires, rows)) if *expires > Instant::now() => return Some(rows.clone()),
+            Some(_) => {}
+            None => return None,
+        }
+        entries.remove(key);
+        None
+    }
+
+    pub fn insert(&self, key: CacheKey, rows: Arc<Vec<Row>>, ttl: Duration) {
+        let mut entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+        entries.insert(key, (Instant::now() + ttl, rows));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_cache_test() {
+        let cache = ResponseCache::new(1);
+        let payload = vec![("id".to_string(), Binding::Int(1))]
+            .into_iter()
+            .collect();
+        let claims = vec![("id".to_string(), Binding::Int(7))]
+            .into_iter()
+            .collect();
+        let key = CacheKey::new("users", &payload, Some(&claims));
+        let anonymous = CacheKey::new("users", &payload, None);
+
+        cache.insert(key.clone(), Arc::new(vec![]), Duration::from_secs(60));
+        assert!(cache.get(&key).is_some());
+        assert!(cache.get(&anonymous).is_none());
+
+        // the least recently used entry is evicted
+        cache.insert(anonymous.clone(), Arc::new(vec![]), Duration::from_secs(60));
+        assert!(cache.get(&key).is_none());
+
+        cache.insert(key.clone(), Arc::new(vec![]), Duration::from_secs(0));
+        assert!(cache.get(&key).is_none());
+    }
+}
@@ -0,0 +1,256 @@
+use actix_web::http::StatusCode;
+use sqlx::{error::DatabaseError, postgres::PgDatabaseError};
+use thiserror::Error;
+
+use crate::{codegen::MissingRole, query::MissingBinding};
+
+/// an error that is reported back to the client over http
+#[derive(Error, Debug)]
+pub enum RequestError {
+    /// the request itself is wrong, e.g. an unknown endpoint or a missing parameter
+    #[error(transparent)]
+    BadRequest(anyhow::Error),
+    /// the auth token is missing or could not be verified
+    #[error("authentication failed: {0}")]
+    Unauthorized(anyhow::Error),
+    /// the auth token is valid but does not hold a role the endpoint requires
+    #[error("forbidden: {0}")]
+    Forbidden(anyhow::Error),
+    /// the request conflicts with existing rows, i.e. a unique or foreign key violation
+    #[error(transparent)]
+    Conflict(anyhow::Error),
+    /// the server failed, e.g. the database is unreachable or a row could not be converted
+    #[error(transparent)]
+    Internal(anyhow::Error),
+}
+
+impl RequestError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            RequestError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            RequestError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            RequestError::Forbidden(_) => StatusCode::FORBIDDEN,
+            RequestError::Conflict(_) => StatusCode::CONFLICT,
+            RequestError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn inner(&self) -> &anyhow::Error {
+        match self {
+            RequestError::BadRequest(err)
+            | RequestError::Unauthorized(err)
+            | RequestError::Forbidden(err)
+            | RequestError::Conflict(err)
+            | RequestError::Internal(err) => err,
+        }
+    }
+
+    /// the SQLSTATE code of the postgres error that caused the request to fail, if any
+    pub fn error_code(&self) -> Option<String> {
+        sqlstate(self.inner())
+    }
+
+    /// the message sent to the client. unique and foreign key violations are replaced
+    /// with the endpoint's `@on_conflict` message where `{constraint}` is the name of
+    /// the violated constraint
+    pub fn message(&self, on_conflict: Option<&str>) -> String {
+        let conflict = match self {
+            RequestError::Conflict(err) => database_error(err),
+            _ => None,
+        };
+        match (on_conflict, conflict) {
+            (Some(message), Some(err)) => {
+                let constraint = err
+                    .try_downcast_ref::<PgDatabaseError>()
+                    .and_then(PgDatabaseError::constraint)
+                    .unwrap_or_default();
+                message.replace("{constraint}", constraint)
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    /// classifies an error from reading the auth token of a request
+    pub fn auth(err: anyhow::Error) -> Self {
+        if err.is::<MissingRole>() {
+            RequestError::Forbidden(err)
+        } else {
+            RequestError::Unauthorized(err)
+        }
+    }
+}
+
+const UNIQUE_VIOLATION: &str = "23505";
+const FOREIGN_KEY_VIOLATION: &str = "23503";
+
+/// the first error returned by postgres in the error's chain
+fn database_error(err: &anyhow::Error) -> Option<&(dyn DatabaseError + 'static)> {
+    err.chain()
+        .filter_map(|err| err.downcast_ref::<sqlx::Error>())
+        .find_map(sqlx::Error::as_database_error)
+}
+
+/// the SQLSTATE code of the first postgres error in the error's chain, such as `23505`
+/// for a unique violation
+pub fn sqlstate(err: &anyhow::Error) -> Option<String> {
+    database_error(err)
+        .and_then(|err| err.code())
+        .map(|code| code.into_owned())
+}
+
+/// classifies an error returned by postgres. unique and foreign key violations conflict
+/// with existing rows, other data exceptions (class 22) and integrity violations
+/// (class 23) are caused by the payload
+fn classify_sqlstate(err: anyhow::Error) -> RequestError {
+    match sqlstate(&err).as_deref() {
+        Some(UNIQUE_VIOLATION) | Some(FOREIGN_KEY_VIOLATION) => RequestError::Conflict(err),
+        Some(code) if code.starts_with("22") || code.starts_with("23") => {
+            RequestError::BadRequest(err)
+        }
+        _ => RequestError::Internal(err),
+    }
+}
+
+/// errors that are not explicitly classified are assumed to be the server's fault
+/// unless they come from binding the payload or postgres rejects the payload's values
+impl From<anyhow::Error> for RequestError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast_ref::<MissingBinding>() {
+            Some(MissingBinding::AuthToken) => RequestError::Unauthorized(err),
+            Some(MissingBinding::Param(_))
+            | Some(MissingBinding::Positional(_))
+            | Some(MissingBinding::Claim(_))
+            | Some(MissingBinding::Rows(_, _))
+            | Some(MissingBinding::Field(_, _, _)) => RequestError::BadRequest(err),
+            None => classify_sqlstate(err),
+        }
+    }
+}
+
+impl From<sqlx::Error> for RequestError {
+    fn from(err: sqlx::Error) -> Self {
+        anyhow::Error::from(err).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    /// a postgres error with the given SQLSTATE code
+    #[derive(Debug)]
+    struct CodeError(&'static str);
+
+    impl std::fmt::Display for CodeError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "error with code {}", self.0)
+        }
+    }
+
+    impl std::error::Error for CodeError {}
+
+    impl DatabaseError for CodeError {
+        fn message(&self) -> &str {
+            "error"
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.0))
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+    }
+
+    fn postgres_error(code: &'static str) -> RequestError {
+        sqlx::Error::Database(Box::new(CodeError(code))).into()
+    }
+
+    #[test]
+    fn status_code_test() {
+        let err: RequestError = anyhow::Error::from(MissingBinding::Param("id".into())).into();
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+
+        let err: RequestError = anyhow::Error::from(MissingBinding::AuthToken).into();
+        assert_eq!(err.status_code(), StatusCode::UNAUTHORIZED);
+
+        let err = RequestError::auth(MissingRole.into());
+        assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+
+        let err = RequestError::auth(anyhow!("authentication is required"));
+        assert_eq!(err.status_code(), StatusCode::UNAUTHORIZED);
+
+        let err: RequestError = anyhow!("could not convert row").into();
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let err: RequestError = sqlx::Error::PoolTimedOut.into();
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // invalid input and constraint violations are caused by the payload
+        for code in &["22P02", "22003", "23502", "23514"] {
+            assert_eq!(postgres_error(code).status_code(), StatusCode::BAD_REQUEST);
+        }
+        // a syntax error or a missing table is the endpoint's fault
+        for code in &["42601", "42P01"] {
+            assert_eq!(
+                postgres_error(code).status_code(),
+                StatusCode::INTERNAL_SERVER_ERROR
+            );
+        }
+        let err: RequestError =
+            anyhow::Error::from(sqlx::Error::Database(Box::new(CodeError("22P02"))))
+                .context("could not run query")
+                .into();
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+
+        // the payload conflicts with rows that already exist or are still referenced
+        for code in &["23505", "23503"] {
+            assert_eq!(postgres_error(code).status_code(), StatusCode::CONFLICT);
+        }
+    }
+
+    #[test]
+    fn error_code_test() {
+        // only errors returned by postgres have a code
+        let err: RequestError = sqlx::Error::PoolTimedOut.into();
+        assert_eq!(err.error_code(), None);
+        let err: RequestError = anyhow!("could not convert row").into();
+        assert_eq!(err.error_code(), None);
+
+        assert_eq!(
+            postgres_error("23505").error_code().as_deref(),
+            Some("23505")
+        );
+        let violation: RequestError =
+            anyhow::Error::from(sqlx::Error::Database(Box::new(CodeError("23505"))))
+                .context("could not run query")
+                .into();
+        assert_eq!(violation.error_code().as_deref(), Some("23505"));
+
+        // the @on_conflict message only replaces constraint violations
+        assert_eq!(err.message(Some("already exists")), "could not convert row");
+        let err = postgres_error("23514");
+        assert_eq!(
+            err.message(Some("already exists")),
+            "error returned from database: error with code 23514"
+        );
+
+        let err = postgres_error("23505");
+        assert_eq!(err.status_code(), StatusCode::CONFLICT);
+        assert_eq!(err.message(Some("already exists")), "already exists");
+        assert_eq!(
+            err.message(None),
+            "error returned from database: error with code 23505"
+        );
+    }
+}
@@ -1,25 +1,112 @@
-use std::time::Duration;
+use std::{future::Future, time::Duration};
 
-use sqlx::{Pool, Postgres};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    Pool, Postgres,
+};
 
 use crate::config::Config;
 
-/// connects the
+/// the pool of the read replica that read only endpoints run against
+pub struct ReplicaPool(pub Pool<Postgres>);
+
+/// connects to the database using the configured connection and pool settings
 pub async fn connect_to_db(
     config: &Config,
     max_connections: Option<u32>,
+) -> anyhow::Result<Pool<Postgres>> {
+    let connect_options = config.database.connect_options()?;
+    if config.database.simple_protocol {
+        warn!("database.simple_protocol is set, bindings are inlined into the sql instead of being sent as typed parameters");
+    }
+    connect_with(
+        config.database.pool_options(),
+        connect_options,
+        max_connections,
+    )
+    .await
+}
+
+/// connects to the database like `connect_to_db` but retries with an exponential backoff
+/// as configured by `database.connect_retries`, for databases that are still starting up
+pub async fn connect_to_db_with_retry(
+    config: &Config,
+    max_connections: Option<u32>,
+) -> anyhow::Result<Pool<Postgres>> {
+    with_retry(config, || connect_to_db(config, max_connections)).await
+}
+
+/// connects to the read replica when one is configured, retrying like the primary
+pub async fn connect_to_replica_with_retry(
+    config: &Config,
+    max_connections: Option<u32>,
+) -> anyhow::Result<Option<Pool<Postgres>>> {
+    let connect_options = match config.database.replica_connect_options()? {
+        Some(connect_options) => connect_options,
+        None => return Ok(None),
+    };
+    let pool = with_retry(config, || {
+        connect_with(
+            config.database.pool_options(),
+            connect_options.clone(),
+            max_connections,
+        )
+    })
+    .await?;
+    Ok(Some(pool))
+}
+
+async fn with_retry<F, Fut>(config: &Config, connect: F) -> anyhow::Result<Pool<Postgres>>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = anyhow::Result<Pool<Postgres>>>,
+{
+    let mut delays = config.database.connect_retry_delays();
+    let mut attempt = 1;
+    loop {
+        let err = match connect().await {
+            Ok(pool) => return Ok(pool),
+            Err(err) => err,
+        };
+        match delays.next() {
+            Some(delay) => {
+                warn!(
+                    "connection attempt {} failed: {:#}. retrying in {}ms",
+                    attempt,
+                    err,
+                    delay.as_millis()
+                );
+                actix_rt::time::delay_for(delay).await;
+                attempt += 1;
+            }
+            None => {
+                return Err(err.context(format!(
+                    "could not connect to the database after {} attempt(s)",
+                    attempt
+                )))
+            }
+        }
+    }
+}
+
+/// connects to the database at the given url
+pub async fn connect_to_url(
+    database_url: &str,
+    max_connections: Option<u32>,
+) -> anyhow::Result<Pool<Postgres>> {
+    let options = PgPoolOptions::new().connect_timeout(Duration::from_secs_f32(10f32));
+    connect_with(options, database_url.parse()?, max_connections).await
+}
+
+async fn connect_with(
+    options: PgPoolOptions,
+    connect_options: PgConnectOptions,
+    max_connections: Option<u32>,
 ) -> anyhow::Result<Pool<Postgres>> {
     info!("connecting to the database");
-    let database_url = config
-        .database
-        .url
-        .as_ref()
-        .and_then(|v| v.value())
-        .ok_or_else(|| anyhow!("must have database url set in config"))?;
-    let pool = sqlx::postgres::PgPoolOptions::new()
-        .connect_timeout(Duration::from_secs_f32(10f32))
+    let pool = options
         .max_connections(max_connections.unwrap_or(10u32))
-        .connect(database_url.as_str())
+        .connect_with(connect_options)
         .await?;
     pool.acquire()
         .await
@@ -0,0 +1,281 @@
+use actix_web::http::StatusCode;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::query::AcquireRecorder;
+
+/// upper bounds of the latency histogram buckets in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct EndpointStats {
+    /// requests by their status code
+    requests: BTreeMap<u16, u64>,
+    errors: u64,
+    /// requests that took at most the matching bound in `LATENCY_BUCKETS`
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    seconds: f64,
+    count: u64,
+}
+
+/// the time spent waiting for pooled connections. it is shared by every query no matter
+/// which command runs it, so it lives outside of the registry
+static ACQUIRE: Mutex<AcquireStats> = Mutex::new(AcquireStats {
+    buckets: [0; LATENCY_BUCKETS.len()],
+    seconds: 0.0,
+    count: 0,
+});
+
+struct AcquireStats {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    seconds: f64,
+    count: u64,
+}
+
+/// records how long a query waited for a connection from the pool
+fn record_acquire(elapsed: Duration) {
+    let mut stats = ACQUIRE.lock().unwrap_or_else(|err| err.into_inner());
+    let seconds = elapsed.as_secs_f64();
+    for (bound, bucket) in LATENCY_BUCKETS.iter().zip(stats.buckets.iter_mut()) {
+        if seconds <= *bound {
+            *bucket += 1;
+        }
+    }
+    stats.seconds += seconds;
+    stats.count += 1;
+}
+
+/// per endpoint request metrics. a disabled registry skips timing and recording entirely
+#[derive(Default)]
+pub struct Metrics {
+    endpoints: Option<Mutex<BTreeMap<String, EndpointStats>>>,
+}
+
+impl Metrics {
+    pub fn new(enabled: bool) -> Self {
+        Metrics {
+            endpoints: if enabled {
+                Some(Mutex::new(BTreeMap::new()))
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.endpoints.is_some()
+    }
+
+    /// records the waits for pooled connections, only given out when metrics are enabled
+    pub fn acquire_recorder(&self) -> Option<AcquireRecorder> {
+        self.endpoints
+            .as_ref()
+            .map(|_| record_acquire as AcquireRecorder)
+    }
+
+    /// the start of a request, only taken when metrics are enabled
+    pub fn start(&self) -> Option<Instant> {
+        self.endpoints.as_ref().map(|_| Instant::now())
+    }
+
+    pub fn record(&self, endpoint: &str, status: StatusCode, started: Option<Instant>) {
+        if let Some(started) = started {
+            self.record_elapsed(endpoint, status, started.elapsed());
+        }
+    }
+
+    fn record_elapsed(&self, endpoint: &str, status: StatusCode, elapsed: Duration) {
+        let endpoints = match self.endpoints.as_ref() {
+            Some(endpoints) => endpoints,
+            None => return,
+        };
+        let mut endpoints = match endpoints.lock() {
+            Ok(endpoints) => endpoints,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if !endpoints.contains_key(endpoint) {
+            endpoints.insert(endpoint.to_string(), EndpointStats::default());
+        }
+        let stats = endpoints.get_mut(endpoint).unwrap();
+
+        *stats.requests.entry(status.as_u16()).or_insert(0) += 1;
+        if status.is_client_error() || status.is_server_error() {
+            stats.errors += 1;
+        }
+        let seconds = elapsed.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(stats.buckets.iter_mut()) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        stats.seconds += seconds;
+        stats.count += 1;
+    }
+
+    /// the metrics in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut buf = String::new();
+        let endpoints = match self.endpoints.as_ref() {
+            Some(endpoints) => endpoints,
+            None => return buf,
+        };
+        let endpoints = match endpoints.lock() {
+            Ok(endpoints) => endpoints,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let acquire = ACQUIRE.lock().unwrap_or_else(|err| err.into_inner());
+        // writing to a string can not fail
+        let _ = write_metrics(&mut buf, &endpoints);
+        let _ = write_acquire_metrics(&mut buf, &acquire);
+        buf
+    }
+}
+
+fn write_metrics(
+    buf: &mut String,
+    endpoints: &BTreeMap<String, EndpointStats>,
+) -> std::fmt::Result {
+    writeln!(
+        buf,
+        "# HELP justsql_requests_total requests by endpoint and status"
+    )?;
+    writeln!(buf, "# TYPE justsql_requests_total counter")?;
+    for (endpoint, stats) in endpoints {
+        for (status, count) in stats.requests.iter() {
+            writeln!(
+                buf,
+                "justsql_requests_total{{endpoint=\"{}\",status=\"{}\"}} {}",
+                escape_label(endpoint),
+                status,
+                count
+            )?;
+        }
+    }
+
+    writeln!(
+        buf,
+        "# HELP justsql_errors_total failed requests by endpoint"
+    )?;
+    writeln!(buf, "# TYPE justsql_errors_total counter")?;
+    for (endpoint, stats) in endpoints {
+        writeln!(
+            buf,
+            "justsql_errors_total{{endpoint=\"{}\"}} {}",
+            escape_label(endpoint),
+            stats.errors
+        )?;
+    }
+
+    writeln!(
+        buf,
+        "# HELP justsql_request_duration_seconds request latency by endpoint"
+    )?;
+    writeln!(buf, "# TYPE justsql_request_duration_seconds histogram")?;
+    for (endpoint, stats) in endpoints {
+        let endpoint = escape_label(endpoint);
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(stats.buckets.iter()) {
+            writeln!(
+                buf,
+                "justsql_request_duration_seconds_bucket{{endpoint=\"{}\",le=\"{}\"}} {}",
+                endpoint, bound, count
+            )?;
+        }
+        writeln!(
+            buf,
+            "justsql_request_duration_seconds_bucket{{endpoint=\"{}\",le=\"+Inf\"}} {}",
+            endpoint, stats.count
+        )?;
+        writeln!(
+            buf,
+            "justsql_request_duration_seconds_sum{{endpoint=\"{}\"}} {}",
+            endpoint, stats.seconds
+        )?;
+        writeln!(
+            buf,
+            "justsql_request_duration_seconds_count{{endpoint=\"{}\"}} {}",
+            endpoint, stats.count
+        )?;
+    }
+    Ok(())
+}
+
+fn write_acquire_metrics(buf: &mut String, stats: &AcquireStats) -> std::fmt::Result {
+    writeln!(
+        buf,
+        "# HELP justsql_pool_acquire_seconds time spent waiting for a pooled connection"
+    )?;
+    writeln!(buf, "# TYPE justsql_pool_acquire_seconds histogram")?;
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(stats.buckets.iter()) {
+        writeln!(
+            buf,
+            "justsql_pool_acquire_seconds_bucket{{le=\"{}\"}} {}",
+            bound, count
+        )?;
+    }
+    writeln!(
+        buf,
+        "justsql_pool_acquire_seconds_bucket{{le=\"+Inf\"}} {}",
+        stats.count
+    )?;
+    writeln!(buf, "justsql_pool_acquire_seconds_sum {}", stats.seconds)?;
+    writeln!(buf, "justsql_pool_acquire_seconds_count {}", stats.count)?;
+    Ok(())
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_test() {
+        let metrics = Metrics::new(true);
+        metrics.record_elapsed("users", StatusCode::OK, Duration::from_millis(20));
+        metrics.record_elapsed("users", StatusCode::BAD_REQUEST, Duration::from_secs(20));
+
+        let text = metrics.render();
+        assert!(text.contains("justsql_requests_total{endpoint=\"users\",status=\"200\"} 1\n"));
+        assert!(text.contains("justsql_requests_total{endpoint=\"users\",status=\"400\"} 1\n"));
+        assert!(text.contains("justsql_errors_total{endpoint=\"users\"} 1\n"));
+        assert!(text.contains(
+            "justsql_request_duration_seconds_bucket{endpoint=\"users\",le=\"0.025\"} 1\n"
+        ));
+        assert!(text
+            .contains("justsql_request_duration_seconds_bucket{endpoint=\"users\",le=\"10\"} 1\n"));
+        assert!(text.contains(
+            "justsql_request_duration_seconds_bucket{endpoint=\"users\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(text.contains("justsql_request_duration_seconds_count{endpoint=\"users\"} 2\n"));
+    }
+
+    #[test]
+    fn acquire_test() {
+        let metrics = Metrics::new(true);
+        metrics.acquire_recorder().unwrap()(Duration::from_millis(2));
+        let text = metrics.render();
+        assert!(text.contains("# TYPE justsql_pool_acquire_seconds histogram\n"));
+        assert!(text.contains("justsql_pool_acquire_seconds_bucket{le=\"+Inf\"} "));
+        assert!(!text.contains("justsql_pool_acquire_seconds_count 0\n"));
+    }
+
+    #[test]
+    fn disabled_test() {
+        let metrics = Metrics::default();
+        assert!(metrics.start().is_none());
+        metrics.record("users", StatusCode::OK, Some(Instant::now()));
+        assert!(metrics.acquire_recorder().is_none());
+        assert_eq!(metrics.render(), "");
+    }
+}
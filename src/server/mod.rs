@@ -1,2 +1,7 @@
+pub mod body_log;
+pub mod cache;
+pub mod error;
 pub mod init;
+pub mod metrics;
 pub mod routes;
+pub mod subscribe;
@@ -1,31 +1,68 @@
-use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use actix_web::{
+    error::InternalError, http::StatusCode, web, web::Bytes, HttpMessage, HttpRequest,
+    HttpResponse, Responder, ResponseError,
+};
+use futures::{channel::mpsc, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use sqlx::{postgres::PgArguments, PgPool, Postgres};
-use std::{collections::BTreeMap, sync::Arc};
+use sqlx::PgPool;
+use std::{borrow::Cow, collections::BTreeMap, convert::TryInto, sync::Arc, time::Duration};
+use uuid::Uuid;
 
 use crate::{
-    binding::Binding,
-    codegen::AuthSettings,
+    binding::{Binding, Payload},
+    codegen::{AuthSettings, Module, ParamKind, Returns},
     config::Config,
     engine::Evaluator,
-    query::{self, build_queries},
-    row_type::{convert_row, RowType},
+    query::{self, RunOptions},
+    row_type::{Row, RowType},
+    server::{
+        body_log,
+        cache::{CacheKey, ResponseCache},
+        error::{sqlstate, RequestError},
+        init::ReplicaPool,
+        metrics::Metrics,
+    },
 };
 
 // TODO currently can only send over simplistic types
 #[derive(Deserialize)]
 pub struct Query {
     endpoint: String,
-    payload: BTreeMap<String, Binding>,
+    payload: Payload,
+    /// runs the query and rolls it back so nothing it writes persists.
+    /// only allowed when `server.allow_preview` is set
+    #[serde(default)]
+    preview: bool,
+}
+
+/// previews roll back before they are committed which is only supported by /api/v1/query
+fn reject_preview(preview: bool) -> Result<(), RequestError> {
+    if preview {
+        Err(RequestError::BadRequest(anyhow!(
+            "preview is only supported by /api/v1/query"
+        )))?
+    }
+    Ok(())
 }
 
 #[derive(Serialize)]
 pub struct QueryResult<A> {
     #[serde(rename = "endpoint")]
     endpoint: String,
+    /// identifies the http request in the logs
+    request_id: Uuid,
     #[serde(flatten)]
     data: QueryStatus<A>,
+    /// only set when `server.debug_sql` is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug: Option<QueryDebug>,
+}
+
+#[derive(Serialize)]
+pub struct QueryDebug {
+    /// the statements sent to postgres, with `$n` placeholders instead of the bound values
+    sql: Vec<String>,
 }
 
 #[derive(Serialize)]
@@ -34,12 +71,156 @@ pub enum QueryStatus<A> {
     #[serde(rename = "success")]
     Success { data: A },
     #[serde(rename = "error")]
-    Error { message: String },
+    Error {
+        message: String,
+        /// the SQLSTATE code when postgres rejected the query
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error_code: Option<String>,
+    },
+}
+
+/// the rows of an endpoint, or the only value of its only row for `@returns scalar`
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum QueryData {
+    Rows(Vec<Row>),
+    Scalar(RowType),
+}
+
+impl QueryData {
+    fn new(rows: Vec<Row>, returns: Option<Returns>) -> Result<Self, RequestError> {
+        match returns {
+            None => Ok(QueryData::Rows(rows)),
+            Some(Returns::Scalar) => {
+                let [row]: [Row; 1] = rows.try_into().map_err(|rows: Vec<Row>| {
+                    RequestError::Internal(anyhow!(
+                        "@returns scalar expects exactly one row but the query returned {}",
+                        rows.len()
+                    ))
+                })?;
+                let columns: Vec<_> = row.into_iter().collect();
+                let [(_, value)]: [(String, RowType); 1] =
+                    columns.try_into().map_err(|columns: Vec<_>| {
+                        RequestError::Internal(anyhow!(
+                            "@returns scalar expects exactly one column but the query returned {}",
+                            columns.len()
+                        ))
+                    })?;
+                Ok(QueryData::Scalar(value))
+            }
+        }
+    }
+}
+
+impl<A> QueryStatus<A> {
+    /// the error of an endpoint, `on_conflict` is the endpoint's `@on_conflict` message
+    pub fn error(err: &RequestError, on_conflict: Option<&str>) -> Self {
+        QueryStatus::Error {
+            message: err.message(on_conflict),
+            error_code: err.error_code(),
+        }
+    }
+}
+
+/// what a client needs to know to call an endpoint
+#[derive(Serialize)]
+pub struct EndpointInfo {
+    endpoint: String,
+    params: Vec<ParamInfo>,
+    /// one of verify, authorize or clear when the endpoint declares `@auth`
+    auth: Option<&'static str>,
+    /// roles of which the token must hold one, empty when any token is accepted
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    roles: Vec<String>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
 }
 
+#[derive(Serialize)]
+pub struct ParamInfo {
+    name: String,
+    /// the declared type of the parameter if it has one
+    kind: Option<&'static str>,
+}
+
+impl EndpointInfo {
+    fn new(endpoint: String, module: &Module) -> Self {
+        let front_matter = &module.front_matter;
+        let params = front_matter
+            .params
+            .iter()
+            .map(|name| ParamInfo {
+                name: name.clone(),
+                kind: front_matter.param_kinds.get(name).map(|kind| match kind {
+                    ParamKind::Json => "json",
+                }),
+            })
+            .collect();
+        let (auth, roles) = match front_matter.auth_settings.as_ref() {
+            Some(AuthSettings::VerifyToken(_, roles)) => (Some("verify"), roles.clone()),
+            Some(AuthSettings::SetToken(_)) => (Some("authorize"), vec![]),
+            Some(AuthSettings::RemoveToken) => (Some("clear"), vec![]),
+            None => (None, vec![]),
+        };
+        EndpointInfo {
+            endpoint,
+            params,
+            auth,
+            roles,
+            stream: front_matter.stream,
+            tags: front_matter.tags.clone(),
+        }
+    }
+}
+
+/// rows buffered between the database and a slow client before fetching waits
+const STREAM_BUFFER_ROWS: usize = 64;
+
 // TODO allow COOKIE_NAME to change based on env vars
 // TODO set env vars with lazy static
-const COOKIE_NAME: &'static str = "justsql_token";
+pub(crate) const COOKIE_NAME: &'static str = "justsql_token";
+
+/// limits the size of json bodies and reports rejected bodies in the same shape as failed queries.
+/// bodies sent with a gzip, deflate or br `Content-Encoding` are decompressed by the extractor
+/// and the limit applies to the decompressed size
+pub fn json_config(max_body_bytes: usize) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(max_body_bytes)
+        .error_handler(|err, _req| {
+            let response =
+                HttpResponse::build(err.error_response().status()).json(QueryStatus::<()>::Error {
+                    message: err.to_string(),
+                    error_code: None,
+                });
+            InternalError::from_response(err, response).into()
+        })
+}
+
+/// the refreshed token replaces the old one so the row it is made from must still hold
+/// every claim that the endpoints read through `@auth`, nested ones like `org.id` included
+fn check_refresh_claims(evaluator: &Evaluator, endpoint: &str, row: &Row) -> anyhow::Result<()> {
+    let row = serde_json::to_value(row)?;
+    let claims = evaluator.auth_claims()?;
+    let missing: Vec<&str> = claims
+        .iter()
+        .map(String::as_str)
+        .filter(|claim| {
+            claim
+                .split('.')
+                .try_fold(&row, |value, segment| value.get(segment))
+                .is_none()
+        })
+        .collect();
+    if !missing.is_empty() {
+        Err(anyhow!(
+            "endpoint {} refreshes the auth token but its row is missing the claims {} that are read through @auth",
+            endpoint,
+            missing.join(", ")
+        ))?
+    }
+    Ok(())
+}
 
 pub async fn auth_query(
     req: HttpRequest,
@@ -47,6 +228,7 @@ pub async fn auth_query(
     evaluator: web::Data<Evaluator>,
     pool: web::Data<PgPool>,
     config: web::Data<Arc<Config>>,
+    metrics: web::Data<Metrics>,
 ) -> impl Responder {
     enum ReturnType {
         SetToken(String),
@@ -54,55 +236,88 @@ pub async fn auth_query(
         DoNothing,
     }
 
+    let request_id = Uuid::new_v4();
+    let started = metrics.start();
     let cookie = req.cookie(COOKIE_NAME);
     let pool = pool.get_ref();
     let data = data.into_inner();
 
-    let (endpoint, payload) = (data.endpoint, data.payload);
-    let return_type: anyhow::Result<ReturnType> = async {
-        let mut tx = pool.begin().await?;
-        let module = evaluator.endpoint(endpoint.as_str())?;
-        let auth = module
-            .front_matter
-            .auth_settings
-            .as_ref()
-            .ok_or_else(|| anyhow!("module at endpoint {} does not have any auth settings"))?;
-
-        let auth_bindings = module.get_auth_bindings(
-            config.auth.as_ref(),
-            cookie.as_ref().map(|cookie| cookie.value()),
-        )?;
-
-        let statements =
-            evaluator.evaluate_endpoint(endpoint.as_str(), &payload, auth_bindings.as_ref())?;
-        let queries = build_queries(&statements)?;
-        let mut query: Option<sqlx::query::Query<Postgres, PgArguments>> = None;
-        for cur in queries {
-            if let Some(cur_query) = query {
-                cur_query.execute(&mut tx).await?;
-            }
-            query = Some(cur);
-        }
+    let (endpoint, payload, preview) = (data.endpoint, data.payload.0, data.preview);
+    let mut on_conflict = None;
+    let return_type: Result<ReturnType, RequestError> = async {
+        reject_preview(preview)?;
+        let module = evaluator
+            .endpoint(endpoint.as_str())
+            .map_err(RequestError::BadRequest)?;
+        on_conflict = module.front_matter.on_conflict.clone();
+        let auth = module.front_matter.auth_settings.as_ref().ok_or_else(|| {
+            RequestError::BadRequest(anyhow!(
+                "module at endpoint {} does not have any auth settings",
+                endpoint
+            ))
+        })?;
 
-        let query = query
-            .ok_or_else(|| anyhow!("module at endpoint {} did not have any queries", endpoint))?;
+        let auth_bindings = module
+            .get_auth_bindings(
+                config.auth.as_ref(),
+                cookie.as_ref().map(|cookie| cookie.value()),
+            )
+            .map_err(RequestError::auth)?;
+
+        let options = RunOptions {
+            record_acquire: metrics.acquire_recorder(),
+            ..RunOptions::from_config(&config, false)
+        };
+        let mut tx = query::begin(pool, options.slow_acquire, options.record_acquire).await?;
+        query::set_local_claims(&mut tx, auth_bindings.as_ref(), options).await?;
+        // the rows of the last statement, or the one annotated with `-- @returns_rows`
+        let rows = query::run_in_transaction(
+            &mut tx,
+            module.as_ref(),
+            &evaluator.importer,
+            &payload,
+            auth_bindings.as_ref(),
+            options,
+        )
+        .await?
+        .pop()
+        .map(|result| result.rows)
+        .unwrap_or_default();
+        let row = rows
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("module at endpoint {} did not return a row", endpoint));
 
         let res: ReturnType = match auth {
             AuthSettings::RemoveToken => {
-                query.execute(&mut tx).await?;
+                // revoked tokens are rejected even if the client holds on to the cookie
+                if let (Some(secret), Some(cookie)) = (config.auth.as_ref(), cookie.as_ref()) {
+                    if let Err(err) = secret.revoke(cookie.value()) {
+                        debug!("request {} did not revoke token: {}", request_id, err);
+                    }
+                }
                 ReturnType::RemoveToken
             }
 
-            AuthSettings::VerifyToken(v) => {
-                let res = query.fetch_one(&mut tx).await?;
-                let data = convert_row(res)?;
+            AuthSettings::VerifyToken(v, _) => {
+                let data = row?;
                 let secret = config
                     .auth
                     .as_ref()
                     .ok_or_else(|| anyhow!("config does not have secrets configured"))?;
-                match v.as_ref() {
-                    None => ReturnType::DoNothing,
-                    Some(exp) => {
+                // the token was verified above so it can be decoded again for its expiration
+                let token_exp = cookie
+                    .as_ref()
+                    .map(|cookie| secret.decode(cookie.value()))
+                    .transpose()?
+                    .map(|claims| claims.exp);
+                match (v.as_ref(), token_exp) {
+                    (None, _) => ReturnType::DoNothing,
+                    (Some(exp), Some(token_exp)) if !secret.should_refresh(token_exp, *exp)? => {
+                        ReturnType::DoNothing
+                    }
+                    (Some(exp), _) => {
+                        check_refresh_claims(&evaluator, endpoint.as_str(), &data)?;
                         let data = secret.encode(&data, *exp)?;
                         ReturnType::SetToken(data)
                     }
@@ -115,8 +330,7 @@ pub async fn auth_query(
                 // TODO change errors to explain what happens
                 // depending on whether or not the server is run
                 // with debug mode
-                let res = query.fetch_one(&mut tx).await?;
-                let data = convert_row(res)?;
+                let data = row?;
                 let secret = config
                     .auth
                     .as_ref()
@@ -130,14 +344,45 @@ pub async fn auth_query(
         Ok(res)
     }
     .await;
+    // the result of an auth endpoint is the token itself and its payload holds
+    // credentials, so only the shape of the payload is logged
+    if config.server.log_bodies {
+        body_log::log_body(
+            &config.server,
+            request_id,
+            endpoint.as_str(),
+            "payload",
+            &body_log::redact_all(body_log::to_json(&payload)),
+            &[],
+        );
+    }
+
+    let status = match &return_type {
+        Err(err) => err.status_code(),
+        Ok(ReturnType::RemoveToken) if cookie.is_none() => StatusCode::BAD_REQUEST,
+        Ok(_) => StatusCode::OK,
+    };
+    if started.is_some() {
+        metrics.record(
+            metrics_label(&evaluator, endpoint.as_str()),
+            status,
+            started,
+        );
+    }
 
     match return_type {
-        Err(err) => HttpResponse::BadRequest().json(QueryResult::<()> {
-            endpoint,
-            data: QueryStatus::Error {
-                message: err.to_string(),
-            },
-        }),
+        Err(err) => {
+            error!(
+                "request {} failed at endpoint {}: {:?}",
+                request_id, endpoint, err
+            );
+            HttpResponse::build(err.status_code()).json(QueryResult::<()> {
+                endpoint,
+                request_id,
+                data: QueryStatus::error(&err, on_conflict.as_deref()),
+                debug: None,
+            })
+        }
         Ok(value) => match (value, req.cookie(COOKIE_NAME)) {
             (ReturnType::RemoveToken, Some(mut cookie)) => {
                 // wipes out the cookie the old-fashioned way.
@@ -162,53 +407,155 @@ pub async fn auth_query(
 
                 HttpResponse::Ok().cookie(cookie).json(QueryResult {
                     endpoint,
+                    request_id,
                     data: QueryStatus::Success {
                         data: "Cookie is deleted.",
                     },
+                    debug: None,
                 })
             }
             (ReturnType::RemoveToken, None) => HttpResponse::BadRequest().json(QueryResult::<()> {
                 endpoint,
+                request_id,
                 data: QueryStatus::Error {
                     message: "User was not logged in.".to_string(),
+                    error_code: None,
                 },
+                debug: None,
             }),
             (ReturnType::DoNothing, _) => HttpResponse::Ok().json(QueryResult {
                 endpoint,
+                request_id,
                 data: QueryStatus::Success {
                     data: "User is authorized.",
                 },
+                debug: None,
             }),
             (ReturnType::SetToken(token), _) => {
                 let cookie = config.cookie.build(COOKIE_NAME, token);
                 HttpResponse::Ok().cookie(cookie).json(json!(QueryResult {
                     endpoint,
+                    request_id,
                     data: QueryStatus::Success {
                         data: "User is authorized. Cookie is set.",
                     },
+                    debug: None,
                 }))
             }
         },
     }
 }
 
+/// endpoints come from the request so unknown ones share a label instead of
+/// growing the metrics without bound
+fn metrics_label<'a>(evaluator: &Evaluator, endpoint: &'a str) -> &'a str {
+    if evaluator.endpoint(endpoint).is_ok() {
+        endpoint
+    } else {
+        "unknown"
+    }
+}
+
+/// what build of justsql is serving requests
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    /// the commit set in `JUSTSQL_COMMIT` when justsql was built
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commit: Option<&'static str>,
+    /// either debug or release
+    profile: &'static str,
+    target: String,
+}
+
+/// reports the version and build of the server so deployments can be checked
+pub async fn version() -> impl Responder {
+    HttpResponse::Ok().json(VersionInfo {
+        version: crate::VERSION,
+        commit: option_env!("JUSTSQL_COMMIT"),
+        profile: if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        },
+        target: format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+    })
+}
+
+/// serves the metrics in the prometheus text format, if they are enabled
+pub async fn metrics(metrics: web::Data<Metrics>) -> impl Responder {
+    if !metrics.is_enabled() {
+        return HttpResponse::NotFound().finish();
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
+
+/// lists every endpoint with its parameters when `server.expose_introspection` is set
+pub async fn endpoints(
+    evaluator: web::Data<Evaluator>,
+    config: web::Data<Arc<Config>>,
+) -> impl Responder {
+    if !config.server.expose_introspection {
+        return HttpResponse::NotFound().finish();
+    }
+    let endpoints = evaluator
+        .importer
+        .get_all_endpoints()
+        .and_then(|endpoints| {
+            endpoints
+                .into_iter()
+                .map(|endpoint| {
+                    let module = evaluator.endpoint(endpoint.as_str())?;
+                    Ok(EndpointInfo::new(endpoint, module.as_ref()))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        });
+    match endpoints {
+        Ok(data) => HttpResponse::Ok().json(QueryStatus::Success { data }),
+        Err(err) => {
+            error!("failed to list endpoints: {}", err);
+            HttpResponse::InternalServerError().json(QueryStatus::<()>::Error {
+                message: err.to_string(),
+                error_code: None,
+            })
+        }
+    }
+}
+
 pub async fn run_queries(
     req: HttpRequest,
     data: web::Json<Vec<Query>>,
     evaluator: web::Data<Evaluator>,
     pool: web::Data<PgPool>,
     config: web::Data<Arc<Config>>,
+    metrics: web::Data<Metrics>,
+    cache: web::Data<ResponseCache>,
 ) -> impl Responder {
+    let request_id = Uuid::new_v4();
     let evaluator = evaluator.get_ref();
+    let metrics = metrics.get_ref();
+    let cache = cache.get_ref();
     let pool = pool.get_ref();
+    let replica = req
+        .app_data::<web::Data<ReplicaPool>>()
+        .map(|replica| &replica.get_ref().0);
     let data = data.into_inner();
     let config_secret = &config.auth;
+    let debug_sql = config.server.debug_sql;
+    let allow_preview = config.server.allow_preview;
+    let server_config = &config.server;
+    let options = RunOptions {
+        record_acquire: metrics.acquire_recorder(),
+        ..RunOptions::from_config(&config, false)
+    };
     let cookie = &req.cookie(COOKIE_NAME);
     let cookie = cookie.as_ref().map(|v| v.value());
 
     let (endpoints, payloads) = data
         .into_iter()
-        .map(|query| (query.endpoint, query.payload))
+        .map(|query| (query.endpoint, (query.payload.0, query.preview)))
         .fold((vec![], vec![]), |(mut v1, mut v2), (e1, e2)| {
             v1.push(e1);
             v2.push(e2);
@@ -220,34 +567,621 @@ pub async fn run_queries(
             .iter()
             .zip(payloads.into_iter())
             .map(|(endpoint, payload)| async move {
-                let module = evaluator.endpoint(endpoint.as_str())?;
-                let auth_bindings = module.get_auth_bindings(config_secret.as_ref(), cookie)?;
+                let (payload, preview) = payload;
+                let started = metrics.start();
+                let mut debug = None;
+                let mut on_conflict = None;
+                let mut returns = None;
+                let mut claims = None;
+                let rows: Result<Vec<Row>, RequestError> = async {
+                    if preview && !allow_preview {
+                        Err(RequestError::Forbidden(anyhow!(
+                            "previews are not allowed, set server.allow_preview to allow them"
+                        )))?
+                    }
+                    let options = RunOptions {
+                        rollback: preview,
+                        ..options
+                    };
+                    let module = evaluator
+                        .endpoint(endpoint.as_str())
+                        .map_err(RequestError::BadRequest)?;
+                    on_conflict = module.front_matter.on_conflict.clone();
+                    returns = module.front_matter.returns;
+                    if module.front_matter.stream {
+                        Err(RequestError::BadRequest(anyhow!(
+                        "endpoint {} streams its rows and must be queried through /api/v1/stream",
+                        endpoint
+                    )))?
+                    }
+                    let auth_bindings = module
+                        .get_auth_bindings(config_secret.as_ref(), cookie)
+                        .map_err(RequestError::auth)?;
+                    // the sql of an endpoint is only shown to those allowed to run it
+                    if debug_sql {
+                        debug = debug_statements(module.as_ref(), evaluator, &payload, options);
+                    }
+                    if server_config.log_bodies {
+                        claims = auth_bindings.as_ref().map(body_log::to_json);
+                    }
 
-                query::run_query(
-                    module.as_ref(),
-                    &evaluator.importer,
-                    pool,
-                    &payload,
-                    auth_bindings.as_ref(),
-                    false,
-                )
-                .await
+                    // previews neither read nor fill the cache since their writes do not persist
+                    let cache_ttl = module.front_matter.cache_ttl.filter(|_| !preview);
+                    let cache_key = cache_ttl.map(|ttl| {
+                        let key = CacheKey::new(endpoint, &payload, auth_bindings.as_ref());
+                        (key, Duration::from_secs(ttl))
+                    });
+                    if let Some(rows) = cache_key.as_ref().and_then(|(key, _)| cache.get(key)) {
+                        return Ok(rows.as_ref().clone());
+                    }
+
+                    // writes always go to the primary
+                    let pool = match replica {
+                        Some(replica) if module.front_matter.read_only => replica,
+                        _ => pool,
+                    };
+                    let rows = query::run_query(
+                        module.as_ref(),
+                        &evaluator.importer,
+                        pool,
+                        &payload,
+                        auth_bindings.as_ref(),
+                        options,
+                    )
+                    .await?;
+                    if let Some((key, ttl)) = cache_key {
+                        cache.insert(key, Arc::new(rows.clone()), ttl);
+                    }
+                    Ok(rows)
+                }
+                .await;
+                let rows = rows.and_then(|rows| QueryData::new(rows, returns));
+                if server_config.log_bodies {
+                    let secrets = claims
+                        .as_ref()
+                        .map(body_log::claim_values)
+                        .unwrap_or_default();
+                    let payload = body_log::to_json(&payload);
+                    body_log::log_body(
+                        server_config,
+                        request_id,
+                        endpoint,
+                        "payload",
+                        &payload,
+                        &secrets,
+                    );
+                    if let Ok(data) = &rows {
+                        body_log::log_body(
+                            server_config,
+                            request_id,
+                            endpoint,
+                            "result",
+                            data,
+                            &secrets,
+                        );
+                    }
+                }
+                let status = rows
+                    .as_ref()
+                    .map_or_else(RequestError::status_code, |_| StatusCode::OK);
+                if started.is_some() {
+                    let label = metrics_label(evaluator, endpoint.as_str());
+                    metrics.record(label, status, started);
+                }
+                (rows, (debug, on_conflict))
             });
 
-    let results: Vec<anyhow::Result<Vec<BTreeMap<String, RowType>>>> =
-        futures::future::join_all(query_results).await;
+    let (results, extras): (Vec<Result<QueryData, RequestError>>, Vec<_>) =
+        futures::future::join_all(query_results)
+            .await
+            .into_iter()
+            .unzip();
+
+    // the response carries the most severe status of all the failed queries
+    let status = results
+        .iter()
+        .filter_map(|res| res.as_ref().err())
+        .map(RequestError::status_code)
+        .max_by_key(|status| status.as_u16())
+        .unwrap_or(StatusCode::OK);
 
-    let results: Vec<QueryResult<Vec<BTreeMap<String, RowType>>>> = results
+    let results: Vec<QueryResult<QueryData>> = results
         .into_iter()
         .zip(endpoints.into_iter())
-        .map(|(res, endpoint)| QueryResult {
-            endpoint,
-            data: match res.map_err(|err| err.to_string()) {
+        .zip(extras)
+        .map(|((res, endpoint), (debug, on_conflict))| {
+            let data = match res {
                 Ok(res) => QueryStatus::Success { data: res },
-                Err(res) => QueryStatus::Error { message: res },
-            },
+                Err(err) => {
+                    error!(
+                        "request {} failed at endpoint {}: {:?}",
+                        request_id, endpoint, err
+                    );
+                    QueryStatus::error(&err, on_conflict.as_deref())
+                }
+            };
+            QueryResult {
+                endpoint,
+                request_id,
+                data,
+                debug,
+            }
         })
         .collect();
 
-    HttpResponse::Ok().json(results)
+    HttpResponse::build(status).json(results)
+}
+
+/// the statements of the module as they are sent to postgres. a statement that fails
+/// to build is left out of the response, the query itself reports the error
+fn debug_statements(
+    module: &Module,
+    evaluator: &Evaluator,
+    payload: &BTreeMap<String, Binding>,
+    options: RunOptions,
+) -> Option<QueryDebug> {
+    let sql = module
+        .sql
+        .iter()
+        .map(|statement| {
+            query::build_query_statement(
+                module,
+                &evaluator.importer,
+                statement.as_slice(),
+                Some(payload),
+                options.max_import_depth,
+            )
+            .map(|(sql, _)| sql)
+        })
+        .collect::<anyhow::Result<Vec<String>>>()
+        .ok()?;
+    Some(QueryDebug { sql })
+}
+
+/// runs a single `@stream` endpoint and writes its rows as newline delimited json
+/// while they are fetched. since the status is sent before the first row, an error
+/// part way through is written as a final `{"status": "error", ...}` line.
+pub async fn stream_query(
+    req: HttpRequest,
+    data: web::Json<Query>,
+    evaluator: web::Data<Evaluator>,
+    pool: web::Data<PgPool>,
+    config: web::Data<Arc<Config>>,
+    metrics: web::Data<Metrics>,
+) -> HttpResponse {
+    let request_id = Uuid::new_v4();
+    let data = data.into_inner();
+    let (endpoint, payload, preview) = (data.endpoint, data.payload.0, data.preview);
+    let cookie = req.cookie(COOKIE_NAME);
+    let options = RunOptions {
+        record_acquire: metrics.acquire_recorder(),
+        ..RunOptions::from_config(&config, false)
+    };
+
+    type Statements = Vec<(String, Vec<Binding>)>;
+    type Claims = Option<BTreeMap<String, Binding>>;
+    let statements: Result<(Statements, Claims), RequestError> = (|| {
+        reject_preview(preview)?;
+        let module = evaluator
+            .endpoint(endpoint.as_str())
+            .map_err(RequestError::BadRequest)?;
+        if !module.front_matter.stream {
+            Err(RequestError::BadRequest(anyhow!(
+                "endpoint {} is not declared with @stream",
+                endpoint
+            )))?
+        }
+        let auth_bindings = module
+            .get_auth_bindings(
+                config.auth.as_ref(),
+                cookie.as_ref().map(|cookie| cookie.value()),
+            )
+            .map_err(RequestError::auth)?;
+
+        let statements = if options.simple_protocol {
+            query::evaluate_inline(
+                module.as_ref(),
+                &evaluator.importer,
+                &payload,
+                auth_bindings.as_ref(),
+                options.max_import_depth,
+                options.assume_null_if_missing,
+            )?
+            .into_iter()
+            .map(|statement| (statement, vec![]))
+            .collect()
+        } else {
+            evaluator
+                .evaluate_endpoint(
+                    endpoint.as_str(),
+                    &payload,
+                    auth_bindings.as_ref(),
+                    options.max_import_depth,
+                    options.assume_null_if_missing,
+                )?
+                .into_iter()
+                .map(|(statement, bindings)| {
+                    (
+                        statement,
+                        bindings.into_iter().map(Cow::into_owned).collect(),
+                    )
+                })
+                .collect()
+        };
+        Ok((statements, auth_bindings))
+    })();
+
+    let (statements, auth_bindings) = match statements {
+        Ok(statements) => statements,
+        Err(err) => {
+            error!(
+                "request {} failed at endpoint {}: {:?}",
+                request_id, endpoint, err
+            );
+            return HttpResponse::build(err.status_code()).json(QueryResult::<()> {
+                endpoint,
+                request_id,
+                data: QueryStatus::error(&err, None),
+                debug: None,
+            });
+        }
+    };
+
+    let (sender, receiver) = mpsc::channel(STREAM_BUFFER_ROWS);
+    actix_rt::spawn(query::stream_rows(
+        pool.get_ref().clone(),
+        statements,
+        auth_bindings,
+        options,
+        sender,
+    ));
+
+    let body = receiver.map(move |row| {
+        let line = match row {
+            Ok(row) => serde_json::to_vec(&row),
+            Err(err) => {
+                error!(
+                    "request {} failed at endpoint {}: {:?}",
+                    request_id, endpoint, err
+                );
+                serde_json::to_vec(&QueryStatus::<()>::Error {
+                    message: err.to_string(),
+                    error_code: sqlstate(&err),
+                })
+            }
+        };
+        let mut line = line.map_err(actix_web::Error::from)?;
+        line.push(b'\n');
+        Ok::<_, actix_web::Error>(Bytes::from(line))
+    });
+
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::UpfrontImporter, row_type::Category, util::temp_dir::temp_dir};
+    use actix_web::{
+        dev::{Service, ServiceResponse},
+        test, App,
+    };
+    use std::path::Path;
+
+    /// every route of the server over the examples/current_user.sql endpoint. no
+    /// database is running so queries that get past auth fail quickly
+    async fn test_app(
+        config: &str,
+    ) -> impl Service<Request = actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>
+    {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/current_user.sql");
+        let importer = UpfrontImporter::from_paths(&[path.as_path()], false).unwrap();
+        let config: Config = serde_yaml::from_str(config).unwrap();
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_timeout(Duration::from_millis(100))
+            .connect_lazy("postgres://localhost/justsql")
+            .unwrap();
+
+        test::init_service(
+            App::new()
+                .app_data(json_config(config.server.max_body_bytes))
+                .data(Arc::new(config))
+                .data(pool)
+                .data(Evaluator::with_importer(importer))
+                .data(Metrics::default())
+                .data(ResponseCache::new(1))
+                .route("/api/v1/auth", web::post().to(auth_query))
+                .route("/api/v1/query", web::post().to(run_queries))
+                .route("/api/v1/stream", web::post().to(stream_query))
+                .route("/api/v1/endpoints", web::get().to(endpoints))
+                .route(
+                    "/api/v1/subscribe/{endpoint}",
+                    web::get().to(crate::server::subscribe::subscribe),
+                ),
+        )
+        .await
+    }
+
+    #[actix_rt::test]
+    async fn unauthorized_without_cookie_test() {
+        let mut app =
+            test_app("auth:\n  algorithm: HS256\n  secret_key_base64: dGVzdGluZw==").await;
+
+        let query = json!({"endpoint": "currentUser", "payload": {}});
+        let req = test::TestRequest::post()
+            .uri("/api/v1/query")
+            .set_json(&json!([query]))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/auth")
+            .set_json(&query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn stream_requires_stream_endpoint_test() {
+        let mut app = test_app("{}").await;
+        // only endpoints declared with @stream can be streamed
+        let req = test::TestRequest::post()
+            .uri("/api/v1/stream")
+            .set_json(&json!({"endpoint": "currentUser", "payload": {}}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn subscribe_requires_subscribe_endpoint_test() {
+        let mut app = test_app("{}").await;
+        // only endpoints declared with @subscribe can be subscribed to
+        let req = test::TestRequest::get()
+            .uri("/api/v1/subscribe/currentUser")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn subscribe_checks_origin_test() {
+        let mut app = test_app("cors:\n  allowed_origins: ['https://example.com']").await;
+        // browsers do not apply cors to websockets so other origins are rejected here
+        let req = test::TestRequest::get()
+            .uri("/api/v1/subscribe/currentUser")
+            .header("origin", "https://evil.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/subscribe/currentUser")
+            .header("origin", "https://example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn max_body_bytes_test() {
+        let mut app = test_app("server:\n  max_body_bytes: 64").await;
+        let query = json!({"endpoint": "currentUser", "payload": {"padding": "x".repeat(64)}});
+        let req = test::TestRequest::post()
+            .uri("/api/v1/auth")
+            .set_json(&query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], "error");
+    }
+
+    #[actix_rt::test]
+    async fn endpoints_test() {
+        // introspection is disabled unless the config turns it on
+        let mut app = test_app("{}").await;
+        let req = test::TestRequest::get()
+            .uri("/api/v1/endpoints")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let mut app = test_app("server:\n  expose_introspection: true").await;
+        let req = test::TestRequest::get()
+            .uri("/api/v1/endpoints")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["data"][0]["endpoint"], "currentUser");
+        assert_eq!(body["data"][0]["auth"], "verify");
+    }
+
+    #[actix_rt::test]
+    async fn debug_sql_test() {
+        let query = json!([{"endpoint": "currentUser", "payload": {}}]);
+
+        // the sql is left out unless the config turns it on
+        let mut app = test_app("{}").await;
+        let req = test::TestRequest::post()
+            .uri("/api/v1/query")
+            .set_json(&query)
+            .to_request();
+        let body: serde_json::Value =
+            test::read_body_json(test::call_service(&mut app, req).await).await;
+        assert!(body[0].get("debug").is_none());
+
+        let mut app = test_app("server:\n  debug_sql: true").await;
+        let req = test::TestRequest::post()
+            .uri("/api/v1/query")
+            .set_json(&query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert!(body[0].get("debug").is_none());
+
+        // once the cookie is verified the sql is included even if the database fails
+        let config = "auth:\n  algorithm: HS256\n  secret_key_base64: dGVzdGluZw==\nserver:\n  debug_sql: true";
+        let secret = serde_yaml::from_str::<Config>(config)
+            .unwrap()
+            .auth
+            .unwrap();
+        let token = secret.encode(&json!({"id": 5}), 60).unwrap();
+        let mut app = test_app(config).await;
+        let req = test::TestRequest::post()
+            .uri("/api/v1/query")
+            .cookie(actix_web::cookie::Cookie::new(COOKIE_NAME, token))
+            .set_json(&query)
+            .to_request();
+        let body: serde_json::Value =
+            test::read_body_json(test::call_service(&mut app, req).await).await;
+        assert_eq!(body[0]["status"], "error");
+        assert_eq!(body[0]["debug"]["sql"], json!(["select $1 as id\n"]));
+    }
+
+    #[test]
+    fn returns_scalar_test() {
+        let row = |columns: &[&str]| -> Row {
+            columns
+                .iter()
+                .map(|column| (column.to_string(), RowType::Int8(Category::Value(Some(5)))))
+                .collect()
+        };
+        let data = |rows: Vec<Row>, returns| {
+            QueryData::new(rows, returns).map(|data| serde_json::to_value(data).unwrap())
+        };
+
+        assert_eq!(
+            data(vec![row(&["count"])], None).unwrap(),
+            json!([{ "count": 5 }])
+        );
+        assert_eq!(
+            data(vec![row(&["count"])], Some(Returns::Scalar)).unwrap(),
+            json!(5)
+        );
+        for rows in [
+            vec![],
+            vec![row(&["count"]), row(&["count"])],
+            vec![row(&["count", "total"])],
+        ] {
+            let err = data(rows, Some(Returns::Scalar)).unwrap_err();
+            assert!(err
+                .to_string()
+                .starts_with("@returns scalar expects exactly one"));
+        }
+    }
+
+    #[test]
+    fn refresh_claims_test() {
+        let directory = temp_dir("refresh_claims_test");
+        std::fs::write(
+            directory.join("refresh.sql"),
+            "-- @endpoint refresh\n-- @auth verify 1d\nselect @auth.id as id, @auth.org.id as org_id",
+        )
+        .unwrap();
+        let importer = UpfrontImporter::new(directory.to_str().unwrap(), "sql", false).unwrap();
+        let evaluator = Evaluator::with_importer(importer);
+        let row = |columns: Vec<(&str, RowType)>| -> Row {
+            columns
+                .into_iter()
+                .map(|(column, value)| (column.to_string(), value))
+                .collect()
+        };
+        let id = || RowType::Int8(Category::Value(Some(5)));
+        let json = |value| RowType::Json(Category::Value(Some(value)));
+
+        // the nested claim is read from the json column
+        let accepted = row(vec![("id", id()), ("org", json(json!({"id": 7})))]);
+        check_refresh_claims(&evaluator, "refresh", &accepted).unwrap();
+
+        for rejected in [
+            row(vec![("id", id())]),
+            row(vec![("id", id()), ("org", json(json!({"name": "acme"})))]),
+            row(vec![("id", id()), ("org", json(json!(7)))]),
+        ] {
+            let err = check_refresh_claims(&evaluator, "refresh", &rejected).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "endpoint refresh refreshes the auth token but its row is missing the claims org.id that are read through @auth"
+            );
+        }
+        let err = check_refresh_claims(&evaluator, "refresh", &row(vec![])).unwrap_err();
+        assert!(err.to_string().contains("the claims id, org.id that"));
+    }
+
+    #[actix_rt::test]
+    async fn compressed_body_test() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let gzip = |body: &[u8]| {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        };
+        let mut app = test_app("server:\n  max_body_bytes: 16384").await;
+
+        let query = json!([{"endpoint": "currentUser", "payload": {}}]).to_string();
+        let req = test::TestRequest::post()
+            .uri("/api/v1/query")
+            .header("content-type", "application/json")
+            .header("content-encoding", "gzip")
+            .set_payload(gzip(query.as_bytes()))
+            .to_request();
+        let body: serde_json::Value =
+            test::read_body_json(test::call_service(&mut app, req).await).await;
+        assert_eq!(body[0]["endpoint"], "currentUser");
+
+        // the limit applies to the decompressed body so small bombs are rejected
+        let padding = " ".repeat(1024 * 1024);
+        let bomb = gzip(format!("{}{}", padding, query).as_bytes());
+        assert!(bomb.len() < 16 * 1024);
+        let req = test::TestRequest::post()
+            .uri("/api/v1/query")
+            .header("content-type", "application/json")
+            .header("content-encoding", "gzip")
+            .set_payload(bomb)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[actix_rt::test]
+    async fn version_test() {
+        let mut app =
+            test::init_service(App::new().route("/version", web::get().to(version))).await;
+        let req = test::TestRequest::get().uri("/version").to_request();
+        let body: serde_json::Value =
+            test::read_body_json(test::call_service(&mut app, req).await).await;
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[actix_rt::test]
+    async fn preview_test() {
+        let query = json!([{"endpoint": "currentUser", "payload": {}, "preview": true}]);
+
+        // previews are rejected unless the config allows them
+        let mut app = test_app("{}").await;
+        let req = test::TestRequest::post()
+            .uri("/api/v1/query")
+            .set_json(&query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let mut app = test_app("server:\n  allow_preview: true").await;
+        let req = test::TestRequest::post()
+            .uri("/api/v1/query")
+            .set_json(&query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
 }
@@ -0,0 +1,257 @@
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{self, CloseCode, CloseReason, Codec, Frame, Message};
+use actix_web::{
+    http::header,
+    web::{self, BytesMut},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, Either},
+    SinkExt, StreamExt,
+};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{postgres::PgListener, PgPool};
+use std::{sync::Arc, time::Duration};
+use uuid::Uuid;
+
+use crate::{
+    config::Config,
+    engine::Evaluator,
+    server::{
+        error::RequestError,
+        routes::{QueryStatus, COOKIE_NAME},
+    },
+};
+
+/// messages buffered for a slow websocket client before it is disconnected
+const SUBSCRIBE_BUFFER_MESSAGES: usize = 64;
+
+/// how long a client that fell behind gets to make room for the close frame
+const SUBSCRIBE_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// a notification forwarded to the websocket client
+#[derive(Serialize)]
+struct Notification {
+    channel: String,
+    /// the payload as json, or as a string when it is not valid json
+    payload: Value,
+}
+
+/// upgrades the request to a websocket and forwards the notifications of the
+/// endpoint's `@subscribe` channel as json text messages. the listening connection is
+/// taken from the pool for as long as the socket is open and unlistens when it closes.
+/// clients that fall more than `SUBSCRIBE_BUFFER_MESSAGES` behind are disconnected
+/// instead of buffering their notifications without bound.
+pub async fn subscribe(
+    req: HttpRequest,
+    payload: web::Payload,
+    endpoint: web::Path<String>,
+    evaluator: web::Data<Evaluator>,
+    pool: web::Data<PgPool>,
+    config: web::Data<Arc<Config>>,
+) -> HttpResponse {
+    let request_id = Uuid::new_v4();
+    let endpoint = endpoint.into_inner();
+    let cookie = req.cookie(COOKIE_NAME);
+
+    let channel: Result<String, RequestError> = (|| {
+        // any page could open a socket with the user's cookie since browsers do not
+        // apply cors to websockets
+        if let Some(origin) = req.headers().get(header::ORIGIN) {
+            let allowed = origin
+                .to_str()
+                .map_or(false, |origin| config.cors.allows_origin(origin));
+            if !allowed {
+                Err(RequestError::Forbidden(anyhow!(
+                    "origin {:?} is not in cors.allowed_origins or cors.allowed_origin_patterns",
+                    origin
+                )))?
+            }
+        }
+        let module = evaluator
+            .endpoint(endpoint.as_str())
+            .map_err(RequestError::BadRequest)?;
+        let channel = module.front_matter.subscribe.clone().ok_or_else(|| {
+            RequestError::BadRequest(anyhow!(
+                "endpoint {} is not declared with @subscribe",
+                endpoint
+            ))
+        })?;
+        module
+            .get_auth_bindings(
+                config.auth.as_ref(),
+                cookie.as_ref().map(|cookie| cookie.value()),
+            )
+            .map_err(RequestError::auth)?;
+        Ok(channel)
+    })();
+
+    // the handshake is checked before listening so a rejected request never leaves a
+    // listening connection in the pool
+    let response = channel.and_then(|channel| {
+        let response = ws::handshake(req.head())
+            .map_err(|err| RequestError::BadRequest(anyhow!("{}", err)))?;
+        Ok((channel, response))
+    });
+    let listener: Result<_, RequestError> = async {
+        let (channel, response) = response?;
+        let mut listener = PgListener::connect_with(pool.get_ref()).await?;
+        listener.listen(channel.as_str()).await?;
+        Ok((channel, listener, response))
+    }
+    .await;
+
+    let (channel, listener, mut response) = match listener {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(
+                "request {} failed at endpoint {}: {:?}",
+                request_id, endpoint, err
+            );
+            return HttpResponse::build(err.status_code())
+                .json(QueryStatus::<()>::error(&err, None));
+        }
+    };
+
+    let (sender, receiver) = mpsc::channel(SUBSCRIBE_BUFFER_MESSAGES);
+    let (stop, stopped) = oneshot::channel();
+    actix_rt::spawn(read_frames(payload, sender.clone(), stop));
+    actix_rt::spawn(forward_notifications(
+        listener, channel, sender, stopped, request_id,
+    ));
+
+    let mut codec = Codec::new();
+    let body = receiver.map(move |message| {
+        let mut buf = BytesMut::new();
+        codec.encode(message, &mut buf)?;
+        Ok::<_, actix_web::Error>(buf.freeze())
+    });
+    response.streaming(body)
+}
+
+/// answers pings and closes from the client. `stop` fires once the client closes
+/// the socket or goes away
+async fn read_frames(
+    mut payload: web::Payload,
+    mut sender: mpsc::Sender<Message>,
+    stop: oneshot::Sender<()>,
+) {
+    let mut codec = Codec::new();
+    let mut buf = BytesMut::new();
+    'read: while let Some(Ok(chunk)) = payload.next().await {
+        buf.extend_from_slice(&chunk);
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(Some(Frame::Ping(msg))) => {
+                    let _ = sender.try_send(Message::Pong(msg));
+                }
+                Ok(Some(Frame::Close(reason))) => {
+                    let _ = sender.send(Message::Close(reason)).await;
+                    break 'read;
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(_) => break 'read,
+            }
+        }
+    }
+    let _ = stop.send(());
+}
+
+/// queues the close frame for a client that fell behind. the buffer is full so the
+/// close has to wait for room, but a client that stopped reading altogether must not
+/// keep the listening connection out of the pool. returns whether the close was queued
+async fn close_behind(sender: &mut mpsc::Sender<Message>, timeout: Duration) -> bool {
+    let close = sender.send(Message::Close(Some(CloseReason {
+        code: CloseCode::Policy,
+        description: Some("subscriber fell behind".to_string()),
+    })));
+    match future::select(close, actix_rt::time::delay_for(timeout)).await {
+        Either::Left((sent, _)) => sent.is_ok(),
+        Either::Right(_) => false,
+    }
+}
+
+/// sends every notification of the channel to the client until the client goes away
+async fn forward_notifications(
+    mut listener: PgListener,
+    channel: String,
+    mut sender: mpsc::Sender<Message>,
+    mut stopped: oneshot::Receiver<()>,
+    request_id: Uuid,
+) {
+    loop {
+        let notification = match future::select(Box::pin(listener.recv()), &mut stopped).await {
+            Either::Left((Ok(notification), _)) => notification,
+            Either::Left((Err(err), _)) => {
+                error!(
+                    "request {} stopped listening to {}: {:?}",
+                    request_id, channel, err
+                );
+                let _ = sender.try_send(Message::Close(Some(CloseReason {
+                    code: CloseCode::Error,
+                    description: Some(err.to_string()),
+                })));
+                break;
+            }
+            Either::Right(_) => break,
+        };
+
+        let payload = notification.payload();
+        let message = serde_json::to_string(&Notification {
+            channel: notification.channel().to_string(),
+            payload: serde_json::from_str(payload)
+                .unwrap_or_else(|_| Value::String(payload.to_string())),
+        });
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                error!("request {} dropped a notification: {:?}", request_id, err);
+                continue;
+            }
+        };
+
+        if let Err(err) = sender.try_send(Message::Text(message)) {
+            if err.is_full() {
+                debug!("request {} fell behind on {}", request_id, channel);
+                close_behind(&mut sender, SUBSCRIBE_CLOSE_TIMEOUT).await;
+            }
+            break;
+        }
+    }
+
+    // the connection goes back to the pool so it must stop listening first
+    if let Err(err) = listener.unlisten(channel.as_str()).await {
+        error!(
+            "request {} could not unlisten {}: {:?}",
+            request_id, channel, err
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn close_behind_test() {
+        let (mut sender, mut receiver) = mpsc::channel(0);
+        sender.try_send(Message::Text("first".to_string())).unwrap();
+
+        // a client that never reads gives up the close instead of waiting forever
+        assert!(!close_behind(&mut sender, Duration::from_millis(50)).await);
+
+        // a client that makes room receives the close
+        let read = async move {
+            actix_rt::time::delay_for(Duration::from_millis(20)).await;
+            receiver.next().await;
+            receiver.next().await
+        };
+        let (closed, last) =
+            future::join(close_behind(&mut sender, Duration::from_secs(5)), read).await;
+        assert!(closed);
+        assert!(matches!(last, Some(Message::Close(_))));
+    }
+}
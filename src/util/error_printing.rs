@@ -1,6 +1,14 @@
-use std::fmt::{Debug, Write};
+use std::{
+    cell::Cell,
+    fmt::{self, Debug, Display, Write},
+    io::IsTerminal,
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 use thiserror::Error;
 
+use super::logging::LogFormat;
+
 pub trait PrintableError {
     fn print_error<W: Write>(&self, writer: &mut W) -> Result<(), PrintError>;
 }
@@ -31,19 +39,127 @@ pub enum PrintError {
     MissingLineError,
 }
 
-fn line_pad<W: Write>(writer: &mut W, row: usize, include_line: bool) -> Result<(), PrintError> {
-    let positions = (row as f64).log10().floor() as usize + 1;
-    if include_line {
-        write!(writer, "{: >width$} |", row, width = positions)?;
-    } else {
-        write!(writer, "{: >width$} |", "", width = positions)?;
+/// whether errors are printed with ansi colors
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// set while printing errors that are sent somewhere other than the terminal
+    static PLAIN: Cell<bool> = const { Cell::new(false) };
+}
+
+/// how many lines are printed before and after the line of an error
+static CONTEXT_LINES: AtomicUsize = AtomicUsize::new(0);
+
+/// ansi style of the arrow, line numbers and gutter
+const LOCATION_STYLE: &str = "\x1b[1;34m";
+/// ansi style of the line numbers of the lines around an error
+const CONTEXT_STYLE: &str = "\x1b[2m";
+/// ansi style of the caret and explanation
+const ERROR_STYLE: &str = "\x1b[1;31m";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    /// colors are used when stderr is a terminal and logs are text
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(anyhow!("color must be one of 'auto', 'always' or 'never'")),
+        }
+    }
+}
+
+/// sets whether errors printed from now on are colorized
+pub fn init_color(choice: ColorChoice, format: LogFormat) {
+    let color = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => format == LogFormat::Text && std::io::stderr().is_terminal(),
+    };
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+/// sets how many lines around an error are printed from now on
+pub fn init_context_lines(lines: usize) {
+    CONTEXT_LINES.store(lines, Ordering::Relaxed);
+}
+
+/// a value that is wrapped in an ansi style when colors are enabled
+struct Painted<T> {
+    style: &'static str,
+    value: T,
+    color: bool,
+}
+
+fn paint<T: Display>(style: &'static str, value: T) -> Painted<T> {
+    Painted {
+        style,
+        value,
+        color: COLOR.load(Ordering::Relaxed) && !PLAIN.with(Cell::get),
     }
+}
+
+/// prints the errors inside of `print` without colors, for errors that end up in a
+/// response or another error instead of on the terminal
+pub fn without_color<T>(print: impl FnOnce() -> T) -> T {
+    let plain = PLAIN.with(|plain| plain.replace(true));
+    let res = print();
+    PLAIN.with(|cell| cell.set(plain));
+    res
+}
+
+impl<T: Display> Display for Painted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.color {
+            write!(f, "{}{}\x1b[0m", self.style, self.value)
+        } else {
+            write!(f, "{}", self.value)
+        }
+    }
+}
+
+fn pad_width(row: usize) -> usize {
+    (row as f64).log10().floor() as usize + 1
+}
+
+/// the gutter of a line, padded to the width of the largest printed line number
+fn gutter<W: Write>(
+    writer: &mut W,
+    width: usize,
+    row: Option<usize>,
+    style: &'static str,
+) -> Result<(), PrintError> {
+    let pad = match row {
+        Some(row) => format!("{: >width$} |", row, width = width),
+        None => format!("{: >width$} |", "", width = width),
+    };
+    write!(writer, "{}", paint(style, pad))?;
     Ok(())
 }
 
+fn line_pad<W: Write>(writer: &mut W, row: usize, include_line: bool) -> Result<(), PrintError> {
+    let label = if include_line { Some(row) } else { None };
+    gutter(writer, pad_width(row), label, LOCATION_STYLE)
+}
+
 fn file_name_pad<W: Write>(writer: &mut W, row: usize) -> Result<(), PrintError> {
-    let positions = (row as f64).log10().floor() as usize + 1;
-    write!(writer, "{: >width$}{}", "", "-->", width = positions)?;
+    let positions = pad_width(row);
+    write!(
+        writer,
+        "{: >width$}{}",
+        "",
+        paint(LOCATION_STYLE, "-->"),
+        width = positions
+    )?;
     Ok(())
 }
 
@@ -56,34 +172,38 @@ pub fn print_unpositioned_error<W: Write>(
     write!(writer, " {}\n", file_name)?;
 
     line_pad(writer, 1, false)?;
-    write!(writer, " {}\n", explanation)?;
+    write!(writer, " {}\n", paint(ERROR_STYLE, explanation))?;
     Ok(())
 }
 
 struct Position {
     row: usize,
+    /// the column counted in characters starting from 1
     col: usize,
-    idx: usize,
+    /// the byte index of the start of the line
+    line_start: usize,
 }
 
-/// find the row and column of a file given a character position
+/// find the row and column of a file given a byte position. columns count characters
+/// so that multi-byte characters earlier on the line do not shift the caret
 fn find_row_col(file: &str, position: usize) -> Result<Position, PrintError> {
     let iter = file
         .char_indices()
-        .scan((1usize, 0usize), |pos, (idx, chr)| {
+        .scan((1usize, 0usize, 0usize), |pos, (idx, chr)| {
             if chr == '\n' {
-                pos.0 += 1;
-                pos.1 = idx;
+                *pos = (pos.0 + 1, 0, idx + 1);
+            } else {
+                pos.1 += 1;
             };
-            Some((pos.0, idx - pos.1, idx))
+            Some((*pos, idx))
         });
 
     let mut prev = None;
     let mut data = None;
-    for (row, col, idx) in iter {
+    for (pos, idx) in iter {
         prev = data.take();
-        data = Some((row, col, idx));
-        if idx == position {
+        data = Some(pos);
+        if idx >= position {
             break;
         }
     }
@@ -96,16 +216,57 @@ fn find_row_col(file: &str, position: usize) -> Result<Position, PrintError> {
         data = prev
     }
 
-    data.map(|(row, col, idx)| Position { row, col, idx })
-        .ok_or_else(|| PrintError::MissingPositionError)
+    data.map(|(row, col, line_start)| Position {
+        row,
+        col,
+        line_start,
+    })
+    .ok_or_else(|| PrintError::MissingPositionError)
 }
 
+/// prints an error that points at a single character
+#[allow(dead_code)]
 pub fn print_error<W: Write>(
     writer: &mut W,
     file: &str,
     position: usize,
     explanation: &str,
     file_name: &str,
+) -> Result<(), PrintError> {
+    print_error_span(writer, file, position, 1, explanation, file_name)
+}
+
+/// prints an error that underlines the `len` bytes starting at the position. the
+/// underline stops at the end of the line and is at least one character wide
+pub fn print_error_span<W: Write>(
+    writer: &mut W,
+    file: &str,
+    position: usize,
+    len: usize,
+    explanation: &str,
+    file_name: &str,
+) -> Result<(), PrintError> {
+    let context_lines = CONTEXT_LINES.load(Ordering::Relaxed);
+    print_error_with_context(
+        writer,
+        file,
+        position,
+        len,
+        context_lines,
+        explanation,
+        file_name,
+    )
+}
+
+/// prints an error along with up to `context_lines` lines before and after its line
+pub fn print_error_with_context<W: Write>(
+    writer: &mut W,
+    file: &str,
+    position: usize,
+    len: usize,
+    context_lines: usize,
+    explanation: &str,
+    file_name: &str,
 ) -> Result<(), PrintError> {
     debug!(
         "finding error in file {} at position {}",
@@ -119,30 +280,74 @@ pub fn print_error<W: Write>(
         );
     }
 
-    // we shadow position here since find_row_col will sometimes
-    // go back a line if the current position is at the line beginning.
+    let underline = file
+        .get(position..position + len)
+        .map_or(0, |span| {
+            span.chars().take_while(|chr| *chr != '\n').count()
+        })
+        .max(1);
+
+    // errors at the end of the file, like an unfinished final statement, point
+    // at the last token instead of the trailing whitespace
+    let position = if position >= file.len() {
+        let trimmed = file.trim_end();
+        trimmed
+            .rfind(char::is_whitespace)
+            .map(|idx| idx + trimmed[idx..].chars().next().map_or(1, char::len_utf8))
+            .unwrap_or(0)
+    } else {
+        position
+    };
+
+    // find_row_col will sometimes go back a line if the current position
+    // is at the line beginning.
     let Position {
         row,
         col,
-        idx: position,
+        line_start,
     } = find_row_col(file, position)?;
 
     let line = file
-        .get(position - col + 1..)
+        .get(line_start..)
         .ok_or_else(|| PrintError::MissingLineError)?;
     let line = &line[0..line.find('\n').unwrap_or(line.len())];
 
-    file_name_pad(writer, row)?;
+    let lines: Vec<&str> = file.lines().collect();
+    let before = row.saturating_sub(context_lines).max(1)..row;
+    let last_row = row.saturating_add(context_lines).min(lines.len()).max(row);
+    let after = row + 1..last_row + 1;
+    let width = pad_width(last_row);
+
+    file_name_pad(writer, last_row)?;
     write!(writer, " {}:{}:{}\n", file_name, row, col)?;
 
-    line_pad(writer, row, false)?;
+    gutter(writer, width, None, LOCATION_STYLE)?;
     write!(writer, "\n")?;
 
-    line_pad(writer, row, true)?;
+    for context_row in before {
+        gutter(writer, width, Some(context_row), CONTEXT_STYLE)?;
+        writeln!(writer, " {}", lines[context_row - 1])?;
+    }
+
+    gutter(writer, width, Some(row), LOCATION_STYLE)?;
     write!(writer, " {}\n", line)?;
 
-    line_pad(writer, row, false)?;
-    write!(writer, "{:col$}^{}\n", "", explanation, col = col)?;
+    gutter(writer, width, None, LOCATION_STYLE)?;
+    write!(
+        writer,
+        "{:col$}{}\n",
+        "",
+        paint(
+            ERROR_STYLE,
+            format_args!("{}{}", "^".repeat(underline), explanation)
+        ),
+        col = col
+    )?;
+
+    for context_row in after {
+        gutter(writer, width, Some(context_row), CONTEXT_STYLE)?;
+        writeln!(writer, " {}", lines[context_row - 1])?;
+    }
     Ok(())
 }
 
@@ -165,6 +370,35 @@ mod tests {
         assert_eq!("        -->", res);
     }
 
+    #[test]
+    fn painted_test() {
+        let painted = |color| {
+            Painted {
+                style: ERROR_STYLE,
+                value: "^unexpected token",
+                color,
+            }
+            .to_string()
+        };
+        assert_eq!(painted(false), "^unexpected token");
+        assert_eq!(painted(true), "\x1b[1;31m^unexpected token\x1b[0m");
+        assert_eq!("never".parse::<ColorChoice>().unwrap(), ColorChoice::Never);
+        assert!("blue".parse::<ColorChoice>().is_err());
+    }
+
+    #[test]
+    fn without_color_test() {
+        assert!(!without_color(
+            || paint(ERROR_STYLE, "^unexpected token").color
+        ));
+        // a nested scope leaves the outer one plain
+        without_color(|| {
+            without_color(|| ());
+            assert!(PLAIN.with(Cell::get));
+        });
+        assert!(!PLAIN.with(Cell::get));
+    }
+
     #[test]
     fn unpositioned_test() {
         let mut res = String::new();
@@ -215,6 +449,204 @@ limit 1
         )
     }
 
+    #[test]
+    fn multi_byte_test() {
+        let example_string = "-- café für ñandú\nselect * from ñandú where é = @\n";
+        let file_name = "src/text.sql";
+
+        let position = example_string.find('@').unwrap();
+        let mut res = String::new();
+        print_error(
+            &mut res,
+            example_string,
+            position,
+            "unexpected token",
+            file_name,
+        )
+        .unwrap();
+        assert_eq!(
+            format!("\n{}", res.as_str()),
+            r#"
+ --> src/text.sql:2:31
+  |
+2 | select * from ñandú where é = @
+  |                               ^unexpected token
+"#
+        );
+
+        let position = example_string.find("für").unwrap();
+        let mut res = String::new();
+        print_error(
+            &mut res,
+            example_string,
+            position,
+            "unexpected token",
+            file_name,
+        )
+        .unwrap();
+        assert_eq!(
+            format!("\n{}", res.as_str()),
+            r#"
+ --> src/text.sql:1:9
+  |
+1 | -- café für ñandú
+  |         ^unexpected token
+"#
+        );
+    }
+
+    #[test]
+    fn print_error_span_test() {
+        let example_string = "select * from users\nwhere userId = @user_id\nlimit 1\n";
+        let file_name = "src/text.sql";
+        let position = example_string.find('@').unwrap();
+
+        let mut res = String::new();
+        print_error_span(
+            &mut res,
+            example_string,
+            position,
+            "@user_id".len(),
+            "undefined parameter user_id",
+            file_name,
+        )
+        .unwrap();
+        assert_eq!(
+            format!("\n{}", res.as_str()),
+            r#"
+ --> src/text.sql:2:16
+  |
+2 | where userId = @user_id
+  |                ^^^^^^^^undefined parameter user_id
+"#
+        );
+
+        // spans over multiple lines are only underlined until the end of the first
+        let mut res = String::new();
+        print_error_span(
+            &mut res,
+            example_string,
+            position,
+            example_string.len() - position,
+            "unexpected token",
+            file_name,
+        )
+        .unwrap();
+        assert!(res.ends_with("  |                ^^^^^^^^unexpected token\n"));
+    }
+
+    #[test]
+    fn context_lines_test() {
+        let example_string = (1..=10)
+            .map(|row| format!("select {}", row))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let file_name = "src/text.sql";
+        let position = example_string.find("select 9").unwrap();
+
+        let mut res = String::new();
+        print_error_with_context(
+            &mut res,
+            example_string.as_str(),
+            position,
+            6,
+            2,
+            "unexpected token",
+            file_name,
+        )
+        .unwrap();
+        assert_eq!(
+            format!("\n{}", res.as_str()),
+            r#"
+  --> src/text.sql:9:1
+   |
+ 7 | select 7
+ 8 | select 8
+ 9 | select 9
+   | ^^^^^^unexpected token
+10 | select 10
+"#
+        );
+
+        // the context stops at the start of the file
+        let mut res = String::new();
+        print_error_with_context(
+            &mut res,
+            example_string.as_str(),
+            0,
+            1,
+            1,
+            "unexpected token",
+            file_name,
+        )
+        .unwrap();
+        assert_eq!(
+            format!("\n{}", res.as_str()),
+            r#"
+ --> src/text.sql:1:1
+  |
+1 | select 1
+  | ^unexpected token
+2 | select 2
+"#
+        );
+
+        // asking for more context than fits in a usize shows the whole file
+        let mut res = String::new();
+        print_error_with_context(
+            &mut res,
+            example_string.as_str(),
+            position,
+            6,
+            usize::MAX,
+            "unexpected token",
+            file_name,
+        )
+        .unwrap();
+        assert!(res.contains(" 1 | select 1\n"));
+        assert!(res.ends_with("10 | select 10\n"));
+    }
+
+    #[test]
+    fn end_of_file_test() {
+        let file_name = "src/text.sql";
+        for example_string in &[
+            "select * from users\nwhere id = ",
+            "select * from users\nwhere id = \n\n",
+        ] {
+            let mut res = String::new();
+            print_error(
+                &mut res,
+                example_string,
+                example_string.len(),
+                "unexpected end of file",
+                file_name,
+            )
+            .unwrap();
+            assert_eq!(
+                format!("\n{}", res.as_str()),
+                r#"
+ --> src/text.sql:2:10
+  |
+2 | where id = 
+  |          ^unexpected end of file
+"#
+            );
+        }
+
+        let mut res = String::new();
+        print_error(&mut res, "select", 6, "unexpected end of file", file_name).unwrap();
+        assert_eq!(
+            format!("\n{}", res.as_str()),
+            r#"
+ --> src/text.sql:1:1
+  |
+1 | select
+  | ^unexpected end of file
+"#
+        );
+    }
+
     #[test]
     fn row_position_test() {
         fn assert_row_position(data: &str) {
@@ -0,0 +1,125 @@
+use std::{io::Write, str::FromStr};
+
+use serde_json::{json, Value};
+
+/// the target used by actix's request logger middleware
+const ACCESS_LOG_TARGET: &str = "actix_web::middleware::logger";
+
+/// access log format used in json mode. the fields are separated by a control character
+/// that can not be part of a request line or header value, and the user agent comes last
+/// so anything left over stays inside of it
+pub const JSON_ACCESS_LOG_FORMAT: &str = "%a\x1f%r\x1f%s\x1f%b\x1f%D\x1f%{User-Agent}i";
+
+const ACCESS_LOG_FIELDS: [&str; 6] = [
+    "remote_addr",
+    "request",
+    "status",
+    "size",
+    "duration_ms",
+    "user_agent",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("log format must be either 'text' or 'json'")),
+        }
+    }
+}
+
+/// initializes the global logger. the text format is env_logger's default
+pub fn init_logger(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_env(
+        env_logger::Env::new().default_filter_or("actix_web=info,justsql=info"),
+    );
+
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let message = record.args().to_string();
+            let message = match record.target() {
+                ACCESS_LOG_TARGET => access_log_json(message),
+                _ => Value::String(message),
+            };
+            let line = json!({
+                "timestamp": chrono::Utc::now().to_rfc3339(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": message,
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+
+    builder.init();
+}
+
+/// the fields of an access log message as a json object. numbers are kept as numbers,
+/// everything else including what the client sent is nested as a string
+fn access_log_json(message: String) -> Value {
+    let fields: Vec<&str> = message.splitn(ACCESS_LOG_FIELDS.len(), '\x1f').collect();
+    if fields.len() != ACCESS_LOG_FIELDS.len() {
+        return Value::String(message);
+    }
+    let object = ACCESS_LOG_FIELDS
+        .iter()
+        .zip(fields)
+        .map(|(key, field)| {
+            let value = match *key {
+                "status" | "size" | "duration_ms" => {
+                    serde_json::from_str::<serde_json::Number>(field)
+                        .map(Value::Number)
+                        .unwrap_or_else(|_| Value::String(field.to_string()))
+                }
+                _ => Value::String(field.to_string()),
+            };
+            (key.to_string(), value)
+        })
+        .collect();
+    Value::Object(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_log_json_test() {
+        let message = [
+            "127.0.0.1",
+            "GET /api/v1/endpoints HTTP/1.1",
+            "200",
+            "15",
+            "1.234000",
+            r#"x","status":500,"remote_addr":"1.2.3.4"#,
+        ]
+        .join("\x1f");
+        // the quotes of the user agent can not add or overwrite fields
+        assert_eq!(
+            access_log_json(message),
+            json!({
+                "remote_addr": "127.0.0.1",
+                "request": "GET /api/v1/endpoints HTTP/1.1",
+                "status": 200,
+                "size": 15,
+                "duration_ms": 1.234,
+                "user_agent": r#"x","status":500,"remote_addr":"1.2.3.4"#,
+            })
+        );
+
+        // messages in another format are kept as text
+        assert_eq!(
+            access_log_json("starting server".to_string()),
+            json!("starting server")
+        );
+    }
+}
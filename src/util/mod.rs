@@ -1,4 +1,7 @@
 pub mod error_printing;
+pub mod logging;
 pub mod matches_map;
 pub mod mixed_ref;
 pub mod path;
+#[cfg(test)]
+pub mod temp_dir;
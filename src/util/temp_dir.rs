@@ -0,0 +1,21 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// creates an empty directory for a test to write files into. the directory is
+/// unique to the process and the call so tests running at the same time, or the
+/// leftovers of earlier runs, never share files
+pub fn temp_dir(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!(
+        "justsql_{}_{}_{}",
+        name,
+        std::process::id(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    directory.canonicalize().unwrap()
+}